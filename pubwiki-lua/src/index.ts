@@ -13,7 +13,15 @@ import {
   js_rdf_insert,
   js_rdf_delete,
   js_rdf_query,
-  js_rdf_batch_insert
+  js_rdf_batch_insert,
+  js_rdf_exists,
+  js_rdf_count,
+  js_rdf_replace,
+  js_rdf_begin,
+  js_rdf_commit,
+  js_rdf_rollback,
+  js_rdf_increment,
+  js_rdf_flush
 } from './rdf-bridge'
 
 // ============= 导出类型 =============
@@ -41,6 +49,14 @@ let modulePromise: Promise<void> | null = null
 let heapU8: Uint8Array | null = null
 let heapU32: Uint32Array | null = null
 let lastFetchError: string | null = null
+// 运行器通过 js_set_fetch_timeout 告知的模块获取超时（毫秒，0 表示不限制）
+let fetchTimeoutMs = 0
+// js_cache_get / js_cache_set 使用的键值存储（值及过期时间，expiresAt 为 0 表示不过期），在同一个页面中跨运行保留
+const hostCache = new Map<string, { value: string; expiresAt: number }>()
+// lua_set_custom_searcher 开启后 require 使用的解析函数：返回模块源码，null 表示找不到
+let moduleResolver: ((name: string) => string | null) | null = null
+// mw.getCurrentUser 返回的用户信息，null（默认）表示匿名用户
+let userContext: Record<string, unknown> | null = null
 
 interface LuaModule {
   HEAPU8: Uint8Array
@@ -82,6 +98,34 @@ function allocateImportBytes(bytes: Uint8Array, module: LuaModule) {
   return { ptr, length }
 }
 
+// 辅助函数：把返回给运行器的字符串复制到 WASM 内存（以 0 结尾），运行器用 js_rdf_free 释放
+function allocateResultString(text: string, module: LuaModule) {
+  const bytes = textEncoder.encode(text)
+  const ptr = module._malloc(bytes.length + 1)
+  module.HEAPU8.set(bytes, ptr)
+  module.HEAPU8[ptr + bytes.length] = 0
+  return ptr
+}
+
+/**
+ * 同步等待指定的毫秒数（js_sleep，RDF 调用重试前的退避）
+ * Node.js 和 Worker 中用 Atomics.wait 阻塞；浏览器主线程不允许 Atomics.wait，
+ * 没有 SharedArrayBuffer（页面未开启跨源隔离）时也无法使用，这两种情况下忙等待
+ */
+function sleepSync(ms: number): void {
+  if (ms <= 0) return
+  try {
+    Atomics.wait(new Int32Array(new SharedArrayBuffer(4)), 0, 0, ms)
+    return
+  } catch {
+    // 回退到忙等待
+  }
+  const until = performance.now() + ms
+  while (performance.now() < until) {
+    // 忙等待
+  }
+}
+
 // ============= Module Loading (require support) =============
 
 /**
@@ -90,14 +134,19 @@ function allocateImportBytes(bytes: Uint8Array, module: LuaModule) {
 function httpGetSync(url: string): string {
   if (isBrowser) {
     // 浏览器环境：使用 XMLHttpRequest
+    // 同步 XHR 不支持 timeout 属性，只能在请求完成后检查耗时
     const xhr = new XMLHttpRequest()
     xhr.open('GET', url, false)
     xhr.overrideMimeType('text/plain; charset=utf-8')
+    const startedAt = Date.now()
     try {
       xhr.send(null)
     } catch (error) {
       throw new Error(`Network error while fetching ${url}: ${error}`)
     }
+    if (fetchTimeoutMs > 0 && Date.now() - startedAt > fetchTimeoutMs) {
+      throw new Error(`TIMEOUT: ${url} took longer than ${fetchTimeoutMs} ms`)
+    }
     if (xhr.status >= 200 && xhr.status < 300) {
       return xhr.responseText
     }
@@ -211,6 +260,24 @@ function fetchModuleSource(spec: string): string {
   return source
 }
 
+/**
+ * 没有设置解析函数时的默认解析：带 mediawiki://、http(s)://、file:// 前缀的名称按 require 相同的方式获取，其他名称视为找不到
+ */
+function defaultModuleResolver(name: string): string | null {
+  if (/^(mediawiki|https?|file):\/\//.test(name)) {
+    return fetchModuleSource(name)
+  }
+  return null
+}
+
+/**
+ * 设置 lua_set_custom_searcher 开启后 require 使用的解析函数（js_resolve_module），
+ * 返回模块源码，返回 null 表示找不到，抛出的错误作为 require 的错误；传入 null 恢复默认解析
+ */
+export function setModuleResolver(resolver: ((name: string) => string | null) | null): void {
+  moduleResolver = resolver
+}
+
 /**
  * 上传文件模块（用于 file:// 协议）
  */
@@ -368,6 +435,10 @@ export async function loadRunner(customGluePath?: string): Promise<void> {
             }
           }
 
+          env.js_set_fetch_timeout = (timeoutMs: number) => {
+            fetchTimeoutMs = timeoutMs
+          }
+
           env.get_last_fetch_error = (lenPtr: number) => {
             if (!localModule) return 0
             setHeapViews(localModule)
@@ -381,6 +452,27 @@ export async function loadRunner(customGluePath?: string): Promise<void> {
             return ptr
           }
           
+          // RDF 调用重试前的退避等待
+          env.js_sleep = (ms: number) => sleepSync(ms)
+          
+          // 单调时钟（毫秒），用于 lua_set_timeout_ms 的执行超时检查
+          env.js_now_ms = () => performance.now()
+          
+          // 当前 Unix 时间（秒），供 os.clock / mw.elapsedTime 等计时使用
+          env.js_now_epoch = () => Date.now() / 1000
+
+          // 用随机字节填满 WASM 内存中的缓冲区（mw.uuid 使用），随机源不可用时返回非 0
+          env.js_random_bytes = (bufPtr: number, len: number) => {
+            const crypto = globalThis.crypto
+            if (!localModule || typeof crypto?.getRandomValues !== 'function') return 1
+            setHeapViews(localModule)
+            // getRandomValues 每次最多填充 65536 字节
+            for (let offset = 0; offset < len; offset += 65536) {
+              crypto.getRandomValues(heapU8!.subarray(bufPtr + offset, bufPtr + Math.min(len, offset + 65536)))
+            }
+            return 0
+          }
+
           // 注入 RDF 函数
           env.js_rdf_insert = (subjectPtr: number, predicatePtr: number, objectJsonPtr: number) => {
             console.log('[js_rdf_insert] Called')
@@ -447,6 +539,78 @@ export async function loadRunner(customGluePath?: string): Promise<void> {
             return resultPtr
           }
           
+          env.js_rdf_exists = (patternJsonPtr: number) => {
+            if (!localModule) return 0
+            const patternJson = localModule.UTF8ToString(patternJsonPtr)
+            return allocateResultString(js_rdf_exists(patternJson), localModule)
+          }
+          
+          env.js_rdf_count = (patternJsonPtr: number) => {
+            if (!localModule) return 0
+            const patternJson = localModule.UTF8ToString(patternJsonPtr)
+            return allocateResultString(js_rdf_count(patternJson), localModule)
+          }
+          
+          env.js_rdf_replace = (subjectPtr: number, predicatePtr: number, objectJsonPtr: number) => {
+            if (!localModule) return 0
+            const subject = localModule.UTF8ToString(subjectPtr)
+            const predicate = localModule.UTF8ToString(predicatePtr)
+            const objectJson = localModule.UTF8ToString(objectJsonPtr)
+            return allocateResultString(js_rdf_replace(subject, predicate, objectJson), localModule)
+          }
+          
+          env.js_rdf_begin = () => localModule ? allocateResultString(js_rdf_begin(), localModule) : 0
+          env.js_rdf_commit = () => localModule ? allocateResultString(js_rdf_commit(), localModule) : 0
+          env.js_rdf_rollback = () => localModule ? allocateResultString(js_rdf_rollback(), localModule) : 0
+          
+          env.js_rdf_increment = (subjectPtr: number, predicatePtr: number, deltaJsonPtr: number) => {
+            if (!localModule) return 0
+            const subject = localModule.UTF8ToString(subjectPtr)
+            const predicate = localModule.UTF8ToString(predicatePtr)
+            const deltaJson = localModule.UTF8ToString(deltaJsonPtr)
+            return allocateResultString(js_rdf_increment(subject, predicate, deltaJson), localModule)
+          }
+          
+          env.js_rdf_flush = () => localModule ? allocateResultString(js_rdf_flush(), localModule) : 0
+          
+          // lua_set_custom_searcher 开启时一步完成模块解析和获取：返回源码，"ERROR:" 开头表示失败，空指针表示找不到
+          env.js_resolve_module = (namePtr: number) => {
+            if (!localModule) return 0
+            const name = localModule.UTF8ToString(namePtr)
+            try {
+              const source = (moduleResolver ?? defaultModuleResolver)(name)
+              return source === null ? 0 : allocateResultString(source, localModule)
+            } catch (error) {
+              return allocateResultString(`ERROR:${error instanceof Error ? error.message : String(error)}`, localModule)
+            }
+          }
+          
+          // 当前用户信息，没有设置时返回空指针，mw.getCurrentUser 得到匿名用户
+          env.js_get_user_context = () => {
+            if (!localModule || userContext === null) return 0
+            return allocateResultString(JSON.stringify(userContext), localModule)
+          }
+          
+          // 键值存储（lua_set_rdf_cache_backend 的查询缓存等使用），未命中时返回空指针
+          env.js_cache_get = (keyPtr: number) => {
+            if (!localModule) return 0
+            const key = localModule.UTF8ToString(keyPtr)
+            const entry = hostCache.get(key)
+            if (!entry) return 0
+            if (entry.expiresAt !== 0 && Date.now() >= entry.expiresAt) {
+              hostCache.delete(key)
+              return 0
+            }
+            return allocateResultString(entry.value, localModule)
+          }
+          
+          env.js_cache_set = (keyPtr: number, valuePtr: number, ttlSecs: number) => {
+            if (!localModule) return
+            const key = localModule.UTF8ToString(keyPtr)
+            const value = localModule.UTF8ToString(valuePtr)
+            hostCache.set(key, { value, expiresAt: ttlSecs > 0 ? Date.now() + ttlSecs * 1000 : 0 })
+          }
+          
           env.js_rdf_free = (ptr: number) => {
             if (localModule && ptr !== 0) {
               localModule._free(ptr)
@@ -595,6 +759,14 @@ export async function runLua(code: string, rdfStore: RDFStore | SyncRDFStore): P
   }
 }
 
+/**
+ * 设置 mw.getCurrentUser 返回的用户信息，例如 { name: 'Alice', groups: ['sysop'], isAnon: false }；
+ * 传入 null 恢复为匿名用户
+ */
+export function setUserContext(user: Record<string, unknown> | null): void {
+  userContext = user
+}
+
 /**
  * 设置 WASM glue 文件路径
  */
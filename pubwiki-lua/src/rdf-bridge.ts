@@ -97,6 +97,31 @@ export function setRDFStore(store: SyncRDFStore): void {
  */
 export function clearRDFStore(): void {
   currentStore = null
+  transactionLog = null
+}
+
+// State.transaction 期间的撤销记录，按写入顺序保存；null 表示没有进行中的事务
+// SyncRDFStore 没有事务接口，回滚时按相反顺序撤销这些写入
+interface UndoEntry {
+  inserted?: Triple
+  deleted?: Triple
+}
+let transactionLog: UndoEntry[] | null = null
+
+// 事务中记录即将插入的三元组（已存在的三元组插入后不变，不需要撤销）
+function journalInsert(store: SyncRDFStore, triple: Triple): void {
+  if (transactionLog && store.query(triple).length === 0) {
+    transactionLog.push({ inserted: triple })
+  }
+}
+
+// 事务中记录即将被删除的三元组，object 为 null 或 undefined 时匹配所有值
+function journalDelete(store: SyncRDFStore, subject: string, predicate: string, object?: any): void {
+  if (transactionLog) {
+    for (const triple of store.query({ subject, predicate, object: object ?? undefined })) {
+      transactionLog.push({ deleted: triple })
+    }
+  }
 }
 
 /**
@@ -109,6 +134,7 @@ export function js_rdf_insert(subject: string, predicate: string, objectJson: st
   
   try {
     const object = JSON.parse(objectJson)
+    journalInsert(currentStore, { subject, predicate, object })
     currentStore.insert(subject, predicate, object)
     return "OK"
   } catch (err) {
@@ -126,6 +152,7 @@ export function js_rdf_delete(subject: string, predicate: string, objectJson: st
   
   try {
     const object = objectJson ? JSON.parse(objectJson) : undefined
+    journalDelete(currentStore, subject, predicate, object)
     currentStore.delete(subject, predicate, object)
     return "OK"
   } catch (err) {
@@ -160,6 +187,9 @@ export function js_rdf_batch_insert(triplesJson: string): string {
   
   try {
     const triples: Triple[] = JSON.parse(triplesJson)
+    for (const triple of triples) {
+      journalInsert(currentStore, triple)
+    }
     
     if (currentStore.batchInsert) {
       currentStore.batchInsert(triples)
@@ -176,6 +206,149 @@ export function js_rdf_batch_insert(triplesJson: string): string {
   }
 }
 
+/**
+ * Rust 调用的同步函数：是否存在匹配的三元组（State.exists / State.has），返回 "true" 或 "false"
+ */
+export function js_rdf_exists(patternJson: string): string {
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  
+  try {
+    const pattern: TriplePattern = JSON.parse(patternJson)
+    return currentStore.query(pattern).length > 0 ? "true" : "false"
+  } catch (err) {
+    return `ERROR:${err instanceof Error ? err.message : String(err)}`
+  }
+}
+
+/**
+ * Rust 调用的同步函数：匹配的三元组数量（State.count），返回十进制数字字符串
+ */
+export function js_rdf_count(patternJson: string): string {
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  
+  try {
+    const pattern: TriplePattern = JSON.parse(patternJson)
+    return String(currentStore.query(pattern).length)
+  } catch (err) {
+    return `ERROR:${err instanceof Error ? err.message : String(err)}`
+  }
+}
+
+/**
+ * Rust 调用的同步函数：删除 subject + predicate 的所有三元组并插入新的三元组（开启 lua_set_rdf_atomic_replace 时的 State.set）
+ * 同步存储在两步之间不会执行其他写入，因此整个替换是原子的
+ */
+export function js_rdf_replace(subject: string, predicate: string, objectJson: string): string {
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  
+  try {
+    const object = JSON.parse(objectJson)
+    journalDelete(currentStore, subject, predicate)
+    currentStore.delete(subject, predicate)
+    journalInsert(currentStore, { subject, predicate, object })
+    currentStore.insert(subject, predicate, object)
+    return "OK"
+  } catch (err) {
+    return `ERROR:${err instanceof Error ? err.message : String(err)}`
+  }
+}
+
+/**
+ * Rust 调用的同步函数：开始事务（State.transaction），不支持嵌套
+ */
+export function js_rdf_begin(): string {
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  if (transactionLog) {
+    return "ERROR:transaction already active"
+  }
+  transactionLog = []
+  return "OK"
+}
+
+/**
+ * Rust 调用的同步函数：提交事务，写入已经直接作用于存储，只需丢弃撤销记录
+ */
+export function js_rdf_commit(): string {
+  transactionLog = null
+  return "OK"
+}
+
+/**
+ * Rust 调用的同步函数：回滚事务，按相反顺序撤销事务中的写入
+ */
+export function js_rdf_rollback(): string {
+  const log = transactionLog
+  transactionLog = null
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  
+  try {
+    for (const entry of (log ?? []).reverse()) {
+      if (entry.inserted) {
+        currentStore.delete(entry.inserted.subject, entry.inserted.predicate, entry.inserted.object)
+      } else if (entry.deleted) {
+        currentStore.insert(entry.deleted.subject, entry.deleted.predicate, entry.deleted.object)
+      }
+    }
+    return "OK"
+  } catch (err) {
+    return `ERROR:${err instanceof Error ? err.message : String(err)}`
+  }
+}
+
+/**
+ * Rust 调用的同步函数：把 subject + predicate 的数值加上 delta（State.increment），返回新值的 JSON
+ * 不存在时从 0 开始；已有的值不是数字时返回错误
+ */
+export function js_rdf_increment(subject: string, predicate: string, deltaJson: string): string {
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  
+  try {
+    const delta = JSON.parse(deltaJson)
+    const existing = currentStore.query({ subject, predicate })
+    const current = existing.length > 0 ? existing[0].object : 0
+    if (typeof current !== 'number' || typeof delta !== 'number') {
+      return "ERROR:cannot increment non-numeric value"
+    }
+    const updated = current + delta
+    journalDelete(currentStore, subject, predicate)
+    currentStore.delete(subject, predicate)
+    journalInsert(currentStore, { subject, predicate, object: updated })
+    currentStore.insert(subject, predicate, updated)
+    return JSON.stringify(updated)
+  } catch (err) {
+    return `ERROR:${err instanceof Error ? err.message : String(err)}`
+  }
+}
+
+/**
+ * Rust 调用的同步函数：提交缓冲的写入（State.flush），存储没有实现 flush 时什么也不做
+ * createSyncAdapter 返回的存储在写入后立即可读，因此不需要 flush
+ */
+export function js_rdf_flush(): string {
+  if (!currentStore) {
+    return "ERROR:RDFStore not initialized"
+  }
+  
+  try {
+    currentStore.flush?.()
+    return "OK"
+  } catch (err) {
+    return `ERROR:${err instanceof Error ? err.message : String(err)}`
+  }
+}
+
 /**
  * 为异步 RDFStore 创建同步适配器
  * 使用 N3 Store 作为内存缓存来实现同步查询
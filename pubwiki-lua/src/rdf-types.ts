@@ -65,4 +65,8 @@ export interface SyncRDFStore {
   query(pattern: TriplePattern): Triple[]
   batchInsert?(triples: Triple[]): void
   batchDelete?(patterns: TriplePattern[]): void
+  /**
+   * 立即提交缓冲的写入（可选，State.flush 调用）；写入后立即可读的存储不需要实现
+   */
+  flush?(): void
 }
@@ -0,0 +1,194 @@
+//! 轻量的 Lua 5.4 词法分析器：只切分记号，不执行代码，供宿主做语法高亮和简单的静态检查
+
+use serde::Serialize;
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// 按最长匹配排列的运算符和分隔符
+const OPERATORS: &[&str] = &[
+    "...", "..", "==", "~=", "<=", ">=", "<<", ">>", "//", "::", "+", "-", "*", "/", "%", "^", "#",
+    "&", "~", "|", "<", ">", "=", "(", ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+/// 一个记号：value 为源码中的原始文本，line/col 为起始位置（均从 1 开始，col 按字符计）
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct Token {
+    #[serde(rename = "type")]
+    pub(crate) kind: &'static str,
+    pub(crate) value: String,
+    pub(crate) line: usize,
+    pub(crate) col: usize,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl Lexer {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.peek(i) == Some(c))
+    }
+
+    /// 若当前位置是长括号 `[==[`，返回等号个数
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek(0) != Some('[') {
+            return None;
+        }
+        let mut level = 0;
+        while self.peek(1 + level) == Some('=') {
+            level += 1;
+        }
+        (self.peek(1 + level) == Some('[')).then_some(level)
+    }
+
+    /// 读取长括号包围的内容（长字符串或长注释），当前位置在开头的 `[`
+    fn read_long_bracket(&mut self, level: usize, what: &str) -> Result<(), String> {
+        let (line, col) = (self.line, self.col);
+        for _ in 0..level + 2 {
+            self.bump();
+        }
+        let close = format!("]{}]", "=".repeat(level));
+        loop {
+            if self.starts_with(&close) {
+                for _ in 0..level + 2 {
+                    self.bump();
+                }
+                return Ok(());
+            }
+            if self.bump().is_none() {
+                return Err(format!("unfinished long {} starting at line {}, col {}", what, line, col));
+            }
+        }
+    }
+
+    /// 读取引号字符串，支持转义（包括 `\z` 和反斜杠续行）
+    fn read_quoted_string(&mut self, quote: char) -> Result<(), String> {
+        let (line, col) = (self.line, self.col);
+        self.bump();
+        loop {
+            match self.bump() {
+                Some('\\') => {
+                    if self.bump().is_none() {
+                        break;
+                    }
+                }
+                Some('\n') | None => break,
+                Some(c) if c == quote => return Ok(()),
+                Some(_) => {}
+            }
+        }
+        Err(format!("unfinished string starting at line {}, col {}", line, col))
+    }
+
+    fn read_number(&mut self) {
+        let hex = self.peek(0) == Some('0') && matches!(self.peek(1), Some('x' | 'X'));
+        if hex {
+            self.bump();
+            self.bump();
+        }
+        let exponent: &[char] = if hex { &['p', 'P'] } else { &['e', 'E'] };
+        while let Some(c) = self.peek(0) {
+            if exponent.contains(&c) {
+                self.bump();
+                if matches!(self.peek(0), Some('+' | '-')) {
+                    self.bump();
+                }
+            } else if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                // 与 Lua 相同，先贪婪读取，格式错误的数字仍作为一个记号返回
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, String> {
+        while self.peek(0).is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+        let Some(c) = self.peek(0) else {
+            return Ok(None);
+        };
+        let (start, line, col) = (self.pos, self.line, self.col);
+
+        let kind = if self.starts_with("--") {
+            self.bump();
+            self.bump();
+            match self.long_bracket_level() {
+                Some(level) => self.read_long_bracket(level, "comment")?,
+                None => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                }
+            }
+            "comment"
+        } else if c.is_alphabetic() || c == '_' {
+            while self.peek(0).is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                self.bump();
+            }
+            let word: String = self.chars[start..self.pos].iter().collect();
+            if KEYWORDS.contains(&word.as_str()) { "keyword" } else { "name" }
+        } else if c.is_ascii_digit() || (c == '.' && self.peek(1).is_some_and(|c| c.is_ascii_digit())) {
+            self.read_number();
+            "number"
+        } else if c == '"' || c == '\'' {
+            self.read_quoted_string(c)?;
+            "string"
+        } else if let Some(level) = self.long_bracket_level() {
+            self.read_long_bracket(level, "string")?;
+            "string"
+        } else if let Some(op) = OPERATORS.iter().find(|op| self.starts_with(op)) {
+            for _ in 0..op.chars().count() {
+                self.bump();
+            }
+            "operator"
+        } else {
+            return Err(format!("unexpected symbol '{}' at line {}, col {}", c, line, col));
+        };
+
+        Ok(Some(Token {
+            kind,
+            value: self.chars[start..self.pos].iter().collect(),
+            line,
+            col,
+        }))
+    }
+}
+
+/// 把源码切分为记号（包括注释），遇到无法识别的符号或未结束的字符串/注释时返回错误
+pub(crate) fn tokenize(code: &str) -> Result<Vec<Token>, String> {
+    let mut lexer = Lexer {
+        chars: code.chars().collect(),
+        pos: 0,
+        line: 1,
+        col: 1,
+    };
+    let mut tokens = Vec::new();
+    while let Some(token) = lexer.next_token()? {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
@@ -3,16 +3,333 @@
 // by the -sEXPORTED_FUNCTIONS link flag configured in .cargo/config.toml.
 
 use mlua::prelude::*;
-use mlua::{prelude::LuaMultiValue, Table, Variadic};
-use std::cell::RefCell;
-use std::ffi::{CStr, CString};
+use mlua::{prelude::LuaMultiValue, Debug, DebugEvent, Table, Variadic};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{c_void, CStr, CString};
 use std::os::raw::{c_char, c_uchar};
 use std::rc::Rc;
 use std::slice;
 
+mod lexer;
+mod mw;
+mod ustring;
+mod yaml;
+
 #[derive(Clone, Default)]
 struct MediaWikiStack(Vec<String>);
 
+/// 可以被宿主在运行时禁用的全局 API
+/// 运行器没有安装 Cache 全局变量：宿主缓存（js_cache_get / js_cache_set）只作为 State 查询缓存的后端，
+/// 需要禁止时用 lua_set_import_allowlist 去掉 "cache"
+const DISABLEABLE_APIS: &[&str] = &["State", "mw"];
+
+/// mw.uuid 的生成方式
+#[derive(Clone, Copy, Default, PartialEq)]
+enum UuidStrategy {
+    /// 使用 js_random_bytes 生成 v4 随机 UUID
+    #[default]
+    Random,
+    /// 使用 js_now_epoch 与递增计数器生成按时间排序的 v7 UUID（不具备密码学随机性）
+    Time,
+}
+
+/// 模拟的 Lua 版本语义
+#[derive(Clone, Copy, Default, PartialEq)]
+enum CompatMode {
+    /// Lua 5.4 原生行为
+    #[default]
+    Lua54,
+    /// Lua 5.1（Scribunto 使用的版本），安装兼容函数
+    Lua51,
+}
+
+/// Lua 5.1 兼容函数，在 lua_set_compat_mode("5.1") 后于每次运行前安装
+///
+/// 只补齐被移除或改名的库函数，数值语义不做模拟：5.1 只有浮点数，而 5.4 区分整数子类型
+/// （`/` 总是得到浮点数，`7 // 2` 等整数运算得到整数，整数溢出会回绕）。
+/// `#` 作用于有空洞的表时两个版本都只保证返回某个边界。
+const LUA51_COMPAT_SHIMS: &str = r#"
+unpack = unpack or table.unpack
+loadstring = loadstring or load
+table.getn = table.getn or function(t) return #t end
+table.maxn = table.maxn or function(t)
+    local max = 0
+    for k in pairs(t) do
+        if type(k) == "number" and k > max then max = k end
+    end
+    return max
+end
+math.mod = math.mod or math.fmod
+math.pow = math.pow or function(x, y) return x ^ y end
+"#;
+
+/// 超出 JavaScript 安全整数范围（|n| > 2^53）的整数的序列化方式
+#[derive(Clone, Copy, Default, PartialEq)]
+enum BigIntMode {
+    /// 原样输出为 JSON 数字（JS 端可能丢失精度）
+    #[default]
+    Number,
+    /// 输出为 JSON 字符串
+    String,
+    /// 报错
+    Error,
+}
+
+/// RDF 三元组中布尔 object 在宿主 JSON 中的表示方式
+#[derive(Clone, Copy, Default, PartialEq)]
+enum BooleanMode {
+    /// JSON 布尔值 true/false
+    #[default]
+    Native,
+    /// 字符串 "true"/"false"
+    String,
+    /// 带 xsd:boolean 数据类型的字面量 {"@value": "true", "@type": XSD_BOOLEAN}
+    Typed,
+}
+
+/// xsd:boolean 数据类型的完整 IRI
+const XSD_BOOLEAN: &str = "http://www.w3.org/2001/XMLSchema#boolean";
+
+/// State.queryIter 默认的每页三元组数量
+const DEFAULT_RDF_PAGE_SIZE: usize = 100;
+
+/// 运行配置：由宿主通过 C ABI 设置，作用于之后的每次 lua_run
+struct RunConfig {
+    disabled_apis: HashSet<String>,
+    auto_display: bool,
+    bigint_mode: BigIntMode,
+    rdf_boolean_mode: BooleanMode,
+    compat_mode: CompatMode,
+    uuid_strategy: UuidStrategy,
+    /// 宿主通过 lua_seed_uuid 设置的 v4 UUID 种子（None 时使用 js_random_bytes）
+    uuid_seed: Option<u64>,
+    /// 宿主通过 lua_mount_vfs 挂载的模块源码（模块名 -> 源码）
+    vfs: HashMap<String, String>,
+    /// 宿主通过 lua_set_require_alias 设置的模块别名（require 的名称 -> 实际加载的名称）
+    require_aliases: HashMap<String, String>,
+    result_header_mode: bool,
+    rdf_page_size: usize,
+    /// State.query 是否把结果缓存到宿主的键值存储（lua_set_rdf_cache_backend）
+    rdf_cache_backend: bool,
+    /// 缓存的查询结果的有效期（秒），0 表示由宿主决定
+    rdf_cache_ttl_secs: u32,
+    /// 写入 RDF 的单个 object 序列化为 JSON 后的最大字节数，0 表示不限制
+    rdf_max_object_bytes: usize,
+    /// 写操作要求 subject 和 predicate 是合法的 IRI
+    rdf_strict_iri: bool,
+    /// 宿主提供 js_rdf_replace 时，State.set 使用原子替换
+    rdf_atomic_replace: bool,
+    /// 单次运行最多加载的不同模块数量，0 表示不限制
+    max_modules_per_run: usize,
+    /// print 输出的每一行的前缀/后缀，空字符串表示不添加
+    print_line_prefix: String,
+    print_line_suffix: String,
+    /// 加载模块前去掉源码首尾的 <nowiki> 包装
+    strip_module_wrappers: bool,
+    /// 不带参数的 print() 是否输出一个空行（Lua 默认行为）
+    empty_print_emits_newline: bool,
+    /// io.write 是否接受 nil 和布尔值（默认与标准 Lua 一样报错）
+    iowrite_coerce: bool,
+    /// 宿主通过 lua_set_constants 设置的只读全局 CONFIG
+    constants: Option<serde_json::Map<String, serde_json::Value>>,
+    /// mw.log 日志的总字节数上限，0 表示不限制
+    log_limit: usize,
+    /// print 和 io.write 输出的总字节数上限，0 表示不限制
+    output_limit: usize,
+    /// 是否统计执行的指令数并在结果中输出 "instructions"
+    count_instructions: bool,
+    /// 加载模块前是否把 \r\n 和单独的 \r 转换为 \n
+    normalize_newlines: bool,
+    /// 是否记录每个函数的调用次数和指令数并在结果中输出 "profile"
+    profile: bool,
+    /// 是否在结果中输出 "timings"（序列化结果信封的耗时等）
+    report_timings: bool,
+    /// 每次向宿主获取模块的超时时间（毫秒），0 表示不限制
+    fetch_timeout_ms: u32,
+    /// mw.clone、CONFIG 冻结等遍历嵌套表的辅助函数允许的最大嵌套层数，0 表示不限制
+    max_structure_depth: usize,
+    /// 每次运行前执行的公共代码（lua_set_baseline）
+    baseline: Option<String>,
+    /// RDF 宿主调用遇到可重试错误时的最大重试次数，0 表示不重试
+    rdf_retry_count: u32,
+    /// 第一次重试前等待的毫秒数，之后每次加倍
+    rdf_retry_backoff_ms: u32,
+    /// IRI 前缀映射（前缀 -> 命名空间 IRI），State.toJsonLd 将其输出为 @context
+    rdf_prefixes: HashMap<String, String>,
+    /// 每次运行最多执行的指令数，0 表示不限制
+    instruction_limit: u32,
+    /// 每次运行的最长执行时间（毫秒），0 表示不限制
+    timeout_ms: u32,
+    /// 是否以捕获的输出作为结果（忽略代码块的返回值）
+    result_from_output: bool,
+    /// 返回值中的 ±inf 是否输出为字符串 "Infinity"/"-Infinity"（否则与 NaN 一样输出为 null）
+    infinity_as_string: bool,
+    /// subject 和 predicate 的最大长度（字节），0 表示不限制
+    rdf_max_iri_len: usize,
+    /// Lua 可分配的最大内存（字节），0 表示不限制
+    memory_limit: usize,
+    /// 允许调用的宿主能力，None 表示不限制（lua_set_import_allowlist）
+    import_allowlist: Option<HashSet<Capability>>,
+    /// 宿主通过 lua_set_host_imports 声明已实现的导入函数组，None 表示全部可用
+    host_imports: Option<HashSet<&'static str>>,
+    /// require 是否完全交给宿主的 js_resolve_module 处理
+    custom_searcher: bool,
+    /// lua_run_batch 是否在同一个上下文中依次运行所有代码片段
+    batch_shared_context: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        RunConfig {
+            disabled_apis: HashSet::new(),
+            auto_display: false,
+            bigint_mode: BigIntMode::default(),
+            rdf_boolean_mode: BooleanMode::default(),
+            compat_mode: CompatMode::default(),
+            uuid_strategy: UuidStrategy::default(),
+            uuid_seed: None,
+            vfs: HashMap::new(),
+            require_aliases: HashMap::new(),
+            result_header_mode: false,
+            rdf_page_size: DEFAULT_RDF_PAGE_SIZE,
+            rdf_cache_backend: false,
+            rdf_cache_ttl_secs: 0,
+            rdf_max_object_bytes: 0,
+            rdf_strict_iri: false,
+            rdf_atomic_replace: false,
+            max_modules_per_run: 0,
+            print_line_prefix: String::new(),
+            print_line_suffix: String::new(),
+            strip_module_wrappers: false,
+            empty_print_emits_newline: true,
+            iowrite_coerce: false,
+            constants: None,
+            log_limit: 0,
+            output_limit: 0,
+            count_instructions: false,
+            profile: false,
+            report_timings: false,
+            normalize_newlines: false,
+            fetch_timeout_ms: 0,
+            max_structure_depth: 0,
+            baseline: None,
+            rdf_retry_count: 0,
+            rdf_retry_backoff_ms: 0,
+            rdf_prefixes: HashMap::new(),
+            instruction_limit: 0,
+            timeout_ms: 0,
+            result_from_output: false,
+            infinity_as_string: false,
+            rdf_max_iri_len: 0,
+            memory_limit: 0,
+            batch_shared_context: false,
+            import_allowlist: None,
+            host_imports: None,
+            custom_searcher: false,
+        }
+    }
+}
+
+/// 宿主导入函数的类别，用于 lua_set_import_allowlist
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Capability {
+    Fetch,
+    RdfRead,
+    RdfWrite,
+    Cache,
+    Message,
+    Preprocess,
+}
+
+impl Capability {
+    const ALL: [Capability; 6] = [
+        Capability::Fetch,
+        Capability::RdfRead,
+        Capability::RdfWrite,
+        Capability::Cache,
+        Capability::Message,
+        Capability::Preprocess,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Capability::Fetch => "fetch",
+            Capability::RdfRead => "rdf_read",
+            Capability::RdfWrite => "rdf_write",
+            Capability::Cache => "cache",
+            Capability::Message => "message",
+            Capability::Preprocess => "preprocess",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Capability::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// 提供该能力的宿主导入函数组（lua_set_host_imports 使用的名称）
+    fn import_group(self) -> &'static str {
+        match self {
+            Capability::Fetch => "fetch",
+            Capability::RdfRead | Capability::RdfWrite => "rdf",
+            Capability::Cache => "cache",
+            Capability::Message => "message",
+            Capability::Preprocess => "preprocess",
+        }
+    }
+}
+
+/// lua_set_host_imports 可以声明的宿主导入函数组
+const HOST_IMPORT_GROUPS: [&str; 9] = ["fetch", "rdf", "cache", "message", "preprocess", "clock", "sleep", "random", "user"];
+
+/// 宿主是否实现了导入函数组 group（没有调用 lua_set_host_imports 时视为全部可用）
+fn host_import_available(group: &str) -> bool {
+    RUN_CONFIG.with(|c| c.borrow().host_imports.as_ref().is_none_or(|imports| imports.contains(group)))
+}
+
+/// 宿主没有实现导入函数组 group 时返回 Lua 错误 "host capability 'group' unavailable"
+fn require_host_import(group: &str) -> LuaResult<()> {
+    if !host_import_available(group) {
+        return Err(LuaError::external(format!("host capability '{}' unavailable", group)));
+    }
+    Ok(())
+}
+
+/// 设置了导入白名单且其中没有该能力时报错；宿主声明的导入函数组中没有提供该能力的组时，
+/// 在调用导入函数之前报错，避免调用宿主没有实现的导入函数
+fn require_capability(capability: Capability) -> LuaResult<()> {
+    let permitted = RUN_CONFIG.with(|c| {
+        c.borrow().import_allowlist.as_ref().is_none_or(|allowlist| allowlist.contains(&capability))
+    });
+    if !permitted {
+        return Err(LuaError::external(format!("capability '{}' not permitted", capability.name())));
+    }
+    require_host_import(capability.import_group())
+}
+
+thread_local! {
+    static RUN_CONFIG: RefCell<RunConfig> = RefCell::new(RunConfig::default());
+    /// 头部模式下保存的最近一次完整结果信封
+    static LAST_RESULT: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// io.writeBytes 写入的二进制输出，每次运行开始时清空
+    static BINARY_OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    /// lua_run_chunked 保存的序列化结果（句柄 -> 结果信封的 JSON 字节）
+    static CHUNKED_RESULTS: RefCell<HashMap<u32, Vec<u8>>> = RefCell::new(HashMap::new());
+    static NEXT_CHUNKED_HANDLE: std::cell::Cell<u32> = const { std::cell::Cell::new(1) };
+    /// 最近一次通过 js_set_fetch_timeout 告知宿主的超时时间（0 表示没有告知过或已经取消）
+    static FETCH_TIMEOUT_SENT: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    /// 向宿主获取模块的状态；宿主重新进入运行器时会创建新的 Lua 实例，因此不能放在 app_data 中
+    static MODULE_FETCH: std::cell::Cell<ModuleFetchState> = const { std::cell::Cell::new(ModuleFetchState::Idle) };
+    /// 模块源码缓存的版本，lua_clear_module_cache 递增它使所有上下文中的缓存失效
+    static MODULE_CACHE_GENERATION: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    /// lua_new_context 创建且尚未释放的上下文（Box 指针的地址）；上下文不会自动释放，
+    /// 宿主必须用 lua_free_context 或 lua_destroy_all_contexts 释放，否则其中的 Lua 状态一直占用内存
+    static LIVE_CONTEXTS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+    /// 只用于检查代码能否编译的 Lua 实例（不加载标准库），避免每次检查都创建新的状态
+    static SYNTAX_CHECK_LUA: Lua = Lua::new_with(LuaStdLib::NONE, LuaOptions::default())
+        .expect("failed to create syntax check state");
+}
+
 struct ResolvedModuleSource {
     name: String,
     source: String,
@@ -63,6 +380,16 @@ fn mediawiki_base(spec: &str) -> Option<String> {
 }
 
 fn resolve_module_spec(lua: &Lua, name: &str) -> String {
+    // 从 Lua app_data 获取 MediaWiki 栈
+    let base = lua.app_data_ref::<MediaWikiStack>().and_then(|s| s.0.last().cloned());
+    resolve_module_name(base, name)
+}
+
+/// 按别名和命名空间规则解析模块名，base 为当前 MediaWiki 栈顶（如 "mediawiki://wiki/"），None 表示顶层代码
+fn resolve_module_name(base: Option<String>, name: &str) -> String {
+    // 别名在命名空间解析之前生效
+    let aliased = RUN_CONFIG.with(|c| c.borrow().require_aliases.get(name.trim()).cloned());
+    let name = aliased.as_deref().unwrap_or(name);
     if name.contains("://") {
         return name.to_string();
     }
@@ -70,10 +397,8 @@ fn resolve_module_spec(lua: &Lua, name: &str) -> String {
     if trimmed.is_empty() {
         return name.to_string();
     }
-    
-    // 从 Lua app_data 获取 MediaWiki 栈
-    let stack = lua.app_data_ref::<MediaWikiStack>();
-    let Some(mut base) = stack.and_then(|s| s.0.last().cloned()) else {
+
+    let Some(mut base) = base else {
         return name.to_string();
     };
 
@@ -91,13 +416,50 @@ extern "C" {
     fn fetch_lua_module(url_ptr: *const c_char, len_out: *mut u32) -> *const c_uchar;
     fn free_lua_module(ptr: *const c_uchar, len: u32);
     fn get_last_fetch_error(len_out: *mut u32) -> *const c_uchar;
+    // 设置了获取超时时在 fetch_lua_module 之前调用，告知宿主本次获取的超时时间（毫秒）；
+    // 取消超时后再调用一次传入 0。从未设置超时的宿主不需要实现它
+    fn js_set_fetch_timeout(timeout_ms: u32);
+    // 阻塞等待指定的毫秒数（RDF 调用重试前使用）
+    fn js_sleep(ms: u32);
+    // 单调时钟（毫秒），用于执行超时检查；wasm 中 std::time::Instant 不可靠
+    fn js_now_ms() -> f64;
     
     // RDF 三元组存储 API（同步接口）
     fn js_rdf_insert(subject_ptr: *const c_char, predicate_ptr: *const c_char, object_json_ptr: *const c_char) -> *const c_char;
     fn js_rdf_delete(subject_ptr: *const c_char, predicate_ptr: *const c_char, object_json_ptr: *const c_char) -> *const c_char;
     fn js_rdf_query(pattern_json_ptr: *const c_char) -> *const c_char;
     fn js_rdf_batch_insert(triples_json_ptr: *const c_char) -> *const c_char;
+    fn js_rdf_exists(pattern_json_ptr: *const c_char) -> *const c_char;
+    // 返回匹配 pattern 的三元组数量（十进制数字字符串）
+    fn js_rdf_count(pattern_json_ptr: *const c_char) -> *const c_char;
+    // 原子地删除 subject + predicate 的所有三元组并插入新的三元组（lua_set_rdf_atomic_replace 开启时使用）
+    fn js_rdf_replace(subject_ptr: *const c_char, predicate_ptr: *const c_char, object_json_ptr: *const c_char) -> *const c_char;
+    // 事务：State.transaction 在执行函数前后调用，返回 "OK" 或 "ERROR:..."
+    fn js_rdf_begin() -> *const c_char;
+    fn js_rdf_commit() -> *const c_char;
+    fn js_rdf_rollback() -> *const c_char;
+    // 原子地把 subject + predicate 的数值加上 delta（JSON 数字），返回新值的 JSON
+    fn js_rdf_increment(subject_ptr: *const c_char, predicate_ptr: *const c_char, delta_json_ptr: *const c_char) -> *const c_char;
+    // 提交宿主缓冲的写入，返回 "OK" 或 "ERROR:..."
+    fn js_rdf_flush() -> *const c_char;
     fn js_rdf_free(ptr: *const c_char);
+
+    // 宿主的持久键值存储：js_cache_get 未命中时返回空指针，返回的字符串用 js_rdf_free 释放；
+    // js_cache_set 的 ttl_secs 为 0 表示由宿主决定有效期
+    fn js_cache_get(key_ptr: *const c_char) -> *const c_char;
+    fn js_cache_set(key_ptr: *const c_char, value_ptr: *const c_char, ttl_secs: u32);
+
+    // 当前用户信息（JSON 对象），没有上下文时返回空指针；返回的字符串用 js_rdf_free 释放
+    fn js_get_user_context() -> *const c_char;
+
+    // 开启 lua_set_custom_searcher 时由宿主一步完成模块解析和获取：
+    // 返回模块源码，"ERROR:" 开头表示失败，空指针表示找不到；返回的字符串用 js_rdf_free 释放
+    fn js_resolve_module(name_ptr: *const c_char) -> *const c_char;
+
+    // 用随机字节填满 buf，返回 0 表示成功，非 0 表示随机源不可用
+    fn js_random_bytes(buf: *mut u8, len: u32) -> i32;
+    // 当前 Unix 时间（秒，可带小数）
+    fn js_now_epoch() -> f64;
 }
 
 fn read_c_string(ptr: *const c_char) -> LuaResult<String> {
@@ -107,14 +469,22 @@ fn read_c_string(ptr: *const c_char) -> LuaResult<String> {
     unsafe { CStr::from_ptr(ptr) }
         .to_str()
         .map(|s| s.to_string())
-        .map_err(|e| LuaError::external(e))
+        .map_err(LuaError::external)
 }
 
 fn install_print_collector(lua: &Lua, buffer: &Rc<RefCell<String>>) -> LuaResult<()> {
     let buffer = Rc::clone(buffer);
+    let (prefix, suffix, empty_print_emits_newline) = RUN_CONFIG.with(|c| {
+        let config = c.borrow();
+        (config.print_line_prefix.clone(), config.print_line_suffix.clone(), config.empty_print_emits_newline)
+    });
     lua.globals().set(
         "print",
         lua.create_function(move |_lua, values: Variadic<LuaValue>| {
+            if values.is_empty() && !empty_print_emits_newline {
+                return Ok(());
+            }
+
             let mut output = String::new();
             let mut first = true;
 
@@ -125,13 +495,17 @@ fn install_print_collector(lua: &Lua, buffer: &Rc<RefCell<String>>) -> LuaResult
                     output.push('\t');
                 }
 
+                if let Some(text) = call_tostring_metamethod(value)? {
+                    output.push_str(&text);
+                    continue;
+                }
                 let value_str = match value {
                     LuaValue::String(s) => s.to_str()?.to_string(),
                     LuaValue::Number(n) => n.to_string(),
                     LuaValue::Integer(i) => i.to_string(),
                     LuaValue::Boolean(b) => b.to_string(),
                     LuaValue::Nil => "nil".to_string(),
-                    LuaValue::Table(_) => "table".to_string(),
+                    LuaValue::Table(t) => format_print_table(t, 1, &mut HashSet::new())?,
                     LuaValue::Function(_) => "function".to_string(),
                     LuaValue::Thread(_) => "thread".to_string(),
                     LuaValue::UserData(_) => "userdata".to_string(),
@@ -142,15 +516,144 @@ fn install_print_collector(lua: &Lua, buffer: &Rc<RefCell<String>>) -> LuaResult
                 output.push_str(&value_str);
             }
 
-            let line = output + "\n";
-            buffer.borrow_mut().push_str(&line);
-
-            Ok(())
+            // 为每一行加上配置的前缀/后缀（只作用于 print，不影响 io.write）
+            let mut lines = String::new();
+            for line in output.split('\n') {
+                lines.push_str(&prefix);
+                lines.push_str(line);
+                lines.push_str(&suffix);
+                lines.push('\n');
+            }
+            append_output(&mut buffer.borrow_mut(), &lines)
         })?,
     )?;
     Ok(())
 }
 
+/// 把 print / io.write 的文本追加到输出，超过 lua_set_output_limit 的上限时报错且不写入这段文本
+fn append_output(buffer: &mut String, text: &str) -> LuaResult<()> {
+    let limit = RUN_CONFIG.with(|c| c.borrow().output_limit);
+    if limit > 0 && buffer.len() + text.len() > limit {
+        return Err(LuaError::external(format!("output limit exceeded ({} bytes)", limit)));
+    }
+    buffer.push_str(text);
+    Ok(())
+}
+
+/// 表或 userdata 带有 __tostring 元方法时调用它并返回结果，否则返回 None
+fn call_tostring_metamethod(value: &LuaValue) -> LuaResult<Option<String>> {
+    let metamethod = match value {
+        LuaValue::Table(t) => t.metatable().and_then(|mt| mt.get::<Option<LuaFunction>>("__tostring").ok().flatten()),
+        LuaValue::UserData(ud) => ud.metatable().ok().and_then(|mt| mt.get::<Option<LuaFunction>>("__tostring").ok().flatten()),
+        _ => None,
+    };
+    match metamethod {
+        Some(tostring) => tostring.call::<String>(value.clone()).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// print 展开嵌套表的最大层数，更深的表显示为 {...}
+const PRINT_TABLE_MAX_DEPTH: usize = 4;
+
+/// 把表格式化为 print 输出，例如 `{1, 2, key = "value"}`
+/// 数组部分按位置输出，其余键按 `k = v` 输出并排序；有 __tostring 元方法时使用它的结果，
+/// 当前路径上重复出现的表（循环引用）显示为 <cycle>
+fn format_print_table(table: &LuaTable, depth: usize, visiting: &mut HashSet<usize>) -> LuaResult<String> {
+    if let Some(text) = call_tostring_metamethod(&LuaValue::Table(table.clone()))? {
+        return Ok(text);
+    }
+    let pointer = table.to_pointer() as usize;
+    if visiting.contains(&pointer) {
+        return Ok("<cycle>".to_string());
+    }
+    if depth > PRINT_TABLE_MAX_DEPTH {
+        return Ok("{...}".to_string());
+    }
+    visiting.insert(pointer);
+
+    let array_length = table.raw_len();
+    let mut parts = Vec::new();
+    for index in 1..=array_length {
+        parts.push(format_print_nested(&table.raw_get(index)?, depth, visiting)?);
+    }
+    let mut fields = Vec::new();
+    for pair in table.pairs::<LuaValue, LuaValue>() {
+        let (key, value) = pair?;
+        if let LuaValue::Integer(i) = key {
+            if i >= 1 && (i as usize) <= array_length {
+                continue;
+            }
+        }
+        let key = match &key {
+            LuaValue::String(s) => {
+                let s = s.to_str()?.to_string();
+                let is_name = s.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if is_name { s } else { format!("[{:?}]", s) }
+            }
+            other => format!("[{}]", format_print_nested(other, depth, visiting)?),
+        };
+        fields.push(format!("{} = {}", key, format_print_nested(&value, depth, visiting)?));
+    }
+    fields.sort();
+    parts.extend(fields);
+
+    visiting.remove(&pointer);
+    if parts.is_empty() {
+        return Ok("{}".to_string());
+    }
+    Ok(format!("{{{}}}", parts.join(", ")))
+}
+
+/// 格式化表中的值：字符串加引号，嵌套表继续展开
+fn format_print_nested(value: &LuaValue, depth: usize, visiting: &mut HashSet<usize>) -> LuaResult<String> {
+    Ok(match value {
+        LuaValue::String(s) => format!("{:?}", s.to_str()?.to_string()),
+        LuaValue::Table(t) => format_print_table(t, depth + 1, visiting)?,
+        LuaValue::Nil => "nil".to_string(),
+        LuaValue::Boolean(b) => b.to_string(),
+        LuaValue::Integer(i) => i.to_string(),
+        LuaValue::Number(n) => n.to_string(),
+        other => other.type_name().to_string(),
+    })
+}
+
+/// warn() 的收集状态：是否收集，以及分段发出的警告中尚未结束的部分
+struct WarnCollector {
+    enabled: bool,
+    pending: String,
+}
+
+/// 接管 Lua 5.4 的 warn()，把警告收集到结果信封的 "warnings" 中
+/// 默认收集；"@off" 停止收集，"@on" 恢复收集，其他以 @ 开头的控制消息被忽略
+fn install_warn_collector(lua: &Lua) -> LuaResult<()> {
+    lua.set_app_data(WarnCollector { enabled: true, pending: String::new() });
+    lua.set_warning_function(|lua, message, incomplete| {
+        let completed = {
+            let Some(mut collector) = lua.app_data_mut::<WarnCollector>() else {
+                return Ok(());
+            };
+            collector.pending.push_str(message);
+            if incomplete {
+                return Ok(());
+            }
+            let text = std::mem::take(&mut collector.pending);
+            match text.as_str() {
+                "@on" => collector.enabled = true,
+                "@off" => collector.enabled = false,
+                _ => {}
+            }
+            (collector.enabled && !text.starts_with('@')).then_some(text)
+        };
+        if let Some(text) = completed {
+            push_warning(lua, text);
+        }
+        Ok(())
+    });
+    Ok(())
+}
+
 fn install_io_write_collector(lua: &Lua, buffer: &Rc<RefCell<String>>) -> LuaResult<()> {
     let buffer = Rc::clone(buffer);
     
@@ -164,26 +667,76 @@ fn install_io_write_collector(lua: &Lua, buffer: &Rc<RefCell<String>>) -> LuaRes
         }
     };
     
+    // 与标准 Lua 相同只接受字符串和数字；开启 lua_set_iowrite_coerce 后 nil 和布尔值也像 print 一样输出
+    // 带有 __tostring 元方法的表和 userdata 在两种模式下都使用元方法的结果
+    let coerce = RUN_CONFIG.with(|c| c.borrow().iowrite_coerce);
+
     // 替换 io.write 函数
     io.set(
         "write",
         lua.create_function(move |_lua, values: Variadic<LuaValue>| {
             let mut output = String::new();
 
-            for value in values.iter() {
+            for (index, value) in values.iter().enumerate() {
+                if let Some(text) = call_tostring_metamethod(value)? {
+                    output.push_str(&text);
+                    continue;
+                }
                 let value_str = match value {
                     LuaValue::String(s) => s.to_str()?.to_string(),
                     LuaValue::Number(n) => n.to_string(),
                     LuaValue::Integer(i) => i.to_string(),
-                    LuaValue::Boolean(b) => b.to_string(),
-                    LuaValue::Nil => "nil".to_string(),
-                    _ => return Err(LuaError::external("io.write expects string or number")),
+                    LuaValue::Boolean(b) if coerce => b.to_string(),
+                    LuaValue::Nil if coerce => "nil".to_string(),
+                    other => {
+                        return Err(LuaError::external(format!(
+                            "bad argument #{} to 'write' (string expected, got {})",
+                            index + 1,
+                            other.type_name()
+                        )))
+                    }
                 };
                 output.push_str(&value_str);
             }
 
-            buffer.borrow_mut().push_str(&output);
+            append_output(&mut buffer.borrow_mut(), &output)
+        })?,
+    )?;
+
+    // io.stderr:write(...) - 诊断输出不进入 output，每次调用作为一条警告加入 "warnings"（去掉末尾的换行）
+    let stderr = lua.create_table()?;
+    stderr.set(
+        "write",
+        lua.create_function(|lua, (this, values): (LuaValue, Variadic<LuaValue>)| {
+            let mut text = String::new();
+            for (index, value) in values.iter().enumerate() {
+                match value {
+                    LuaValue::String(s) => text.push_str(&s.to_str()?),
+                    LuaValue::Integer(i) => text.push_str(&i.to_string()),
+                    LuaValue::Number(n) => text.push_str(&n.to_string()),
+                    other => {
+                        return Err(LuaError::external(format!(
+                            "bad argument #{} to 'write' (string expected, got {})",
+                            index + 1,
+                            other.type_name()
+                        )))
+                    }
+                }
+            }
+            let text = text.trim_end_matches('\n');
+            if !text.is_empty() {
+                push_warning(lua, text);
+            }
+            Ok(this)
+        })?,
+    )?;
+    io.set("stderr", stderr)?;
 
+    // io.writeBytes(str) - 原样写入字节到二进制输出，不经过 UTF-8 转换
+    io.set(
+        "writeBytes",
+        lua.create_function(|_lua, data: LuaString| {
+            BINARY_OUTPUT.with(|b| b.borrow_mut().extend_from_slice(&data.as_bytes()));
             Ok(())
         })?,
     )?;
@@ -191,11 +744,106 @@ fn install_io_write_collector(lua: &Lua, buffer: &Rc<RefCell<String>>) -> LuaRes
     Ok(())
 }
 
+/// 宿主在获取超时时返回的错误前缀
+const FETCH_TIMEOUT_PREFIX: &str = "TIMEOUT:";
+
+const HOST_REENTRY_ERROR: &str = "host re-entered during module fetch";
+
+#[derive(Clone, Copy, PartialEq)]
+enum ModuleFetchState {
+    Idle,
+    Fetching,
+    /// 获取过程中宿主重新调用了运行器
+    Reentered,
+}
+
+/// 标记正在调用 fetch_lua_module，结束时恢复空闲状态
+struct ModuleFetchGuard;
+
+impl ModuleFetchGuard {
+    fn new() -> Self {
+        MODULE_FETCH.with(|state| state.set(ModuleFetchState::Fetching));
+        ModuleFetchGuard
+    }
+
+    fn reentered(&self) -> bool {
+        MODULE_FETCH.with(|state| state.get()) == ModuleFetchState::Reentered
+    }
+}
+
+impl Drop for ModuleFetchGuard {
+    fn drop(&mut self) {
+        MODULE_FETCH.with(|state| state.set(ModuleFetchState::Idle));
+    }
+}
+
+/// 在模块获取过程中被宿主重新调用时返回错误，并让外层的获取也失败
+fn check_host_reentry() -> Result<(), String> {
+    MODULE_FETCH.with(|state| {
+        if state.get() == ModuleFetchState::Idle {
+            return Ok(());
+        }
+        state.set(ModuleFetchState::Reentered);
+        Err(HOST_REENTRY_ERROR.to_string())
+    })
+}
+
+/// 通过宿主的 js_resolve_module 解析并获取模块，不应用别名、命名空间规则和虚拟文件系统
+fn host_resolve_module(name: &str) -> LuaResult<ResolvedModuleSource> {
+    require_capability(Capability::Fetch)?;
+    let name_c = CString::new(name).map_err(LuaError::external)?;
+    let ptr = unsafe { js_resolve_module(name_c.as_ptr()) };
+    if ptr.is_null() {
+        return Err(LuaError::external(format!("module '{}' not found", name)));
+    }
+    let source = take_rdf_result(ptr).map_err(LuaError::external)?;
+    Ok(ResolvedModuleSource {
+        name: name.to_string(),
+        source: prepare_module_source(source),
+    })
+}
+
 fn fetch_module_source(lua: &Lua, name: &str) -> LuaResult<ResolvedModuleSource> {
+    if RUN_CONFIG.with(|c| c.borrow().custom_searcher) {
+        return host_resolve_module(name);
+    }
     let resolved_name = resolve_module_spec(lua, name);
-    let name_c = CString::new(resolved_name.clone()).map_err(|e| LuaError::external(e))?;
+
+    // 优先查找挂载的虚拟文件系统（先按解析后的名称，再按原始名称）
+    let mounted = RUN_CONFIG.with(|c| {
+        let config = c.borrow();
+        config.vfs.get(&resolved_name).or_else(|| config.vfs.get(name)).cloned()
+    });
+    if let Some(source) = mounted {
+        return Ok(ResolvedModuleSource {
+            name: resolved_name,
+            source: prepare_module_source(source),
+        });
+    }
+
+    require_capability(Capability::Fetch)?;
+    if let Some(source) = cached_module_source(lua, &resolved_name) {
+        return Ok(ResolvedModuleSource {
+            name: resolved_name,
+            source: prepare_module_source(source),
+        });
+    }
+    let name_c = CString::new(resolved_name.clone()).map_err(LuaError::external)?;
+    let timeout_ms = RUN_CONFIG.with(|c| c.borrow().fetch_timeout_ms);
     let mut len: u32 = 0;
+    let guard = ModuleFetchGuard::new();
+    if timeout_ms > 0 || FETCH_TIMEOUT_SENT.with(|sent| sent.get()) > 0 {
+        unsafe { js_set_fetch_timeout(timeout_ms) };
+        FETCH_TIMEOUT_SENT.with(|sent| sent.set(timeout_ms));
+    }
     let ptr = unsafe { fetch_lua_module(name_c.as_ptr(), &mut len) };
+    if guard.reentered() {
+        if !ptr.is_null() {
+            unsafe { free_lua_module(ptr, len) };
+        }
+        return Err(LuaError::external(HOST_REENTRY_ERROR));
+    }
+    drop(guard);
     if ptr.is_null() {
         let message = unsafe {
             let mut err_len: u32 = 0;
@@ -209,25 +857,135 @@ fn fetch_module_source(lua: &Lua, name: &str) -> LuaResult<ResolvedModuleSource>
                 msg
             }
         };
+        // 宿主以 "TIMEOUT:" 开头的错误表示获取超时
+        if let Some(detail) = message.strip_prefix(FETCH_TIMEOUT_PREFIX) {
+            return Err(LuaError::external(format!(
+                "module fetch timed out after {} ms: {}",
+                timeout_ms,
+                detail.trim()
+            )));
+        }
         return Err(LuaError::external(message));
     }
 
     let slice = unsafe { slice::from_raw_parts(ptr, len as usize) };
     let source = std::str::from_utf8(slice)
         .map(|s| s.to_string())
-        .map_err(|e| LuaError::external(e.to_string()))?;
+        .map_err(|e| LuaError::external(e.to_string()));
     unsafe { free_lua_module(ptr, len) };
+    let source = source?;
+    cache_module_source(lua, &resolved_name, &source);
     Ok(ResolvedModuleSource {
         name: resolved_name,
-        source,
+        source: prepare_module_source(source),
     })
 }
 
+/// 从宿主获取成功的模块源码（解析后的名称 -> 未经 prepare_module_source 处理的源码）
+/// 保存在上下文中，持久上下文的后续运行也会复用；获取失败的结果不缓存
+struct ModuleSourceCache {
+    generation: u32,
+    sources: HashMap<String, String>,
+}
+
+/// 查找缓存的模块源码，缓存在 lua_clear_module_cache 之后创建的才有效
+fn cached_module_source(lua: &Lua, resolved_name: &str) -> Option<String> {
+    let generation = MODULE_CACHE_GENERATION.with(|g| g.get());
+    let cache = lua.app_data_ref::<ModuleSourceCache>()?;
+    if cache.generation != generation {
+        return None;
+    }
+    cache.sources.get(resolved_name).cloned()
+}
+
+fn cache_module_source(lua: &Lua, resolved_name: &str, source: &str) {
+    let generation = MODULE_CACHE_GENERATION.with(|g| g.get());
+    let stale = lua
+        .app_data_ref::<ModuleSourceCache>()
+        .is_none_or(|cache| cache.generation != generation);
+    if stale {
+        lua.set_app_data(ModuleSourceCache { generation, sources: HashMap::new() });
+    }
+    if let Some(mut cache) = lua.app_data_mut::<ModuleSourceCache>() {
+        cache.sources.insert(resolved_name.to_string(), source.to_string());
+    }
+}
+
+/// 清空模块源码缓存，宿主在模块被编辑后调用，之后的 require 会重新向宿主获取
+#[no_mangle]
+pub extern "C" fn lua_clear_module_cache() {
+    MODULE_CACHE_GENERATION.with(|g| g.set(g.get().wrapping_add(1)));
+}
+
+/// 加载前按配置清理模块源码
+fn prepare_module_source(source: String) -> String {
+    let (strip_wrappers, normalize_newlines) = RUN_CONFIG.with(|c| {
+        let config = c.borrow();
+        (config.strip_module_wrappers, config.normalize_newlines)
+    });
+    let source = if normalize_newlines && source.contains('\r') {
+        source.replace("\r\n", "\n").replace('\r', "\n")
+    } else {
+        source
+    };
+    if strip_wrappers {
+        strip_module_wrappers(&source)
+    } else {
+        source
+    }
+}
+
+/// 去掉模板附加在模块源码外的 <nowiki> 包装
+///
+/// 只做以下处理：去掉首尾空白；如果以 `<nowiki>` 开头则去掉这个开标签，
+/// 如果以 `</nowiki>` 结尾则去掉这个闭标签（标签名不区分大小写）；最后再去掉首尾空白。
+/// 源码中间出现的标签以及其他 HTML 不做任何改动。
+fn strip_module_wrappers(source: &str) -> String {
+    const OPEN: &str = "<nowiki>";
+    const CLOSE: &str = "</nowiki>";
+    let mut text = source.trim();
+    if text.get(..OPEN.len()).is_some_and(|head| head.eq_ignore_ascii_case(OPEN)) {
+        text = &text[OPEN.len()..];
+    }
+    let close_start = text.len().saturating_sub(CLOSE.len());
+    if text.get(close_start..).is_some_and(|tail| tail.eq_ignore_ascii_case(CLOSE)) {
+        text = &text[..close_start];
+    }
+    text.trim().to_string()
+}
+
+/// 本次运行中加载过的不同模块（按解析后的名称）
+#[derive(Default)]
+struct LoadedModules(HashSet<String>);
+
+/// 记录一次模块加载，超过 lua_set_max_modules_per_run 的限制时报错
+fn record_module_load(lua: &Lua, resolved_name: &str) -> LuaResult<()> {
+    let limit = RUN_CONFIG.with(|c| c.borrow().max_modules_per_run);
+    if lua.app_data_ref::<LoadedModules>().is_none() {
+        lua.set_app_data(LoadedModules::default());
+    }
+    let mut loaded = lua.app_data_mut::<LoadedModules>().expect("LoadedModules was just inserted");
+    if loaded.0.contains(resolved_name) {
+        return Ok(());
+    }
+    if limit > 0 && loaded.0.len() >= limit {
+        return Err(LuaError::external(format!(
+            "too many modules loaded (limit {}) while loading '{}'",
+            limit, resolved_name
+        )));
+    }
+    loaded.0.insert(resolved_name.to_string());
+    Ok(())
+}
+
 fn install_require_loader(lua: &Lua) -> LuaResult<()> {
     let package: Table = lua.globals().get("package")?;
     let searchers: Table = package.get("searchers")?;
 
     let loader = lua.create_function(|lua, module: String| -> LuaResult<LuaValue> {
+        // 超出模块数量限制时直接报错，而不是交给后续的 searcher
+        record_module_load(lua, &resolve_module_spec(lua, &module))?;
+
         let resolved = match fetch_module_source(lua, &module) {
             Ok(resolved) => resolved,
             Err(err) => {
@@ -260,142 +1018,856 @@ fn install_require_loader(lua: &Lua) -> LuaResult<()> {
     Ok(())
 }
 
-/// 安装 RDF 三元组存储 API 到 Lua 全局环境
-fn install_rdf_api(lua: &Lua) -> LuaResult<()> {
-    let state_table = lua.create_table()?;
-    
-    // State.insert(subject, predicate, object) - 插入三元组
-    let insert_fn = lua.create_function(|lua, (subject, predicate, object): (String, String, LuaValue)| -> LuaResult<()> {
-        // 将 object 转为 JSON
-        let object_json = lua_value_to_json(lua, &object)?;
-        let subject_c = CString::new(subject).map_err(|e| LuaError::external(e))?;
-        let predicate_c = CString::new(predicate).map_err(|e| LuaError::external(e))?;
-        let object_c = CString::new(object_json).map_err(|e| LuaError::external(e))?;
-        
-        let result_ptr = unsafe { js_rdf_insert(subject_c.as_ptr(), predicate_c.as_ptr(), object_c.as_ptr()) };
-        let result = read_c_string(result_ptr)?;
-        unsafe { js_rdf_free(result_ptr) };
-        
-        if result.starts_with("ERROR:") {
-            return Err(LuaError::external(result.trim_start_matches("ERROR:")));
+/// 读取并释放 RDF 宿主调用的返回值；宿主以 "ERROR:" 前缀表示失败
+fn take_rdf_result(ptr: *const c_char) -> Result<String, String> {
+    let result = read_c_string(ptr).map_err(|e| e.to_string());
+    unsafe { js_rdf_free(ptr) };
+    let result = result?;
+    match result.strip_prefix("ERROR:") {
+        Some(message) => Err(message.to_string()),
+        None => Ok(result),
+    }
+}
+
+/// 宿主在错误信息前加上该标记表示错误是暂时的，可以重试，例如 "ERROR:RETRYABLE:connection reset"
+const RDF_RETRYABLE_PREFIX: &str = "RETRYABLE:";
+
+/// 执行一次 RDF 宿主调用，遇到可重试错误时按 lua_set_rdf_retry 的设置等待后重试
+/// 其他错误立即返回；重试次数用完后返回最后一次的错误（去掉 RETRYABLE: 标记）
+fn with_rdf_retry(mut call: impl FnMut() -> Result<String, String>) -> Result<String, String> {
+    let (retries, backoff_ms) = RUN_CONFIG.with(|c| {
+        let config = c.borrow();
+        (config.rdf_retry_count, config.rdf_retry_backoff_ms)
+    });
+    let mut attempt = 0;
+    loop {
+        match call() {
+            Err(err) if err.starts_with(RDF_RETRYABLE_PREFIX) => {
+                if attempt >= retries {
+                    return Err(err[RDF_RETRYABLE_PREFIX.len()..].to_string());
+                }
+                let delay = backoff_ms.saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX));
+                // 宿主没有实现 js_sleep 时立即重试
+                if delay > 0 && host_import_available("sleep") {
+                    unsafe { js_sleep(delay) };
+                }
+                attempt += 1;
+            }
+            result => return result,
         }
-        Ok(())
-    })?;
-    state_table.set("insert", insert_fn)?;
+    }
+}
+
+fn rdf_c_arg(value: &str) -> Result<CString, String> {
+    CString::new(value).map_err(|e| e.to_string())
+}
+
+// 写入成功后使缓存的查询结果失效
+fn host_rdf_insert(lua: &Lua, subject: &str, predicate: &str, object_json: &str) -> Result<String, String> {
+    let (subject_c, predicate_c, object_c) = (rdf_c_arg(subject)?, rdf_c_arg(predicate)?, rdf_c_arg(object_json)?);
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_insert(subject_c.as_ptr(), predicate_c.as_ptr(), object_c.as_ptr()) }))?;
+    invalidate_rdf_query_cache(lua);
+    Ok(result)
+}
+
+fn host_rdf_delete(lua: &Lua, subject: &str, predicate: &str, object_json: &str) -> Result<String, String> {
+    let (subject_c, predicate_c, object_c) = (rdf_c_arg(subject)?, rdf_c_arg(predicate)?, rdf_c_arg(object_json)?);
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_delete(subject_c.as_ptr(), predicate_c.as_ptr(), object_c.as_ptr()) }))?;
+    invalidate_rdf_query_cache(lua);
+    Ok(result)
+}
+
+fn host_rdf_replace(lua: &Lua, subject: &str, predicate: &str, object_json: &str) -> Result<String, String> {
+    let (subject_c, predicate_c, object_c) = (rdf_c_arg(subject)?, rdf_c_arg(predicate)?, rdf_c_arg(object_json)?);
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_replace(subject_c.as_ptr(), predicate_c.as_ptr(), object_c.as_ptr()) }))?;
+    invalidate_rdf_query_cache(lua);
+    Ok(result)
+}
+
+fn host_rdf_increment(lua: &Lua, subject: &str, predicate: &str, delta_json: &str) -> Result<String, String> {
+    let (subject_c, predicate_c, delta_c) = (rdf_c_arg(subject)?, rdf_c_arg(predicate)?, rdf_c_arg(delta_json)?);
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_increment(subject_c.as_ptr(), predicate_c.as_ptr(), delta_c.as_ptr()) }))?;
+    invalidate_rdf_query_cache(lua);
+    Ok(result)
+}
+
+fn host_rdf_query(pattern_json: &serde_json::Value) -> Result<String, String> {
+    let pattern_c = rdf_c_arg(&pattern_json.to_string())?;
+    with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_query(pattern_c.as_ptr()) }))
+}
+
+fn host_rdf_batch_insert(lua: &Lua, triples_json: &str) -> Result<String, String> {
+    let triples_c = rdf_c_arg(triples_json)?;
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_batch_insert(triples_c.as_ptr()) }))?;
+    invalidate_rdf_query_cache(lua);
+    Ok(result)
+}
+
+/// 保存查询缓存版本号的键，写入时递增，版本号是缓存键的一部分，因此旧的缓存项不再被命中
+const RDF_CACHE_GENERATION_KEY: &str = "rdf-query-generation";
+
+/// 是否使用宿主键值存储缓存查询结果（需要开启 lua_set_rdf_cache_backend，并且允许 cache 能力）
+fn rdf_cache_enabled() -> bool {
+    RUN_CONFIG.with(|c| c.borrow().rdf_cache_backend) && require_capability(Capability::Cache).is_ok()
+}
+
+/// 从宿主键值存储读取，未命中或出错时返回 None
+fn host_cache_get(key: &str) -> Option<String> {
+    let key_c = rdf_c_arg(key).ok()?;
+    let ptr = unsafe { js_cache_get(key_c.as_ptr()) };
+    if ptr.is_null() {
+        return None;
+    }
+    take_rdf_result(ptr).ok()
+}
+
+fn host_cache_set(key: &str, value: &str, ttl_secs: u32) {
+    if let (Ok(key_c), Ok(value_c)) = (rdf_c_arg(key), rdf_c_arg(value)) {
+        unsafe { js_cache_set(key_c.as_ptr(), value_c.as_ptr(), ttl_secs) };
+    }
+}
+
+/// 本次运行使用的查询缓存版本号，每次运行只从宿主键值存储读取一次
+struct RdfCacheGeneration(u64);
+
+/// 当前的查询缓存版本号；版本号保存在宿主键值存储中，因此跨实例持久
+fn rdf_cache_generation(lua: &Lua) -> u64 {
+    if let Some(generation) = lua.app_data_ref::<RdfCacheGeneration>() {
+        return generation.0;
+    }
+    let generation = host_cache_get(RDF_CACHE_GENERATION_KEY)
+        .and_then(|generation| generation.trim().parse().ok())
+        .unwrap_or(0);
+    lua.set_app_data(RdfCacheGeneration(generation));
+    generation
+}
+
+/// 在本地递增版本号并写回宿主，使之前缓存的查询结果全部失效
+fn invalidate_rdf_query_cache(lua: &Lua) {
+    if !rdf_cache_enabled() {
+        return;
+    }
+    let generation = rdf_cache_generation(lua).wrapping_add(1);
+    lua.set_app_data(RdfCacheGeneration(generation));
+    host_cache_set(RDF_CACHE_GENERATION_KEY, &generation.to_string(), 0);
+}
+
+/// 64 位 FNV-1a 哈希，缓存键需要在不同版本和实例之间保持稳定
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// 查询三元组，开启缓存时先读宿主键值存储，未命中时查询并按配置的有效期写回
+/// 返回查询结果以及是否命中了缓存
+fn cached_rdf_query(lua: &Lua, pattern_json: &serde_json::Value) -> Result<(String, bool), String> {
+    if !rdf_cache_enabled() {
+        return Ok((host_rdf_query(pattern_json)?, false));
+    }
+    let pattern = pattern_json.to_string();
+    let key = format!("rdf-query:{}:{:016x}", rdf_cache_generation(lua), fnv1a_64(pattern.as_bytes()));
+    if let Some(result) = host_cache_get(&key) {
+        return Ok((result, true));
+    }
+    let result = host_rdf_query(pattern_json)?;
+    let ttl_secs = RUN_CONFIG.with(|c| c.borrow().rdf_cache_ttl_secs);
+    host_cache_set(&key, &result, ttl_secs);
+    Ok((result, false))
+}
+
+/// 询问宿主是否存在匹配的三元组；宿主返回 "true"/"false" 或匹配数量
+fn host_rdf_exists(pattern_json: &serde_json::Value) -> Result<bool, String> {
+    let pattern_c = rdf_c_arg(&pattern_json.to_string())?;
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_exists(pattern_c.as_ptr()) }))?;
+    match result.trim() {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        other => other
+            .parse::<u64>()
+            .map(|count| count > 0)
+            .map_err(|_| format!("unexpected exists result: {}", other)),
+    }
+}
+
+/// 询问宿主匹配的三元组数量，不需要传回三元组本身
+fn host_rdf_count(pattern_json: &serde_json::Value) -> Result<u64, String> {
+    let pattern_c = rdf_c_arg(&pattern_json.to_string())?;
+    let result = with_rdf_retry(|| take_rdf_result(unsafe { js_rdf_count(pattern_c.as_ptr()) }))?;
+    result
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("unexpected count result: {}", result.trim()))
+}
+
+/// 截断过长的字符串用于错误信息（按字符截断，避免切断 UTF-8）
+fn truncate_for_error(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}...", &text[..idx]),
+        None => text.to_string(),
+    }
+}
+
+/// 构造带有出错三元组信息的 RDF 错误，例如 `RDF insert failed for <S> <P> "o": host error`
+fn rdf_triple_error(op: &str, subject: &str, predicate: &str, object_json: Option<&str>, message: &str) -> LuaError {
+    let object = object_json
+        .map(|o| format!(" {}", truncate_for_error(o, 64)))
+        .unwrap_or_default();
+    LuaError::external(format!("RDF {} failed for <{}> <{}>{}: {}", op, subject, predicate, object, message))
+}
+
+/// 检查序列化后的 object 是否超过 lua_set_rdf_max_object_bytes 设置的上限
+fn check_rdf_object_size(op: &str, subject: &str, predicate: &str, object_json: &str) -> LuaResult<()> {
+    let limit = RUN_CONFIG.with(|c| c.borrow().rdf_max_object_bytes);
+    if limit > 0 && object_json.len() > limit {
+        let message = format!("RDF object too large ({} bytes, limit {})", object_json.len(), limit);
+        return Err(rdf_triple_error(op, subject, predicate, None, &message));
+    }
+    Ok(())
+}
+
+/// State.query 的 orderBy 可以使用的字段
+const QUERY_ORDER_FIELDS: [&str; 3] = ["subject", "predicate", "object"];
+
+/// 从 Lua pattern table 构造传给宿主的查询 pattern JSON
+/// pattern 是一个 table: {subject = "...", predicate = "...", object = ...}，任意字段可以为 nil（通配符）
+fn build_pattern_json(lua: &Lua, pattern: &LuaTable) -> LuaResult<serde_json::Value> {
+    let subject: Option<String> = pattern.get("subject")?;
+    let predicate: Option<String> = pattern.get("predicate")?;
+    let object: Option<LuaValue> = pattern.get("object")?;
+    check_iri_lengths("query", subject.as_deref(), predicate.as_deref())?;
     
-    // State.delete(subject, predicate, object?) - 删除三元组
-    let delete_fn = lua.create_function(|lua, (subject, predicate, object): (String, String, Option<LuaValue>)| -> LuaResult<()> {
-        let object_json = match object {
-            Some(val) => lua_value_to_json(lua, &val)?,
-            None => "null".to_string(),
-        };
-        
-        let subject_c = CString::new(subject).map_err(|e| LuaError::external(e))?;
-        let predicate_c = CString::new(predicate).map_err(|e| LuaError::external(e))?;
-        let object_c = CString::new(object_json).map_err(|e| LuaError::external(e))?;
-        
-        let result_ptr = unsafe { js_rdf_delete(subject_c.as_ptr(), predicate_c.as_ptr(), object_c.as_ptr()) };
-        let result = read_c_string(result_ptr)?;
-        unsafe { js_rdf_free(result_ptr) };
-        
-        if result.starts_with("ERROR:") {
-            return Err(LuaError::external(result.trim_start_matches("ERROR:")));
+    // 将 Lua 值直接转换为 serde_json::Value，避免双重序列化
+    let object_json = object.as_ref()
+        .map(|v| rdf_object_to_json(lua, v))
+        .transpose()?;
+    
+    Ok(serde_json::json!({
+        "subject": subject,
+        "predicate": predicate,
+        "object": object_json
+    }))
+}
+
+/// 本次运行中 State 函数的读写统计，以 "rdfStats" 加入结果信封
+/// inserted 为写入的三元组数，deleted 为删除操作次数（宿主不返回删除的条数），
+/// queried 为向宿主发出的查询次数（包括 get、exists 和 queryIter 的每一页），queryRows 为这些查询返回的行数，
+/// cacheHits 为由 lua_set_rdf_cache_backend 的缓存直接返回、没有发出查询的 State.query 次数
+#[derive(Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RdfStats {
+    inserted: u64,
+    deleted: u64,
+    queried: u64,
+    query_rows: u64,
+    cache_hits: u64,
+}
+
+/// State.transaction 正在执行时存在，用于拒绝嵌套事务
+struct ActiveTransaction;
+
+/// 本次运行中通过 State.emit 产生的三元组，以 "emittedTriples" 数组加入结果信封
+#[derive(Default)]
+struct EmittedTriples(Vec<serde_json::Value>);
+
+/// 更新本次运行的 RDF 统计（首次使用时创建）
+fn record_rdf_stats(lua: &Lua, update: impl FnOnce(&mut RdfStats)) {
+    if lua.app_data_ref::<RdfStats>().is_none() {
+        lua.set_app_data(RdfStats::default());
+    }
+    if let Some(mut stats) = lua.app_data_mut::<RdfStats>() {
+        update(&mut stats);
+    }
+}
+
+/// 记录一次返回 rows 行的查询
+fn record_rdf_query(lua: &Lua, rows: usize) {
+    record_rdf_stats(lua, |stats| {
+        stats.queried += 1;
+        stats.query_rows += rows as u64;
+    });
+}
+
+/// 把三元组按 subject 分组为 JSON-LD 文档：{"@context": 前缀映射, "@graph": [节点...]}
+/// 每个节点为 {"@id": subject, predicate: object}，同一谓词有多个值时为数组；
+/// table 类型的 object 作为 {"@value": ..., "@type": "@json"} 输出，没有配置前缀时省略 @context
+fn triples_to_json_ld(triples: &[serde_json::Value], prefixes: &HashMap<String, String>) -> serde_json::Value {
+    let mut nodes: Vec<serde_json::Map<String, serde_json::Value>> = Vec::new();
+    let mut node_index: HashMap<&str, usize> = HashMap::new();
+    for triple in triples {
+        let (Some(subject), Some(predicate)) = (triple["subject"].as_str(), triple["predicate"].as_str()) else {
+            continue;
+        };
+        let object = match &triple["object"] {
+            value @ (serde_json::Value::Array(_) | serde_json::Value::Object(_)) => {
+                serde_json::json!({ "@value": value, "@type": "@json" })
+            }
+            value => value.clone(),
+        };
+        let index = *node_index.entry(subject).or_insert_with(|| {
+            let mut node = serde_json::Map::new();
+            node.insert("@id".to_string(), subject.into());
+            nodes.push(node);
+            nodes.len() - 1
+        });
+        match nodes[index].get_mut(predicate) {
+            Some(serde_json::Value::Array(values)) => values.push(object),
+            Some(existing) => *existing = serde_json::Value::Array(vec![existing.take(), object]),
+            None => {
+                nodes[index].insert(predicate.to_string(), object);
+            }
+        }
+    }
+
+    let mut document = serde_json::Map::new();
+    if !prefixes.is_empty() {
+        document.insert("@context".to_string(), serde_json::json!(prefixes));
+    }
+    document.insert("@graph".to_string(), nodes.into_iter().map(serde_json::Value::Object).collect());
+    serde_json::Value::Object(document)
+}
+
+/// State.queryIter 的分页状态
+struct QueryPager {
+    pattern_json: serde_json::Value,
+    page_size: usize,
+    offset: usize,
+    buffer: VecDeque<serde_json::Value>,
+    exhausted: bool,
+}
+
+impl QueryPager {
+    /// 返回下一个三元组；当前页用完时向宿主请求下一页（limit/offset）
+    fn next_triple(&mut self, lua: &Lua) -> Result<Option<serde_json::Value>, String> {
+        if self.buffer.is_empty() && !self.exhausted {
+            let mut page_pattern = self.pattern_json.clone();
+            page_pattern["limit"] = self.page_size.into();
+            page_pattern["offset"] = self.offset.into();
+            let result = host_rdf_query(&page_pattern)?;
+            let mut rows: Vec<serde_json::Value> = serde_json::from_str(&result)
+                .map_err(|e| format!("JSON parse error: {}", e))?;
+            record_rdf_query(lua, rows.len());
+            decode_rdf_booleans(&mut rows);
+            // 返回的行数少于一页说明已经没有更多结果
+            self.exhausted = rows.len() < self.page_size;
+            self.offset += rows.len();
+            self.buffer.extend(rows);
+        }
+        Ok(self.buffer.pop_front())
+    }
+}
+
+/// 通过 State.setValidator 注册的三元组校验函数
+struct RdfValidator(LuaFunction);
+
+/// 简单的 IRI 语法检查：必须以 scheme（字母开头，后接字母、数字、+、-、.）加冒号开始，
+/// 冒号后不能为空，且不能包含空白、控制字符或 <>"{}|\^` 等不允许出现的字符
+/// 合法时返回 None，否则返回原因
+fn iri_error(iri: &str) -> Option<String> {
+    let Some((scheme, rest)) = iri.split_once(':') else {
+        return Some("missing scheme".to_string());
+    };
+    let mut scheme_chars = scheme.chars();
+    let valid_scheme = scheme_chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && scheme_chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !valid_scheme {
+        return Some(format!("invalid scheme '{}'", scheme));
+    }
+    if rest.is_empty() {
+        return Some("empty after scheme".to_string());
+    }
+    iri.chars()
+        .find(|c| c.is_whitespace() || c.is_control() || "<>\"{}|\\^`".contains(*c))
+        .map(|c| format!("illegal character {:?}", c))
+}
+
+/// 检查 subject 和 predicate 是否超过 lua_set_rdf_max_iri_len 设置的长度上限，None 表示通配符
+fn check_iri_lengths(op: &str, subject: Option<&str>, predicate: Option<&str>) -> LuaResult<()> {
+    let limit = RUN_CONFIG.with(|c| c.borrow().rdf_max_iri_len);
+    if limit == 0 {
+        return Ok(());
+    }
+    for (role, value) in [("subject", subject), ("predicate", predicate)] {
+        if let Some(value) = value.filter(|v| v.len() > limit) {
+            return Err(LuaError::external(format!(
+                "RDF {} failed: IRI too long ({} is {} bytes, limit {})",
+                op,
+                role,
+                value.len(),
+                limit
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// 开启 lua_set_rdf_strict_iri 时，拒绝 subject 或 predicate 不是合法 IRI 的写操作
+fn check_strict_iris(op: &str, subject: &str, predicate: &str) -> LuaResult<()> {
+    if !RUN_CONFIG.with(|c| c.borrow().rdf_strict_iri) {
+        return Ok(());
+    }
+    for (role, value) in [("subject", subject), ("predicate", predicate)] {
+        if let Some(reason) = iri_error(value) {
+            let message = format!("{} is not a valid IRI ({})", role, reason);
+            return Err(rdf_triple_error(op, subject, predicate, None, &message));
+        }
+    }
+    Ok(())
+}
+
+/// 写入前调用校验函数：返回 false 或抛出错误都会拒绝写入，可以额外返回拒绝原因
+/// 校验函数在保护模式下调用，它的错误只会变成本次写入的错误
+fn validate_triple(lua: &Lua, op: &str, subject: &str, predicate: &str, object: &LuaValue) -> LuaResult<()> {
+    let Some(validator) = lua.app_data_ref::<RdfValidator>().map(|v| v.0.clone()) else {
+        return Ok(());
+    };
+    let reason = match validator.call::<(LuaValue, Option<String>)>((subject, predicate, object.clone())) {
+        Ok((LuaValue::Boolean(false), reason)) => reason.unwrap_or_else(|| "validator returned false".to_string()),
+        Ok(_) => return Ok(()),
+        Err(e) => e.to_string(),
+    };
+    Err(LuaError::external(format!("RDF {} rejected by validator for <{}> <{}>: {}", op, subject, predicate, reason)))
+}
+
+/// 安装 RDF 三元组存储 API 到 Lua 全局环境
+/// 把查询结果转为 Lua 数组，相同的 subject/predicate 字符串只创建一次 Lua 字符串
+/// （Lua 只自动复用不超过 40 字节的短字符串，较长的 IRI 每次都会单独分配）
+fn triples_to_lua_interned(lua: &Lua, triples: Vec<serde_json::Value>) -> LuaResult<LuaValue> {
+    let mut interned: HashMap<String, LuaString> = HashMap::new();
+    let rows = lua.create_table_with_capacity(triples.len(), 0)?;
+    for triple in triples {
+        let serde_json::Value::Object(fields) = triple else {
+            rows.raw_push(lua.to_value(&triple)?)?;
+            continue;
+        };
+        let row = lua.create_table_with_capacity(0, fields.len())?;
+        for (key, value) in fields {
+            let value = match value {
+                serde_json::Value::String(text) if key == "subject" || key == "predicate" => {
+                    let string = match interned.get(&text) {
+                        Some(string) => string.clone(),
+                        None => {
+                            let string = lua.create_string(&text)?;
+                            interned.insert(text, string.clone());
+                            string
+                        }
+                    };
+                    LuaValue::String(string)
+                }
+                other => lua.to_value(&other)?,
+            };
+            row.raw_set(key, value)?;
+        }
+        rows.raw_push(row)?;
+    }
+    rows.set_metatable(Some(lua.array_metatable()))?;
+    Ok(LuaValue::Table(rows))
+}
+
+fn install_rdf_api(lua: &Lua) -> LuaResult<()> {
+    let state_table = lua.create_table()?;
+    
+    // State.insert(subject, predicate, object) - 插入三元组
+    let insert_fn = lua.create_function(|lua, (subject, predicate, object): (String, String, LuaValue)| -> LuaResult<()> {
+        require_capability(Capability::RdfWrite)?;
+        check_iri_lengths("insert", Some(&subject), Some(&predicate))?;
+        check_strict_iris("insert", &subject, &predicate)?;
+        validate_triple(lua, "insert", &subject, &predicate, &object)?;
+        // 将 object 转为 JSON
+        let object_json = rdf_object_json(lua, &object)?;
+        check_rdf_object_size("insert", &subject, &predicate, &object_json)?;
+        host_rdf_insert(lua, &subject, &predicate, &object_json)
+            .map_err(|e| rdf_triple_error("insert", &subject, &predicate, Some(&object_json), &e))?;
+        record_rdf_stats(lua, |stats| stats.inserted += 1);
+        Ok(())
+    })?;
+    state_table.set("insert", insert_fn.clone())?;
+    
+    // State.put - State.insert 的旧名称，已弃用
+    state_table.set("put", deprecated_alias(lua, "State.put", "State.insert", insert_fn.clone())?)?;
+
+    // State.emit(subject, predicate, object) - 与 State.insert 相同地插入三元组，
+    // 并把它记录到结果信封的 emittedTriples 中，宿主无需关心存储后端即可拿到模块产生的三元组
+    // 记录的 object 与实际写入存储的相同（布尔值按 lua_set_rdf_boolean_mode 转换）
+    let emit_fn = lua.create_function(move |lua, (subject, predicate, object): (String, String, LuaValue)| {
+        let object_json = rdf_object_to_json(lua, &object)?;
+        insert_fn.call::<()>((subject.as_str(), predicate.as_str(), object))?;
+        let triple = serde_json::json!({ "subject": subject, "predicate": predicate, "object": object_json });
+        if lua.app_data_ref::<EmittedTriples>().is_none() {
+            lua.set_app_data(EmittedTriples::default());
         }
+        if let Some(mut emitted) = lua.app_data_mut::<EmittedTriples>() {
+            emitted.0.push(triple);
+        }
+        Ok(())
+    })?;
+    state_table.set("emit", emit_fn)?;
+    
+    // State.delete(subject, predicate, object?) - 删除三元组
+    let delete_fn = lua.create_function(|lua, (subject, predicate, object): (String, String, Option<LuaValue>)| -> LuaResult<()> {
+        require_capability(Capability::RdfWrite)?;
+        check_iri_lengths("delete", Some(&subject), Some(&predicate))?;
+        check_strict_iris("delete", &subject, &predicate)?;
+        let object_json = object.map(|val| rdf_object_json(lua, &val)).transpose()?;
+        
+        host_rdf_delete(lua, &subject, &predicate, object_json.as_deref().unwrap_or("null"))
+            .map_err(|e| rdf_triple_error("delete", &subject, &predicate, object_json.as_deref(), &e))?;
+        record_rdf_stats(lua, |stats| stats.deleted += 1);
         Ok(())
     })?;
     state_table.set("delete", delete_fn)?;
     
     // State.query(pattern) - 查询三元组
     // pattern 是一个 table: {subject = "...", predicate = "...", object = ...}
-    // 其中任意字段可以为 nil (表示通配符)
+    // 其中任意字段可以为 nil (表示通配符)；可选的 predicatePattern 是用于过滤谓词的 Lua 模式
+    // pattern.intern 为 true 时，结果中相同的 subject/predicate 字符串共用同一个 Lua 字符串
+    // 可选的 limit 和 offset（非负整数）交给宿主分页；没有显式排序时结果的顺序不确定，
+    // 分页前后两次查询不保证衔接。predicatePattern 在分页之后才在本地过滤
+    // 可选的 orderBy（"subject"、"predicate" 或 "object"）和 desc = true 交给宿主排序
+    // 开启 lua_set_rdf_cache_backend 时结果经宿主键值存储缓存，任何写入都会使缓存失效
     let query_fn = lua.create_function(|lua, pattern: LuaTable| -> LuaResult<LuaValue> {
-        // 构造 pattern JSON
-        let subject: Option<String> = pattern.get("subject")?;
-        let predicate: Option<String> = pattern.get("predicate")?;
-        let object: Option<LuaValue> = pattern.get("object")?;
-        
-        // 将 Lua 值直接转换为 serde_json::Value，避免双重序列化
-        let object_json = object.as_ref()
-            .map(|v| lua.from_value::<serde_json::Value>(v.clone()))
-            .transpose()?;
+        require_capability(Capability::RdfRead)?;
+        let mut pattern_json = build_pattern_json(lua, &pattern)?;
+        for field in ["limit", "offset"] {
+            if let Some(value) = pattern.get::<Option<i64>>(field)? {
+                if value < 0 {
+                    return Err(LuaError::external(format!("State.query: {} must be non-negative, got {}", field, value)));
+                }
+                pattern_json[field] = value.into();
+            }
+        }
+        if let Some(order_by) = pattern.get::<Option<String>>("orderBy")? {
+            if !QUERY_ORDER_FIELDS.contains(&order_by.as_str()) {
+                return Err(LuaError::external(format!(
+                    "State.query: invalid orderBy field '{}' (expected subject, predicate or object)",
+                    order_by
+                )));
+            }
+            pattern_json["orderBy"] = order_by.into();
+        }
+        if let Some(desc) = pattern.get::<Option<bool>>("desc")? {
+            pattern_json["desc"] = desc.into();
+        }
+        let (result, cache_hit) = cached_rdf_query(lua, &pattern_json).map_err(LuaError::external)?;
+        let record_query = |rows: usize| {
+            if cache_hit {
+                record_rdf_stats(lua, |stats| stats.cache_hits += 1);
+            } else {
+                record_rdf_query(lua, rows);
+            }
+        };
         
-        let pattern_json = serde_json::json!({
-            "subject": subject,
-            "predicate": predicate,
-            "object": object_json
-        });
+        // pattern.predicatePattern - 用 Lua 模式在本地过滤谓词（宿主不支持前缀查询时使用）
+        let predicate_pattern: Option<String> = pattern.get("predicatePattern")?;
+        let intern = pattern.get::<Option<bool>>("intern")?.unwrap_or(false);
+        let native_booleans = RUN_CONFIG.with(|c| c.borrow().rdf_boolean_mode == BooleanMode::Native);
+        if predicate_pattern.is_none() && !intern && native_booleans {
+            let value = json_to_lua_value(lua, &result)?;
+            let rows = match &value {
+                LuaValue::Table(rows) => rows.raw_len(),
+                _ => 0,
+            };
+            record_query(rows);
+            return Ok(value);
+        }
         
-        let pattern_str = serde_json::to_string(&pattern_json)
-            .map_err(|e| LuaError::external(format!("JSON stringify error: {}", e)))?;
-        let pattern_c = CString::new(pattern_str).map_err(|e| LuaError::external(e))?;
+        let mut triples: Vec<serde_json::Value> = serde_json::from_str(&result)
+            .map_err(|e| LuaError::external(format!("JSON parse error: {}", e)))?;
+        record_query(triples.len());
+        decode_rdf_booleans(&mut triples);
+        if let Some(predicate_pattern) = predicate_pattern {
+            let find: LuaFunction = lua.globals().get::<LuaTable>("string")?.get("find")?;
+            // 先对空字符串匹配一次，使无效的模式即使在没有结果时也会报错
+            find.call::<LuaValue>(("", predicate_pattern.as_str()))
+                .map_err(|e| LuaError::external(format!("invalid predicatePattern '{}': {}", predicate_pattern, e)))?;
+            
+            let mut matched = Vec::new();
+            for triple in triples {
+                let predicate = triple.get("predicate").and_then(|p| p.as_str()).unwrap_or_default();
+                if find.call::<LuaValue>((predicate, predicate_pattern.as_str()))? != LuaValue::Nil {
+                    matched.push(triple);
+                }
+            }
+            triples = matched;
+        }
         
-        let result_ptr = unsafe { js_rdf_query(pattern_c.as_ptr()) };
-        let result = read_c_string(result_ptr)?;
-        unsafe { js_rdf_free(result_ptr) };
+        if intern {
+            triples_to_lua_interned(lua, triples)
+        } else {
+            lua.to_value(&triples)
+        }
+    })?;
+    state_table.set("query", query_fn.clone())?;
+    
+    // State.queryMap(pattern, keyField?, options?) - 查询并按字段建立映射表
+    // keyField 为 "subject"（默认）、"predicate" 或 "object"；
+    // options.valueField 指定时值为该字段而不是整个三元组；
+    // options.collect 为 true 时同一键的所有值收集为数组，否则后出现的覆盖先出现的
+    let query_map_fn = lua.create_function(move |lua, (pattern, key_field, options): (LuaTable, Option<String>, Option<LuaTable>)| -> LuaResult<LuaTable> {
+        const TRIPLE_FIELDS: [&str; 3] = ["subject", "predicate", "object"];
+        let key_field = key_field.unwrap_or_else(|| "subject".to_string());
+        if !TRIPLE_FIELDS.contains(&key_field.as_str()) {
+            return Err(LuaError::external(format!("invalid keyField '{}'", key_field)));
+        }
+        let (value_field, collect) = match &options {
+            Some(options) => (
+                options.get::<Option<String>>("valueField")?,
+                options.get::<Option<bool>>("collect")?.unwrap_or(false),
+            ),
+            None => (None, false),
+        };
+        if let Some(field) = value_field.as_deref().filter(|f| !TRIPLE_FIELDS.contains(f)) {
+            return Err(LuaError::external(format!("invalid valueField '{}'", field)));
+        }
         
-        if result.starts_with("ERROR:") {
-            return Err(LuaError::external(result.trim_start_matches("ERROR:")));
+        let map = lua.create_table()?;
+        let LuaValue::Table(triples) = query_fn.call::<LuaValue>(pattern)? else {
+            return Ok(map);
+        };
+        for triple in triples.sequence_values::<LuaTable>() {
+            let triple = triple?;
+            let key: LuaValue = triple.get(key_field.as_str())?;
+            if key.is_nil() {
+                continue;
+            }
+            let value = match &value_field {
+                Some(field) => triple.get::<LuaValue>(field.as_str())?,
+                None => LuaValue::Table(triple),
+            };
+            if collect {
+                let values = match map.raw_get::<Option<LuaTable>>(key.clone())? {
+                    Some(values) => values,
+                    None => {
+                        let values = lua.create_table()?;
+                        map.raw_set(key, values.clone())?;
+                        values
+                    }
+                };
+                values.raw_push(value)?;
+            } else {
+                map.raw_set(key, value)?;
+            }
         }
+        Ok(map)
+    })?;
+    state_table.set("queryMap", query_map_fn)?;
+    
+    // State.toJsonLd(pattern?) - 查询匹配的三元组并导出为 JSON-LD 文档（table），省略 pattern 时导出全部
+    // 使用 lua_set_rdf_prefix 配置的前缀映射作为 @context
+    let to_json_ld_fn = lua.create_function(|lua, pattern: Option<LuaTable>| -> LuaResult<LuaValue> {
+        require_capability(Capability::RdfRead)?;
+        let pattern = match pattern {
+            Some(pattern) => pattern,
+            None => lua.create_table()?,
+        };
+        let pattern_json = build_pattern_json(lua, &pattern)?;
+        let result = host_rdf_query(&pattern_json).map_err(LuaError::external)?;
+        let triples: Vec<serde_json::Value> = serde_json::from_str(&result)
+            .map_err(|e| LuaError::external(format!("JSON parse error: {}", e)))?;
+        record_rdf_query(lua, triples.len());
         
-        json_to_lua_value(lua, &result)
+        let document = RUN_CONFIG.with(|c| triples_to_json_ld(&triples, &c.borrow().rdf_prefixes));
+        json_to_lua_value(lua, &document.to_string())
+    })?;
+    state_table.set("toJsonLd", to_json_ld_fn)?;
+    
+    // State.queryIter(pattern) - 分页查询的迭代器，每次返回一个三元组
+    // 用法: for triple in State.queryIter({subject = "..."}) do ... end
+    // 每页大小由 lua_set_rdf_page_size 配置，内存占用不超过一页
+    let query_iter_fn = lua.create_function(|lua, pattern: LuaTable| -> LuaResult<LuaFunction> {
+        require_capability(Capability::RdfRead)?;
+        let pager = RefCell::new(QueryPager {
+            pattern_json: build_pattern_json(lua, &pattern)?,
+            page_size: RUN_CONFIG.with(|c| c.borrow().rdf_page_size),
+            offset: 0,
+            buffer: VecDeque::new(),
+            exhausted: false,
+        });
+        lua.create_function(move |lua, ()| -> LuaResult<LuaValue> {
+            match pager.borrow_mut().next_triple(lua).map_err(LuaError::external)? {
+                Some(triple) => lua.to_value(&triple),
+                None => Ok(LuaValue::Nil),
+            }
+        })
     })?;
-    state_table.set("query", query_fn)?;
+    state_table.set("queryIter", query_iter_fn)?;
     
-    // State.batchInsert(triples) - 批量插入三元组
+    // State.batchInsert(triples, options) - 批量插入三元组
     // triples 是一个数组: {{subject = "...", predicate = "...", object = ...}, ...}
-    let batch_insert_fn = lua.create_function(|lua, triples: LuaTable| -> LuaResult<()> {
-        // 将 Lua table 转换为 JSON 数组
-        let triples_json = lua_value_to_json(lua, &LuaValue::Table(triples))?;
-        let triples_c = CString::new(triples_json).map_err(|e| LuaError::external(e))?;
+    // options.dedup 为 true 时，发送前去除批次内重复的三元组（保留首次出现的顺序）
+    // 返回被去除的重复三元组数量
+    let batch_insert_fn = lua.create_function(|lua, (triples, options): (LuaTable, Option<LuaTable>)| -> LuaResult<usize> {
+        require_capability(Capability::RdfWrite)?;
+        let dedup = match &options {
+            Some(options) => options.get::<Option<bool>>("dedup")?.unwrap_or(false),
+            None => false,
+        };
+
+        // 逐个转换并校验三元组，出错时报告其在批次中的索引（从 1 开始）
+        let mut items = Vec::new();
+        for (index, triple) in triples.sequence_values::<LuaValue>().enumerate() {
+            let index = index + 1;
+            let LuaValue::Table(triple) = triple? else {
+                return Err(LuaError::external(format!("RDF batchInsert failed at index {}: triple must be a table", index)));
+            };
+            let subject: Option<String> = triple.get("subject")?;
+            let predicate: Option<String> = triple.get("predicate")?;
+            let (Some(subject), Some(predicate)) = (subject, predicate) else {
+                return Err(LuaError::external(format!("RDF batchInsert failed at index {}: subject and predicate must be strings", index)));
+            };
+            let object: LuaValue = triple.get("object")?;
+            let op = format!("batchInsert (index {})", index);
+            check_iri_lengths(&op, Some(&subject), Some(&predicate))?;
+            check_strict_iris(&op, &subject, &predicate)?;
+            validate_triple(lua, &op, &subject, &predicate, &object)?;
+            let object = rdf_object_to_json(lua, &object)
+                .map_err(|e| rdf_triple_error(&op, &subject, &predicate, None, &e.to_string()))?;
+            check_rdf_object_size(&op, &subject, &predicate, &object.to_string())?;
+            items.push(serde_json::json!({
+                "subject": subject,
+                "predicate": predicate,
+                "object": object
+            }));
+        }
         
-        let result_ptr = unsafe { js_rdf_batch_insert(triples_c.as_ptr()) };
-        let result = read_c_string(result_ptr)?;
-        unsafe { js_rdf_free(result_ptr) };
+        // serde_json 的对象按键排序，序列化结果即可作为规范形式比较
+        let total = items.len();
+        if dedup {
+            let mut seen = HashSet::new();
+            items.retain(|item| seen.insert(item.to_string()));
+        }
+        let dropped = total - items.len();
         
-        if result.starts_with("ERROR:") {
-            return Err(LuaError::external(result.trim_start_matches("ERROR:")));
+        let count = items.len();
+        let triples_json = serde_json::Value::Array(items).to_string();
+        host_rdf_batch_insert(lua, &triples_json)
+            .map_err(|e| LuaError::external(format!("RDF batchInsert failed for batch of {} triples: {}", count, e)))?;
+        record_rdf_stats(lua, |stats| stats.inserted += count as u64);
+        Ok(dropped)
+    })?;
+    state_table.set("batchInsert", batch_insert_fn)?;
+
+    // State.transaction(fn) - 在宿主事务中执行 fn：正常返回时提交并返回 fn 的返回值，
+    // fn 抛出错误时回滚后原样抛出该错误。不支持嵌套事务，在事务中再次调用会报错 "nested transaction"
+    let transaction_fn = lua.create_function(|lua, body: LuaFunction| -> LuaResult<LuaMultiValue> {
+        require_capability(Capability::RdfWrite)?;
+        if lua.app_data_ref::<ActiveTransaction>().is_some() {
+            return Err(LuaError::external("nested transaction"));
+        }
+        take_rdf_result(unsafe { js_rdf_begin() })
+            .map_err(|e| LuaError::external(format!("RDF transaction begin failed: {}", e)))?;
+        lua.set_app_data(ActiveTransaction);
+        let result = body.call::<LuaMultiValue>(());
+        lua.remove_app_data::<ActiveTransaction>();
+        let rollback = || {
+            if let Err(rollback_error) = take_rdf_result(unsafe { js_rdf_rollback() }) {
+                push_warning(lua, format!("RDF transaction rollback failed: {}", rollback_error));
+            }
+            // 事务中缓存的查询结果可能包含已回滚的写入
+            invalidate_rdf_query_cache(lua);
+        };
+        match result {
+            Ok(values) => match take_rdf_result(unsafe { js_rdf_commit() }) {
+                Ok(_) => Ok(values),
+                // 提交失败时同样回滚，不留下只写入了一部分的事务
+                Err(e) => {
+                    rollback();
+                    Err(LuaError::external(format!("RDF transaction commit failed: {}", e)))
+                }
+            },
+            Err(e) => {
+                rollback();
+                Err(e)
+            }
         }
+    })?;
+    state_table.set("transaction", transaction_fn)?;
+
+    // State.flush() - 让宿主立即提交缓冲的写入，之后的查询一定能读到之前的写入
+    let flush_fn = lua.create_function(|_, ()| -> LuaResult<()> {
+        require_capability(Capability::RdfWrite)?;
+        take_rdf_result(unsafe { js_rdf_flush() })
+            .map_err(|e| LuaError::external(format!("RDF flush failed: {}", e)))?;
         Ok(())
     })?;
-    state_table.set("batchInsert", batch_insert_fn)?;
+    state_table.set("flush", flush_fn)?;
     
     // State.set(subject, predicate, object) - 设置三元组（先删除后插入）
     // 删除所有匹配 subject + predicate 的三元组，然后插入新的三元组
     let set_fn = lua.create_function(|lua, (subject, predicate, object): (String, String, LuaValue)| -> LuaResult<()> {
-        // 1. 先删除所有匹配的三元组（不指定 object，删除所有）
-        let subject_c = CString::new(subject.clone()).map_err(|e| LuaError::external(e))?;
-        let predicate_c = CString::new(predicate.clone()).map_err(|e| LuaError::external(e))?;
-        let null_c = CString::new("null").map_err(|e| LuaError::external(e))?;
+        require_capability(Capability::RdfWrite)?;
+        check_iri_lengths("set", Some(&subject), Some(&predicate))?;
+        check_strict_iris("set", &subject, &predicate)?;
+        validate_triple(lua, "set", &subject, &predicate, &object)?;
+        let object_json = rdf_object_json(lua, &object)?;
+        check_rdf_object_size("set", &subject, &predicate, &object_json)?;
         
-        let delete_result_ptr = unsafe { js_rdf_delete(subject_c.as_ptr(), predicate_c.as_ptr(), null_c.as_ptr()) };
-        let delete_result = read_c_string(delete_result_ptr)?;
-        unsafe { js_rdf_free(delete_result_ptr) };
-        
-        if delete_result.starts_with("ERROR:") {
-            return Err(LuaError::external(delete_result.trim_start_matches("ERROR:")));
+        // 宿主支持原子替换时一步完成，避免删除与插入之间被其他写入穿插
+        if RUN_CONFIG.with(|c| c.borrow().rdf_atomic_replace) {
+            host_rdf_replace(lua, &subject, &predicate, &object_json)
+                .map_err(|e| rdf_triple_error("set", &subject, &predicate, Some(&object_json), &e))?;
+            record_rdf_stats(lua, |stats| {
+                stats.deleted += 1;
+                stats.inserted += 1;
+            });
+            return Ok(());
         }
         
-        // 2. 插入新的三元组
-        let object_json = lua_value_to_json(lua, &object)?;
-        let subject_c = CString::new(subject).map_err(|e| LuaError::external(e))?;
-        let predicate_c = CString::new(predicate).map_err(|e| LuaError::external(e))?;
-        let object_c = CString::new(object_json).map_err(|e| LuaError::external(e))?;
-        
-        let insert_result_ptr = unsafe { js_rdf_insert(subject_c.as_ptr(), predicate_c.as_ptr(), object_c.as_ptr()) };
-        let insert_result = read_c_string(insert_result_ptr)?;
-        unsafe { js_rdf_free(insert_result_ptr) };
+        // 1. 先删除所有匹配的三元组（不指定 object，删除所有）
+        host_rdf_delete(lua, &subject, &predicate, "null")
+            .map_err(|e| rdf_triple_error("set (delete step)", &subject, &predicate, None, &e))?;
         
-        if insert_result.starts_with("ERROR:") {
-            return Err(LuaError::external(insert_result.trim_start_matches("ERROR:")));
-        }
+        // 2. 插入新的三元组
+        host_rdf_insert(lua, &subject, &predicate, &object_json)
+            .map_err(|e| rdf_triple_error("set", &subject, &predicate, Some(&object_json), &e))?;
+        record_rdf_stats(lua, |stats| {
+            stats.deleted += 1;
+            stats.inserted += 1;
+        });
         
         Ok(())
     })?;
     state_table.set("set", set_fn)?;
     
+    // State.increment(subject, predicate, delta?) - 由宿主原子地把数值加上 delta（默认 1），返回新值
+    // 不存在时从 0 开始；已有的值不是数字时报错 "cannot increment non-numeric value"
+    let increment_fn = lua.create_function(|lua, (subject, predicate, delta): (String, String, Option<LuaValue>)| -> LuaResult<LuaValue> {
+        require_capability(Capability::RdfWrite)?;
+        check_iri_lengths("increment", Some(&subject), Some(&predicate))?;
+        check_strict_iris("increment", &subject, &predicate)?;
+        let delta_json = match delta {
+            None | Some(LuaValue::Nil) => "1".to_string(),
+            Some(delta @ (LuaValue::Integer(_) | LuaValue::Number(_))) => lua_value_to_json(lua, &delta)?,
+            Some(other) => {
+                return Err(LuaError::external(format!(
+                    "State.increment: delta must be a number, got {}",
+                    other.type_name()
+                )))
+            }
+        };
+        let result = host_rdf_increment(lua, &subject, &predicate, &delta_json)
+            .map_err(|e| rdf_triple_error("increment", &subject, &predicate, None, &e))?;
+        let value: serde_json::Value = serde_json::from_str(&result)
+            .map_err(|e| LuaError::external(format!("JSON parse error: {}", e)))?;
+        let value = match value.as_i64() {
+            Some(integer) => LuaValue::Integer(integer),
+            None => match value.as_f64() {
+                Some(number) => LuaValue::Number(number),
+                None => {
+                    let message = "cannot increment non-numeric value";
+                    return Err(rdf_triple_error("increment", &subject, &predicate, None, message));
+                }
+            },
+        };
+        Ok(value)
+    })?;
+    state_table.set("increment", increment_fn)?;
+
     // State.get(subject, predicate) - 获取单个值
     // 查询匹配 subject + predicate 的三元组，返回第一个结果的 object，如果没有则返回 nil
     let get_fn = lua.create_function(|lua, (subject, predicate): (String, String)| -> LuaResult<LuaValue> {
+        require_capability(Capability::RdfRead)?;
+        check_iri_lengths("get", Some(&subject), Some(&predicate))?;
         // 构造查询 pattern
         let pattern_json = serde_json::json!({
             "subject": subject,
@@ -403,22 +1875,14 @@ fn install_rdf_api(lua: &Lua) -> LuaResult<()> {
             "object": serde_json::Value::Null
         });
         
-        let pattern_str = serde_json::to_string(&pattern_json)
-            .map_err(|e| LuaError::external(format!("JSON stringify error: {}", e)))?;
-        let pattern_c = CString::new(pattern_str).map_err(|e| LuaError::external(e))?;
-        
         // 调用查询
-        let result_ptr = unsafe { js_rdf_query(pattern_c.as_ptr()) };
-        let result = read_c_string(result_ptr)?;
-        unsafe { js_rdf_free(result_ptr) };
-        
-        if result.starts_with("ERROR:") {
-            return Err(LuaError::external(result.trim_start_matches("ERROR:")));
-        }
+        let result = host_rdf_query(&pattern_json).map_err(LuaError::external)?;
         
         // 解析结果数组
-        let triples: Vec<serde_json::Value> = serde_json::from_str(&result)
+        let mut triples: Vec<serde_json::Value> = serde_json::from_str(&result)
             .map_err(|e| LuaError::external(format!("JSON parse error: {}", e)))?;
+        record_rdf_query(lua, triples.len());
+        decode_rdf_booleans(&mut triples);
         
         // 如果有结果，返回第一个三元组的 object；否则返回 nil
         if let Some(first_triple) = triples.first() {
@@ -431,18 +1895,166 @@ fn install_rdf_api(lua: &Lua) -> LuaResult<()> {
     })?;
     state_table.set("get", get_fn)?;
     
+    // State.exists(subject, predicate, object?) - 检查三元组是否存在
+    // 只返回布尔值，不传输任何三元组数据；省略 object 时检查 subject + predicate 下是否有任意值
+    let exists_fn = lua.create_function(|lua, (subject, predicate, object): (String, String, Option<LuaValue>)| -> LuaResult<bool> {
+        require_capability(Capability::RdfRead)?;
+        check_iri_lengths("exists", Some(&subject), Some(&predicate))?;
+        let object_json = object
+            .map(|v| rdf_object_to_json(lua, &v))
+            .transpose()?;
+        let pattern_json = serde_json::json!({
+            "subject": subject,
+            "predicate": predicate,
+            "object": object_json
+        });
+        let exists = host_rdf_exists(&pattern_json).map_err(LuaError::external)?;
+        record_rdf_query(lua, 0);
+        Ok(exists)
+    })?;
+    state_table.set("exists", exists_fn.clone())?;
+
+    // State.count(pattern) - 返回匹配 pattern 的三元组数量，pattern 与 State.query 相同（limit 等分页字段不适用）
+    let count_fn = lua.create_function(|lua, pattern: LuaTable| -> LuaResult<i64> {
+        require_capability(Capability::RdfRead)?;
+        let pattern_json = build_pattern_json(lua, &pattern)?;
+        let count = host_rdf_count(&pattern_json).map_err(LuaError::external)?;
+        record_rdf_query(lua, 0);
+        i64::try_from(count).map_err(LuaError::external)
+    })?;
+    state_table.set("count", count_fn)?;
+
+    // State.has - State.exists 的别名
+    state_table.set("has", exists_fn)?;
+    
+    // State.setValidator(fn) - 注册写入前的校验函数 fn(subject, predicate, object)，传入 nil 取消
+    // 对 insert、set 以及 batchInsert 的每个元素生效
+    let set_validator_fn = lua.create_function(|lua, validator: Option<LuaFunction>| {
+        match validator {
+            Some(validator) => {
+                lua.set_app_data(RdfValidator(validator));
+            }
+            None => {
+                lua.remove_app_data::<RdfValidator>();
+            }
+        }
+        Ok(())
+    })?;
+    // State.validateIri(s) - 检查 IRI 语法，返回 true 或 false, reason
+    let validate_iri_fn = lua.create_function(|_, iri: String| -> LuaResult<(bool, Option<String>)> {
+        match iri_error(&iri) {
+            Some(reason) => Ok((false, Some(reason))),
+            None => Ok((true, None)),
+        }
+    })?;
+    state_table.set("validateIri", validate_iri_fn)?;
+    
+    state_table.set("setValidator", set_validator_fn)?;
+    
     lua.globals().set("State", state_table)?;
     Ok(())
 }
 
+/// JavaScript 能精确表示的最大整数 2^53
+const JS_SAFE_INTEGER_LIMIT: u64 = 1 << 53;
+
+/// 按当前的 BigIntMode 处理 JSON 中超出安全范围的整数（浮点数不受影响）
+fn apply_bigint_mode(value: &mut serde_json::Value) -> Result<(), String> {
+    let mode = RUN_CONFIG.with(|c| c.borrow().bigint_mode);
+    if mode == BigIntMode::Number {
+        return Ok(());
+    }
+    apply_bigint_mode_with(value, mode)
+}
+
+fn apply_bigint_mode_with(value: &mut serde_json::Value, mode: BigIntMode) -> Result<(), String> {
+    match value {
+        serde_json::Value::Number(n) => {
+            let magnitude = n.as_i64().map(|i| i.unsigned_abs()).or_else(|| n.as_u64());
+            if magnitude.is_some_and(|m| m > JS_SAFE_INTEGER_LIMIT) {
+                if mode == BigIntMode::Error {
+                    return Err(format!("integer {} exceeds the JSON safe integer range (2^53)", n));
+                }
+                *value = serde_json::Value::String(n.to_string());
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                apply_bigint_mode_with(item, mode)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for item in map.values_mut() {
+                apply_bigint_mode_with(item, mode)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// 将 Lua 值转换为 serde_json::Value，并应用大整数处理方式
+fn lua_to_json_value(lua: &Lua, value: &LuaValue) -> LuaResult<serde_json::Value> {
+    let mut json_value = lua_to_json(lua, value).map_err(LuaError::external)?;
+    apply_bigint_mode(&mut json_value).map_err(LuaError::external)?;
+    Ok(json_value)
+}
+
 /// 将 Lua 值转换为 JSON 字符串（使用 serde_json）
 fn lua_value_to_json(lua: &Lua, value: &LuaValue) -> LuaResult<String> {
-    // 使用 mlua 的序列化功能
-    let json_value: serde_json::Value = lua.from_value(value.clone())?;
+    let json_value = lua_to_json_value(lua, value)?;
     serde_json::to_string(&json_value)
         .map_err(|e| LuaError::external(format!("JSON stringify error: {}", e)))
 }
 
+/// 将 RDF 三元组的 object 转换为 serde_json::Value，布尔值按 lua_set_rdf_boolean_mode 的方式表示
+fn rdf_object_to_json(lua: &Lua, value: &LuaValue) -> LuaResult<serde_json::Value> {
+    let json_value = lua_to_json_value(lua, value)?;
+    let serde_json::Value::Bool(flag) = json_value else {
+        return Ok(json_value);
+    };
+    Ok(match RUN_CONFIG.with(|c| c.borrow().rdf_boolean_mode) {
+        BooleanMode::Native => json_value,
+        BooleanMode::String => flag.to_string().into(),
+        BooleanMode::Typed => serde_json::json!({ "@value": flag.to_string(), "@type": XSD_BOOLEAN }),
+    })
+}
+
+/// rdf_object_to_json 的字符串形式
+fn rdf_object_json(lua: &Lua, value: &LuaValue) -> LuaResult<String> {
+    serde_json::to_string(&rdf_object_to_json(lua, value)?)
+        .map_err(|e| LuaError::external(format!("JSON stringify error: {}", e)))
+}
+
+/// rdf_object_to_json 的逆过程：把查询结果中按当前模式表示的布尔 object 还原为 JSON 布尔值
+/// 字符串模式下 object 恰好为 "true"/"false" 的字符串也会被还原
+fn decode_rdf_booleans(triples: &mut [serde_json::Value]) {
+    let mode = RUN_CONFIG.with(|c| c.borrow().rdf_boolean_mode);
+    if mode == BooleanMode::Native {
+        return;
+    }
+    for triple in triples {
+        let Some(object) = triple.get_mut("object") else {
+            continue;
+        };
+        let text = match (mode, &*object) {
+            (BooleanMode::String, serde_json::Value::String(text)) => text.as_str(),
+            (BooleanMode::Typed, serde_json::Value::Object(literal))
+                if literal.get("@type").and_then(|t| t.as_str()).is_some_and(|t| t == XSD_BOOLEAN || t == "xsd:boolean") =>
+            {
+                literal.get("@value").and_then(|v| v.as_str()).unwrap_or_default()
+            }
+            _ => continue,
+        };
+        let flag = match text {
+            "true" => true,
+            "false" => false,
+            _ => continue,
+        };
+        *object = serde_json::Value::Bool(flag);
+    }
+}
+
 /// 将 JSON 字符串转换为 Lua 值（使用 serde_json）
 fn json_to_lua_value(lua: &Lua, json: &str) -> LuaResult<LuaValue> {
     let json_value: serde_json::Value = serde_json::from_str(json)
@@ -451,112 +2063,2220 @@ fn json_to_lua_value(lua: &Lua, json: &str) -> LuaResult<LuaValue> {
     lua.to_value(&json_value)
 }
 
-#[no_mangle]
-pub extern "C" fn lua_run(code_ptr: *const c_char) -> *const c_char {
-    // 辅助函数：创建 JSON 格式的错误结果
-    let make_error = |msg: String| -> *const c_char {
-        // 返回统一格式: {"result": null, "error": "错误信息"}
-        let error_json = serde_json::json!({
-            "result": serde_json::Value::Null,
-            "error": msg
-        });
-        CString::new(error_json.to_string())
-            .unwrap_or_else(|_| CString::new(r#"{"result":null,"error":"<invalid utf8>"}"#).unwrap())
-            .into_raw()
-    };
-    
-    // 辅助函数：创建 JSON 格式的成功结果
-    let make_success = |result: serde_json::Value, output: String| -> *const c_char {
-        // 返回统一格式: {"result": ..., "output": "...", "error": null}
-        let success_json = serde_json::json!({
-            "result": result,
-            "output": output,
-            "error": serde_json::Value::Null
-        });
-        CString::new(success_json.to_string())
-            .unwrap_or_else(|_| CString::new(r#"{"result":null,"output":"","error":"<invalid utf8>"}"#).unwrap())
-            .into_raw()
-    };
-    
-    let code = match read_c_string(code_ptr) {
-        Ok(s) => s,
-        Err(e) => return make_error(format!("Failed to read code: {}", e)),
-    };
+/// 安装与 cjson 兼容的全局 json 表：json.encode(value, pretty)、json.decode(s) 以及表示 JSON null 的 json.null
+/// 数组与对象的判断与结果信封相同：从 1 开始连续整数键的表编码为数组
+fn install_json_api(lua: &Lua) -> LuaResult<()> {
+    let json = lua.create_table()?;
 
-    let output = Rc::new(RefCell::new(String::new()));
-    let lua = Lua::new();
+    // json.encode(value, pretty) - 默认输出紧凑的 JSON，pretty 为 true 时缩进输出
+    let encode_fn = lua.create_function(|lua, (value, pretty): (LuaValue, Option<bool>)| {
+        if !pretty.unwrap_or(false) {
+            return lua_value_to_json(lua, &value);
+        }
+        let json_value = lua_to_json_value(lua, &value)?;
+        serde_json::to_string_pretty(&json_value)
+            .map_err(|e| LuaError::external(format!("JSON stringify error: {}", e)))
+    })?;
+    json.set("encode", encode_fn)?;
 
-    if let Err(e) = install_print_collector(&lua, &output) {
-        return make_error(format!("Failed to install print collector: {}", e));
-    }
+    // json.decode(s) - 对象和数组解码为表，null 解码为 json.null
+    let decode_fn = lua.create_function(|lua, text: String| json_to_lua_value(lua, &text))?;
+    json.set("decode", decode_fn)?;
 
-    if let Err(e) = install_io_write_collector(&lua, &output) {
-        return make_error(format!("Failed to install io.write collector: {}", e));
-    }
+    json.set("null", LuaValue::NULL)?;
+    lua.globals().set("json", json)?;
+    Ok(())
+}
 
-    if let Err(e) = install_require_loader(&lua) {
-        return make_error(format!("Failed to install require loader: {}", e));
+/// 返回 data 的只读代理：读取经 __index 转发，写入报错，嵌套的表同样只读
+/// 嵌套层数超过 lua_set_max_structure_depth 的限制时报错 "structure too deep"
+fn freeze_table(lua: &Lua, data: LuaTable, depth: usize) -> LuaResult<LuaTable> {
+    let max_depth = RUN_CONFIG.with(|c| c.borrow().max_structure_depth);
+    if max_depth > 0 && depth > max_depth {
+        return Err(LuaError::external(format!("structure too deep (limit {})", max_depth)));
     }
-
-    if let Err(e) = install_rdf_api(&lua) {
-        return make_error(format!("Failed to install RDF API: {}", e));
+    for pair in data.clone().pairs::<LuaValue, LuaValue>() {
+        let (key, value) = pair?;
+        if let LuaValue::Table(nested) = value {
+            data.raw_set(key, freeze_table(lua, nested, depth + 1)?)?;
+        }
     }
 
-    let value = match lua.load(&code).set_name("input").eval::<LuaValue>() {
-        Ok(val) => val,
-        Err(e) => return make_error(format!("runtime error: {}", e)),
+    let metatable = lua.create_table()?;
+    metatable.set("__index", data.clone())?;
+    metatable.set("__newindex", lua.create_function(|_, (_, key): (LuaValue, LuaValue)| -> LuaResult<()> {
+        Err(LuaError::external(format!("attempt to modify read-only CONFIG field '{}'", key.to_string()?)))
+    })?)?;
+    metatable.set("__len", lua.create_function(move |_, _: LuaValue| Ok(data.raw_len()))?)?;
+    metatable.set("__metatable", false)?;
+
+    let proxy = lua.create_table()?;
+    proxy.set_metatable(Some(metatable))?;
+    Ok(proxy)
+}
+
+/// 安装宿主设置的只读全局 CONFIG（未设置时不安装）
+fn install_constants(lua: &Lua) -> LuaResult<()> {
+    let Some(constants) = RUN_CONFIG.with(|c| c.borrow().constants.clone()) else {
+        return Ok(());
     };
+    let LuaValue::Table(data) = lua.to_value(&constants)? else {
+        return Err(LuaError::external("constants must be a JSON object"));
+    };
+    lua.globals().set("CONFIG", freeze_table(lua, data, 1)?)
+}
 
-    // 使用 serde_json 序列化 Lua 值
-    // mlua 的 serialize 特性支持将 LuaValue 转换为 serde_json::Value
-    let result_value: serde_json::Value = match serde_json::to_value(&value) {
-        Ok(json_val) => json_val,
-        Err(e) => {
-            // 如果序列化失败（例如包含 userdata、thread 等不可序列化类型）
-            // 尝试基本类型的回退处理
-            match value {
-                LuaValue::Nil => serde_json::Value::Null,
-                LuaValue::Boolean(b) => serde_json::Value::Bool(b),
-                LuaValue::Integer(i) => serde_json::json!(i),
-                LuaValue::Number(n) => serde_json::json!(n),
-                LuaValue::String(s) => match s.to_str() {
-                    Ok(t) => {
-                        let str_ref: &str = &t;
-                        serde_json::Value::String(str_ref.to_string())
-                    },
-                    Err(_) => serde_json::Value::String("<invalid utf8>".to_string()),
-                },
-                _ => return make_error(format!("Cannot serialize return value: {}", e)),
+/// 上下文中当前被禁用的全局 API 及其原来的值（重新启用时恢复）
+#[derive(Default)]
+struct DisabledApis(HashMap<String, LuaValue>);
+
+/// 使上下文中的全局 API 与宿主的 lua_disable_api / lua_enable_api 设置一致：
+/// 新禁用的 API 保存原值后设为 nil，重新启用的 API 恢复原值
+/// 创建上下文时（在所有 install_* 之后）和每次运行开始时调用，因此对已有的持久上下文同样生效
+fn sync_disabled_apis(lua: &Lua) -> LuaResult<()> {
+    let disabled = RUN_CONFIG.with(|c| c.borrow().disabled_apis.clone());
+    let mut removed = lua.remove_app_data::<DisabledApis>().unwrap_or_default();
+    let globals = lua.globals();
+    for &name in DISABLEABLE_APIS {
+        match (disabled.contains(name), removed.0.contains_key(name)) {
+            (true, false) => {
+                removed.0.insert(name.to_string(), globals.get(name)?);
+                globals.set(name, LuaValue::Nil)?;
             }
+            (false, true) => {
+                if let Some(value) = removed.0.remove(name) {
+                    globals.set(name, value)?;
+                }
+            }
+            _ => {}
         }
-    };
-    
-    // 获取捕获的输出
-    let captured_output = output.borrow().clone();
-    
-    make_success(result_value, captured_output)
+    }
+    lua.set_app_data(removed);
+    Ok(())
 }
 
-/// 释放由 lua_run 返回的结果字符串
-/// 必须由 JS 调用以释放内存
-#[no_mangle]
-pub extern "C" fn lua_free_result(ptr: *const c_char) {
-    if !ptr.is_null() {
-        unsafe {
-            // 从原始指针恢复 CString，Drop 时自动释放内存
-            let _ = CString::from_raw(ptr as *mut c_char);
+/// 恢复的快照可能早于 lua_disable_api：仍被禁用的 API 保存恢复出来的值后重新设为 nil
+fn reapply_disabled_apis(lua: &Lua) -> LuaResult<()> {
+    let Some(mut removed) = lua.remove_app_data::<DisabledApis>() else {
+        return Ok(());
+    };
+    let globals = lua.globals();
+    for (name, value) in removed.0.iter_mut() {
+        let current: LuaValue = globals.get(name.as_str())?;
+        if !current.is_nil() {
+            *value = current;
+            globals.set(name.as_str(), LuaValue::Nil)?;
         }
     }
+    lua.set_app_data(removed);
+    Ok(())
 }
 
-#[allow(unused)]
-fn main() {}
-
-// Tests are temporarily disabled - they need to be updated for the new API
-// #[cfg(test)]
-// #[path = "tests.rs"]
-// mod tests;
+/// 自动显示模式：如果代码的最后一行是表达式，则改写为 `return <表达式>`
+/// 只处理最后一个非空行，并且这一行必须单独构成一个完整的表达式（`return (<行>)` 能作为独立代码块编译），
+/// 因此循环末尾的 `print(i) end`、注释行、赋值语句等都不会被改写；
+/// 改写后整段代码无法编译时同样返回 None，调用方使用原始代码
+fn auto_display_source(lua: &Lua, code: &str) -> Option<String> {
+    let trimmed = code.trim_end();
+    let line_start = trimmed.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let last_line = &trimmed[line_start..];
+    if last_line.trim().is_empty() {
+        return None;
+    }
+    // 换行使行尾注释不会注释掉右括号
+    let expression = format!("return (\n{}\n)", last_line);
+    lua.load(&expression).set_name("input").into_function().ok()?;
+    let candidate = format!("{}return {}", &trimmed[..line_start], last_line);
+    lua.load(&candidate).set_name("input").into_function().ok()?;
+    Some(candidate)
+}
+
+/// 开启（非 0）或关闭（0）自动显示：没有 return 的代码片段以最后一个表达式的值作为结果
+#[no_mangle]
+pub extern "C" fn lua_set_auto_display(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().auto_display = enabled != 0);
+}
+
+/// 设置模拟的 Lua 版本："5.4"（默认）或 "5.1"（安装 unpack、loadstring、math.pow 等兼容函数）
+/// 返回 0 表示成功，-1 表示不支持的版本
+#[no_mangle]
+pub extern "C" fn lua_set_compat_mode(mode_ptr: *const c_char) -> i32 {
+    let mode = match read_c_string(mode_ptr).as_deref() {
+        Ok("5.4") => CompatMode::Lua54,
+        Ok("5.1") => CompatMode::Lua51,
+        _ => return -1,
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().compat_mode = mode);
+    0
+}
+
+/// 设置 mw.uuid 的生成方式："random"（默认，v4）或 "time"（按时间排序的 v7）
+/// 返回 0 表示成功，-1 表示未知的方式
+#[no_mangle]
+pub extern "C" fn lua_set_uuid_strategy(strategy_ptr: *const c_char) -> i32 {
+    let strategy = match read_c_string(strategy_ptr).as_deref() {
+        Ok("random") => UuidStrategy::Random,
+        Ok("time") => UuidStrategy::Time,
+        _ => return -1,
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().uuid_strategy = strategy);
+    0
+}
+
+/// 为 mw.uuid 的 v4 UUID 设置确定性种子：之后每次运行都从该种子开始生成相同的 UUID 序列，
+/// 不再调用 js_random_bytes（用于快照测试，生成的 UUID 不具备密码学随机性）
+#[no_mangle]
+pub extern "C" fn lua_seed_uuid(seed: u64) {
+    RUN_CONFIG.with(|c| c.borrow_mut().uuid_seed = Some(seed));
+}
+
+/// 清除 lua_seed_uuid 设置的种子，恢复使用 js_random_bytes
+#[no_mangle]
+pub extern "C" fn lua_clear_uuid_seed() {
+    RUN_CONFIG.with(|c| c.borrow_mut().uuid_seed = None);
+}
+
+/// 设置大整数序列化方式："number"（默认）、"string" 或 "error"
+/// 返回 0 表示成功，-1 表示未知的模式
+#[no_mangle]
+pub extern "C" fn lua_set_bigint_mode(mode_ptr: *const c_char) -> i32 {
+    let mode = match read_c_string(mode_ptr).as_deref() {
+        Ok("number") => BigIntMode::Number,
+        Ok("string") => BigIntMode::String,
+        Ok("error") => BigIntMode::Error,
+        _ => return -1,
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().bigint_mode = mode);
+    0
+}
+
+/// 设置 RDF 三元组中布尔 object 的表示方式："native"（默认，JSON 布尔值）、"string"（"true"/"false"）
+/// 或 "typed"（{"@value": "true", "@type": "http://www.w3.org/2001/XMLSchema#boolean"}）
+/// 写入（insert/set/batchInsert/delete 及查询 pattern）时按此方式编码，查询结果中的 object 按此方式还原为布尔值
+/// 返回 0 表示成功，-1 表示未知的模式
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_boolean_mode(mode_ptr: *const c_char) -> i32 {
+    let mode = match read_c_string(mode_ptr).as_deref() {
+        Ok("native") => BooleanMode::Native,
+        Ok("string") => BooleanMode::String,
+        Ok("typed") => BooleanMode::Typed,
+        _ => return -1,
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_boolean_mode = mode);
+    0
+}
+
+/// 挂载虚拟文件系统：参数为 JSON 对象 {"模块名": "源码", ...}
+/// require 会先在其中查找模块，找不到时才调用 fetch_lua_module；重复挂载会合并（同名覆盖）
+/// 返回 0 表示成功，-1 表示 JSON 无效
+#[no_mangle]
+pub extern "C" fn lua_mount_vfs(json_ptr: *const c_char) -> i32 {
+    let Ok(json) = read_c_string(json_ptr) else {
+        return -1;
+    };
+    let Ok(modules) = serde_json::from_str::<HashMap<String, String>>(&json) else {
+        return -1;
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().vfs.extend(modules));
+    0
+}
+
+/// 设置 require 的模块别名：之后 require(from) 会改为加载 to（在命名空间解析之前替换）
+/// to 为空字符串时删除该别名；返回 0 表示成功，-1 表示 from 为空或参数无效
+#[no_mangle]
+pub extern "C" fn lua_set_require_alias(from_ptr: *const c_char, to_ptr: *const c_char) -> i32 {
+    let (Ok(from), Ok(to)) = (read_c_string(from_ptr), read_c_string(to_ptr)) else {
+        return -1;
+    };
+    let from = from.trim().to_string();
+    if from.is_empty() {
+        return -1;
+    }
+    RUN_CONFIG.with(|c| {
+        let aliases = &mut c.borrow_mut().require_aliases;
+        if to.trim().is_empty() {
+            aliases.remove(&from);
+        } else {
+            aliases.insert(from, to);
+        }
+    });
+    0
+}
+
+/// 卸载虚拟文件系统中的所有模块
+#[no_mangle]
+pub extern "C" fn lua_unmount_vfs() {
+    RUN_CONFIG.with(|c| c.borrow_mut().vfs.clear());
+}
+
+/// 开启（非 0）或关闭（0）State.query 的读穿缓存：先按 pattern 的哈希读取宿主键值存储（js_cache_get），
+/// 未命中时查询并写回（js_cache_set）。写入三元组时递增存储中的版本号，使旧的缓存项失效
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_cache_backend(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_cache_backend = enabled != 0);
+}
+
+/// 设置缓存的查询结果的有效期（秒），0 表示由宿主决定
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_cache_ttl(ttl_secs: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_cache_ttl_secs = ttl_secs);
+}
+
+/// 设置 State.queryIter 每次向宿主请求的三元组数量，0 表示恢复默认值（100）
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_page_size(page_size: u32) {
+    let page_size = if page_size == 0 { DEFAULT_RDF_PAGE_SIZE } else { page_size as usize };
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_page_size = page_size);
+}
+
+/// 设置写入 RDF 的单个 object 序列化为 JSON 后的最大字节数，超出时 State.insert/set/batchInsert
+/// 报错 "RDF object too large"；0 表示不限制
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_max_object_bytes(max_bytes: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_max_object_bytes = max_bytes as usize);
+}
+
+/// 设置 mw.log 日志的总字节数上限，超出后丢弃后续条目并追加一次 "[log truncated]"
+/// 0 表示不限制
+#[no_mangle]
+pub extern "C" fn lua_set_log_limit(bytes: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().log_limit = bytes as usize);
+}
+
+/// 设置每次运行中 print 和 io.write 输出的总字节数上限，超出时写入输出的调用报错
+/// "output limit exceeded"，之前的输出保留；0 表示不限制。模块可以通过 mw.getLimits().outputBytes 读取
+#[no_mangle]
+pub extern "C" fn lua_set_output_limit(bytes: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().output_limit = bytes as usize);
+}
+
+/// 开启（非 0）或关闭（0）指令计数，开启后结果信封带有 "instructions":N
+/// 计数以 100 条指令为粒度，只统计用户代码（包括 require 的模块）
+#[no_mangle]
+pub extern "C" fn lua_set_count_instructions(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().count_instructions = enabled != 0);
+}
+
+/// 开启（非 0）或关闭（0）模块源码的换行规范化：加载前把 \r\n 和单独的 \r 转换为 \n，
+/// 使错误信息中的行号与编辑器中看到的行一致（Lua 会把 \n\r 当作一个换行）
+#[no_mangle]
+pub extern "C" fn lua_set_normalize_newlines(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().normalize_newlines = enabled != 0);
+}
+
+/// 开启（非 0）或关闭（0）函数级性能分析，开启后结果信封带有按指令数从高到低排列的 "profile" 数组：
+/// [{"function": "[string \"mod\"]:12", "calls": N, "instructions": M}, ...]，function 为块名和函数定义所在的行
+/// 指令数是抽样值：每 100 条指令把这 100 条记到当时正在执行的 Lua 函数上，执行很少的函数可能为 0，
+/// C 函数（包括 State 等宿主 API）的耗时不计入。最多输出开销最高的 50 个函数
+#[no_mangle]
+pub extern "C" fn lua_set_profile(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().profile = enabled != 0);
+}
+
+/// 开启（非 0）或关闭（0）耗时统计，开启后结果信封带有 "timings":{"serializeMs":N}
+/// serializeMs 是把结果信封序列化为 JSON 所用的毫秒数（使用宿主的 js_now_epoch 计时）
+#[no_mangle]
+pub extern "C" fn lua_set_report_timings(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().report_timings = enabled != 0);
+}
+
+/// 开启（非 0）或关闭（0）严格 IRI 模式：State.insert/set/delete/batchInsert
+/// 的 subject 和 predicate 必须通过 State.validateIri 的检查
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_strict_iri(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_strict_iri = enabled != 0);
+}
+
+/// 设置每次向宿主获取模块的超时时间（毫秒），0 表示不限制
+/// 设置后每次获取前通过 js_set_fetch_timeout 告知宿主（取消后再告知一次 0）；宿主超时后应返回以 "TIMEOUT:" 开头的错误
+/// 从未设置超时时不会调用 js_set_fetch_timeout
+#[no_mangle]
+pub extern "C" fn lua_set_fetch_timeout(timeout_ms: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().fetch_timeout_ms = timeout_ms);
+}
+
+/// 开启（非 0）后运行结果的 "result" 为捕获的输出文本，代码块的返回值被忽略；
+/// 适用于只关心模块打印内容的宿主，"output" 字段保持不变
+#[no_mangle]
+pub extern "C" fn lua_set_result_from_output(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().result_from_output = enabled != 0);
+}
+
+/// 开启（非 0）后返回值中的正负无穷输出为字符串 "Infinity" 和 "-Infinity"，嵌套在表中的同样处理；
+/// 默认（0）输出为 null。NaN 总是输出为 null。JSON 没有表示这些值的方式，字符串形式便于宿主区分无穷与缺失
+#[no_mangle]
+pub extern "C" fn lua_set_infinity_as_string(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().infinity_as_string = enabled != 0);
+}
+
+/// 设置每次运行最多执行的 VM 指令数，超出时结果为 {"result":null,"error":"instruction limit exceeded"}
+/// 0（默认）表示不限制；每次运行重新安装钩子，设置不会残留到之后的运行
+#[no_mangle]
+pub extern "C" fn lua_set_instruction_limit(limit: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().instruction_limit = limit);
+}
+
+/// 设置每次运行的最长执行时间（毫秒），超时后结果为 {"result":null,"error":"execution timed out after N ms"}
+/// 0（默认）表示不限制；时间由宿主的 js_now_ms 提供，每隔一段指令检查一次，因此实际中止时间会略晚于截止时间
+#[no_mangle]
+pub extern "C" fn lua_set_timeout_ms(ms: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().timeout_ms = ms);
+}
+
+/// 设置 State 函数中 subject 和 predicate 的最大长度（字节），超出时报错 "IRI too long"；0（默认）表示不限制
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_max_iri_len(max_len: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_max_iri_len = max_len as usize);
+}
+
+/// 内存不足时的错误信息，设置了 lua_set_memory_limit 时带上限制的字节数
+fn memory_error_message(memory_limit: usize) -> String {
+    if memory_limit == 0 {
+        "memory limit exceeded".to_string()
+    } else {
+        format!("memory limit exceeded ({} bytes)", memory_limit)
+    }
+}
+
+/// 设置每个 Lua 实例可分配的最大内存（字节），超出时结果为 {"error":"memory limit exceeded (N bytes)"}
+/// 0（默认）表示不限制，此时宿主分配失败的错误为 "memory limit exceeded"，不带字节数；在创建 Lua 实例时生效（持久上下文使用创建时的设置）
+#[no_mangle]
+pub extern "C" fn lua_set_memory_limit(bytes: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().memory_limit = bytes as usize);
+}
+
+/// 开启（非 0）后 lua_run_batch 在同一个上下文中依次运行所有片段，全局变量和已加载的模块在片段之间保留；
+/// 关闭（0，默认）时每个片段在新的环境中运行
+#[no_mangle]
+pub extern "C" fn lua_set_batch_shared_context(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().batch_shared_context = enabled != 0);
+}
+
+/// 设置本次及之后的运行允许调用的宿主能力：json 为类别名称的数组，
+/// 可用的类别为 fetch、rdf_read、rdf_write、cache、message、preprocess；
+/// 调用未允许的能力时报错 "capability 'rdf_write' not permitted"（挂载到虚拟文件系统的模块不需要 fetch）
+/// json 为空字符串或 null 时取消限制（默认）；返回 0 表示成功，-1 表示 JSON 无效或包含未知类别
+#[no_mangle]
+pub extern "C" fn lua_set_import_allowlist(json_ptr: *const c_char) -> i32 {
+    let Ok(json) = read_c_string(json_ptr) else {
+        return -1;
+    };
+    let allowlist = match json.trim() {
+        "" | "null" => None,
+        json => {
+            let Ok(names) = serde_json::from_str::<Vec<String>>(json) else {
+                return -1;
+            };
+            let capabilities: Option<HashSet<Capability>> = names.iter().map(|name| Capability::from_name(name)).collect();
+            match capabilities {
+                Some(capabilities) => Some(capabilities),
+                None => return -1,
+            }
+        }
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().import_allowlist = allowlist);
+    0
+}
+
+/// 声明宿主实现了哪些导入函数组：json 为组名的数组，可用的组为 fetch（fetch_lua_module、js_resolve_module）、
+/// rdf（js_rdf_*）、cache（js_cache_*）、message、preprocess、clock（js_now_ms、js_now_epoch）、sleep（js_sleep）、
+/// random（js_random_bytes）和 user（js_get_user_context）。缺少 clock 时 os.clock 等计时函数报错、执行超时和 timings 不生效，
+/// 缺少 sleep 时 RDF 重试不等待，缺少 user 时 mw.getCurrentUser 返回匿名用户。只对接了部分导入函数的宿主
+/// （链接时用空实现占位）可以借此让调用得到 Lua 错误 "host capability 'rdf' unavailable"，而不是调用占位函数导致陷入
+/// json 为空字符串或 null 时视为全部可用（默认）；返回 0 表示成功，-1 表示 JSON 无效或包含未知的组
+#[no_mangle]
+pub extern "C" fn lua_set_host_imports(json_ptr: *const c_char) -> i32 {
+    let Ok(json) = read_c_string(json_ptr) else {
+        return -1;
+    };
+    let imports = match json.trim() {
+        "" | "null" => None,
+        json => {
+            let Ok(names) = serde_json::from_str::<Vec<String>>(json) else {
+                return -1;
+            };
+            let groups: Option<HashSet<&'static str>> = names
+                .iter()
+                .map(|name| HOST_IMPORT_GROUPS.into_iter().find(|group| group == name))
+                .collect();
+            match groups {
+                Some(groups) => Some(groups),
+                None => return -1,
+            }
+        }
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().host_imports = imports);
+    0
+}
+
+/// 开启（非 0）后 require 把模块名原样交给宿主的 js_resolve_module，由宿主完成解析和获取
+/// （别名、命名空间规则和虚拟文件系统都不再生效）；关闭（0，默认）时使用内置的解析和 fetch_lua_module
+#[no_mangle]
+pub extern "C" fn lua_set_custom_searcher(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().custom_searcher = enabled != 0);
+}
+
+/// 设置 IRI 前缀映射，例如 ("schema", "https://schema.org/")，State.toJsonLd 将其输出为 @context
+/// iri 为空字符串时删除该前缀；返回 0 表示成功，-1 表示 prefix 为空或参数无效
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_prefix(prefix_ptr: *const c_char, iri_ptr: *const c_char) -> i32 {
+    let (Ok(prefix), Ok(iri)) = (read_c_string(prefix_ptr), read_c_string(iri_ptr)) else {
+        return -1;
+    };
+    let prefix = prefix.trim().to_string();
+    if prefix.is_empty() {
+        return -1;
+    }
+    RUN_CONFIG.with(|c| {
+        let prefixes = &mut c.borrow_mut().rdf_prefixes;
+        if iri.trim().is_empty() {
+            prefixes.remove(&prefix);
+        } else {
+            prefixes.insert(prefix, iri);
+        }
+    });
+    0
+}
+
+/// 设置 RDF 宿主调用的重试策略：宿主返回 "ERROR:RETRYABLE:..." 时最多重试 count 次，
+/// 第一次重试前通过 js_sleep 等待 backoff_ms 毫秒，之后每次加倍；count 为 0（默认）时不重试
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_retry(count: u32, backoff_ms: u32) {
+    RUN_CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        config.rdf_retry_count = count;
+        config.rdf_retry_backoff_ms = backoff_ms;
+    });
+}
+
+/// 声明宿主是否实现了原子的 js_rdf_replace：开启（非 0）后 State.set 调用它，
+/// 关闭（0，默认）时 State.set 先删除再插入
+#[no_mangle]
+pub extern "C" fn lua_set_rdf_atomic_replace(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().rdf_atomic_replace = enabled != 0);
+}
+
+/// 设置 mw.clone、CONFIG 冻结等遍历嵌套表的辅助函数允许的最大嵌套层数，
+/// 超出时报错 "structure too deep"；0 表示不限制
+#[no_mangle]
+pub extern "C" fn lua_set_max_structure_depth(depth: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().max_structure_depth = depth as usize);
+}
+
+/// 设置单次 lua_run 最多加载的不同模块数量，超出时 require 报错 "too many modules loaded"
+/// 0 表示不限制
+#[no_mangle]
+pub extern "C" fn lua_set_max_modules_per_run(limit: u32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().max_modules_per_run = limit as usize);
+}
+
+/// 设置 print 输出每一行的前缀（例如日志级别），空字符串表示不添加
+#[no_mangle]
+pub extern "C" fn lua_set_print_line_prefix(prefix_ptr: *const c_char) {
+    let prefix = read_c_string(prefix_ptr).unwrap_or_default();
+    RUN_CONFIG.with(|c| c.borrow_mut().print_line_prefix = prefix);
+}
+
+/// 设置 print 输出每一行的后缀，空字符串表示不添加
+#[no_mangle]
+pub extern "C" fn lua_set_print_line_suffix(suffix_ptr: *const c_char) {
+    let suffix = read_c_string(suffix_ptr).unwrap_or_default();
+    RUN_CONFIG.with(|c| c.borrow_mut().print_line_suffix = suffix);
+}
+
+/// 开启（非 0）或关闭（0）加载模块前去除首尾 <nowiki>/</nowiki> 包装及空白
+#[no_mangle]
+pub extern "C" fn lua_set_strip_module_wrappers(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().strip_module_wrappers = enabled != 0);
+}
+
+/// 设置不带参数的 print() 是否输出空行：非 0 输出（默认），0 不输出任何内容
+#[no_mangle]
+pub extern "C" fn lua_set_empty_print_emits_newline(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().empty_print_emits_newline = enabled != 0);
+}
+
+/// 设置对所有模块可见的只读全局 CONFIG，参数为 JSON 对象，之后的每次运行都会安装
+/// 返回 0 表示成功，-1 表示参数不是合法的 JSON 对象
+#[no_mangle]
+pub extern "C" fn lua_set_constants(json_ptr: *const c_char) -> i32 {
+    let Ok(json) = read_c_string(json_ptr) else {
+        return -1;
+    };
+    let Ok(constants) = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&json) else {
+        return -1;
+    };
+    RUN_CONFIG.with(|c| c.borrow_mut().constants = Some(constants));
+    0
+}
+
+/// 开启（非 0）后 io.write 把 nil 和布尔值输出为 "nil"/"true"/"false"；
+/// 关闭（0，默认）时与标准 Lua 一样只接受字符串和数字
+#[no_mangle]
+pub extern "C" fn lua_set_iowrite_coerce(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().iowrite_coerce = enabled != 0);
+}
+
+/// 设置每个新 Lua 状态的前置代码（加载常用模块、定义辅助函数等），空字符串表示清除
+/// 每次运行都在新的环境中先执行它，因此各次运行共享它定义的内容但互不影响；其输出会被丢弃
+/// 前置代码不会被缓存：每次 lua_run 和每次 lua_new_context 都会在新的 Lua 状态中重新执行一次，耗时计入每次运行。
+/// 需要只执行一次的公共代码时，在 lua_new_context 创建的上下文上使用 lua_set_context_baseline 和 lua_run_isolated
+/// 返回 0 表示成功，-1 表示代码无法编译
+#[no_mangle]
+pub extern "C" fn lua_set_baseline(code_ptr: *const c_char) -> i32 {
+    let Ok(code) = read_c_string(code_ptr) else {
+        return -1;
+    };
+    if code.trim().is_empty() {
+        RUN_CONFIG.with(|c| c.borrow_mut().baseline = None);
+        return 0;
+    }
+    let compiles = SYNTAX_CHECK_LUA.with(|lua| lua.load(&code).set_name("baseline").into_function().is_ok());
+    if !compiles {
+        return -1;
+    }
+    RUN_CONFIG.with(|c| c.borrow_mut().baseline = Some(code));
+    0
+}
+
+/// 禁用或重新启用一个全局 API，返回 0 表示成功，-1 表示未知的 API 名称
+fn set_api_disabled(name_ptr: *const c_char, disabled: bool) -> i32 {
+    let name = match read_c_string(name_ptr) {
+        Ok(name) if DISABLEABLE_APIS.contains(&name.as_str()) => name,
+        _ => return -1,
+    };
+    RUN_CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        if disabled {
+            config.disabled_apis.insert(name);
+        } else {
+            config.disabled_apis.remove(&name);
+        }
+    });
+    0
+}
+
+/// 禁用一个全局 API（"State" 或 "mw"），之后的运行中该全局变量为 nil
+/// 对 lua_new_context 创建的已有上下文同样生效：下一次 lua_run_in_context 开始时移除
+/// 与其他 lua_set_* 一样是全局设置，没有上下文句柄参数：lua_run 等接口每次都创建新的环境，没有句柄可传，
+/// 需要按片段控制时在运行前调用 lua_disable_api，运行后调用 lua_enable_api
+#[no_mangle]
+pub extern "C" fn lua_disable_api(name_ptr: *const c_char) -> i32 {
+    set_api_disabled(name_ptr, true)
+}
+
+/// 重新启用之前被 lua_disable_api 禁用的全局 API，已有上下文在下一次运行开始时恢复它
+#[no_mangle]
+pub extern "C" fn lua_enable_api(name_ptr: *const c_char) -> i32 {
+    set_api_disabled(name_ptr, false)
+}
+
+/// 创建 JSON 格式的错误结果: {"result": null, "error": "错误信息"}
+fn error_envelope(error: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "result": serde_json::Value::Null,
+        "error": error
+    })
+}
+
+/// 创建 JSON 格式的成功结果: {"result": ..., "output": "...", "error": null}
+fn success_envelope(result: serde_json::Value, output: String) -> serde_json::Value {
+    serde_json::json!({
+        "result": result,
+        "output": output,
+        "error": serde_json::Value::Null
+    })
+}
+
+/// 执行一段代码（开启 auto_display 时先改写为显示最后一个表达式）
+fn eval_code(lua: &Lua, code: String) -> LuaResult<LuaMultiValue> {
+    let code = if RUN_CONFIG.with(|c| c.borrow().auto_display) {
+        auto_display_source(lua, &code).unwrap_or(code)
+    } else {
+        code
+    };
+    let chunk = lua.load(&code).set_name("input").into_function()?;
+    call_with_traceback(lua, chunk, LuaMultiValue::new())
+}
+
+/// 经 xpcall 调用函数时使用的消息处理函数和转发包装
+struct TracebackHandler {
+    handler: LuaFunction,
+    wrapper: LuaFunction,
+}
+
+/// 本次运行中未捕获错误发生时的 Lua 调用栈
+struct ErrorTraceback(String);
+
+/// 以 xpcall 调用 f 并把错误原样重新抛出，这样错误值不变，调用栈由消息处理函数记录
+const TRACEBACK_WRAPPER: &str = r#"local f, handler = ...
+return (function(ok, ...)
+    if ok then return ... end
+    error((...), 0)
+end)(xpcall(f, handler, select(3, ...)))"#;
+
+/// TRACEBACK_WRAPPER 在调用栈中显示的来源
+const TRACEBACK_SHORT_SRC: &str = "[string \"traceback\"]";
+
+/// 调用栈最多记录的层数
+const TRACEBACK_MAX_FRAMES: usize = 20;
+
+/// 按 debug.traceback 的格式生成当前调用栈（沙箱中没有 debug 库，因此直接读取栈信息）
+/// 只包含 TRACEBACK_WRAPPER 以内的部分，跳过消息处理函数自身
+fn format_traceback(lua: &Lua) -> String {
+    let mut frames = Vec::new();
+    for level in 1.. {
+        let frame = lua.inspect_stack(level, |debug| {
+            let source = debug.source();
+            let short_src = source.short_src.as_deref().unwrap_or("?").to_string();
+            let location = match debug.current_line() {
+                Some(line) => format!("{}:{}:", short_src, line),
+                None => format!("{}:", short_src),
+            };
+            let names = debug.names();
+            let description = match (names.name.as_deref(), source.what) {
+                (Some(name), _) => format!("function '{}'", name),
+                (None, "main") => "main chunk".to_string(),
+                (None, "C") => "?".to_string(),
+                (None, _) => format!("function <{}:{}>", short_src, source.line_defined.unwrap_or(0)),
+            };
+            (short_src, format!("\t{} in {}", location, description))
+        });
+        let Some((short_src, line)) = frame else {
+            break;
+        };
+        if short_src == TRACEBACK_SHORT_SRC {
+            // 去掉包装中调用的 xpcall
+            if frames.last().is_some_and(|line: &String| line.starts_with("\t[C]")) {
+                frames.pop();
+            }
+            break;
+        }
+        frames.push(line);
+    }
+    if frames.len() > TRACEBACK_MAX_FRAMES {
+        frames.truncate(TRACEBACK_MAX_FRAMES);
+        frames.push("\t...".to_string());
+    }
+    format!("stack traceback:\n{}", frames.join("\n"))
+}
+
+/// 准备记录调用栈的消息处理函数：它把出错时的调用栈保存为 ErrorTraceback，错误值原样返回
+fn install_traceback_handler(lua: &Lua) -> LuaResult<()> {
+    let handler = lua.create_function(|lua, error: LuaValue| {
+        lua.set_app_data(ErrorTraceback(format_traceback(lua)));
+        Ok(error)
+    })?;
+    let wrapper = lua.load(TRACEBACK_WRAPPER).set_name("traceback").into_function()?;
+    lua.set_app_data(TracebackHandler { handler, wrapper });
+    Ok(())
+}
+
+/// 调用函数，出错时在 ErrorTraceback 中留下调用栈（结果信封的 "traceback"）
+fn call_with_traceback(lua: &Lua, f: LuaFunction, args: LuaMultiValue) -> LuaResult<LuaMultiValue> {
+    let handler = lua
+        .app_data_ref::<TracebackHandler>()
+        .map(|h| (h.handler.clone(), h.wrapper.clone()));
+    let Some((handler, wrapper)) = handler else {
+        return f.call(args);
+    };
+    let mut wrapped_args = vec![LuaValue::Function(f), LuaValue::Function(handler)];
+    wrapped_args.extend(args);
+    wrapper.call(LuaMultiValue::from_vec(wrapped_args))
+}
+
+/// 在新的 Lua 实例中执行代码，返回结果信封
+fn run_code(code: String) -> serde_json::Value {
+    run_code_with_context(code, serde_json::Map::new())
+}
+
+/// 执行一段代码，执行前把 context 的每个顶层键设置为同名全局变量（只在这一次运行的 Lua 环境中存在）
+fn run_code_with_context(code: String, context: serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+    let source = code.clone();
+    let mut envelope = run_entry(move |lua| {
+        let globals = lua.globals();
+        for (name, value) in &context {
+            globals.set(name.as_str(), lua.to_value(value)?)?;
+        }
+        eval_code(lua, code)
+    });
+    add_source_context(&mut envelope, &source);
+    envelope
+}
+
+/// 解析宿主传入的上下文变量：JSON 对象，空字符串表示没有上下文
+fn parse_context_json(context_json: &str) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    if context_json.trim().is_empty() {
+        return Ok(serde_json::Map::new());
+    }
+    serde_json::from_str(context_json).map_err(|e| format!("invalid context JSON: {}", e))
+}
+
+/// 错误信息中代码行前后各显示的行数
+const SOURCE_CONTEXT_LINES: usize = 1;
+
+/// 从错误信息中找出提交的代码（块名 "input"）中出错的行号，例如 `[string "input"]:3: boom` 得到 3
+fn input_error_line(message: &str) -> Option<usize> {
+    const MARKER: &str = "[string \"input\"]:";
+    let start = message.find(MARKER)? + MARKER.len();
+    let digits: String = message[start..].chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+/// 错误发生在提交的代码中时，在结果信封中加入出错行附近的源码：
+/// "context": ["2: 上一行", "3 -> 出错行", "4: 下一行"]；行号超出源码范围时不加入
+fn add_source_context(envelope: &mut serde_json::Value, code: &str) {
+    let Some(line) = envelope["error"].as_str().and_then(input_error_line) else {
+        return;
+    };
+    let lines: Vec<&str> = code.lines().collect();
+    if line == 0 || line > lines.len() {
+        return;
+    }
+    let first = line.saturating_sub(SOURCE_CONTEXT_LINES).max(1);
+    let last = (line + SOURCE_CONTEXT_LINES).min(lines.len());
+    let context: Vec<serde_json::Value> = (first..=last)
+        .map(|n| {
+            let text = lines[n - 1];
+            if n == line {
+                format!("{} -> {}", n, text)
+            } else {
+                format!("{}: {}", n, text)
+            }
+            .into()
+        })
+        .collect();
+    envelope["context"] = serde_json::Value::Array(context);
+}
+
+/// 在新的 Lua 环境中执行入口函数并生成结果信封（创建一个只使用一次的 LuaContext）
+/// 返回多个值时 result 为这些值组成的数组，只有一个值时为该值本身，没有返回值时为 null（末尾的 nil 不计入）
+fn run_entry(entry: impl FnOnce(&Lua) -> LuaResult<LuaMultiValue>) -> serde_json::Value {
+    match LuaContext::new() {
+        Ok(context) => context.run(entry),
+        Err(message) => error_envelope(serde_json::Value::String(message)),
+    }
+}
+
+/// Lua 执行上下文：创建时安装全部 API 并执行公共代码，之后可以多次运行代码
+/// 全局变量、已加载的模块和 State 在多次运行之间保留；输出和其他单次运行的状态在每次运行前清空
+struct LuaContext {
+    lua: Lua,
+    output: Rc<RefCell<String>>,
+    /// 创建时设置的内存上限（字节），0 表示不限制
+    memory_limit: usize,
+    /// 宿主通过 lua_request_cancel 设置的取消标志，只有 lua_new_context 创建的上下文可以取消
+    cancel_requested: Option<Rc<Cell<bool>>>,
+    /// lua_snapshot 保存的全局状态（快照编号 -> 快照）
+    snapshots: RefCell<HashMap<u64, GlobalsSnapshot>>,
+    next_snapshot: Cell<u64>,
+    /// lua_set_context_baseline 执行公共代码后记录的全局状态，lua_run_isolated 每次运行前恢复到这里
+    baseline: RefCell<Option<GlobalsSnapshot>>,
+}
+
+/// 快照时一个表的状态：表本身、元表和全部字段
+struct TableSnapshot {
+    table: LuaTable,
+    metatable: Option<LuaTable>,
+    fields: Vec<(LuaValue, LuaValue)>,
+}
+
+impl TableSnapshot {
+    /// 只记录这个表自己的字段，字段中的表不展开
+    fn capture(table: LuaTable) -> LuaResult<Self> {
+        let metatable = table.metatable();
+        let fields = table.pairs::<LuaValue, LuaValue>().collect::<LuaResult<Vec<_>>>()?;
+        Ok(TableSnapshot { table, metatable, fields })
+    }
+
+    /// 原地把表改回记录时的字段和元表
+    fn restore(&self) -> LuaResult<()> {
+        let keys = self.table
+            .pairs::<LuaValue, LuaValue>()
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<LuaResult<Vec<_>>>()?;
+        for key in keys {
+            self.table.raw_set(key, LuaValue::Nil)?;
+        }
+        for (key, value) in &self.fields {
+            self.table.raw_set(key.clone(), value.clone())?;
+        }
+        self.table.set_metatable(self.metatable.clone())
+    }
+}
+
+/// 从全局表出发可以到达的所有表（字段的键和值、元表）在快照时的内容
+/// 恢复时原地改回每个表的字段，表的身份不变，因此模块闭包、字符串元表等保存的表引用仍然有效；
+/// 函数的 upvalue、userdata 和 app_data 中的状态不在快照范围内
+struct GlobalsSnapshot(Vec<TableSnapshot>);
+
+impl GlobalsSnapshot {
+    /// 每个表只记录一次，循环引用不会导致重复遍历
+    fn capture(lua: &Lua) -> LuaResult<Self> {
+        let mut tables = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![lua.globals()];
+        while let Some(table) = pending.pop() {
+            if !visited.insert(table.to_pointer()) {
+                continue;
+            }
+            let snapshot = TableSnapshot::capture(table)?;
+            for (key, value) in &snapshot.fields {
+                for item in [key, value] {
+                    if let LuaValue::Table(nested) = item {
+                        pending.push(nested.clone());
+                    }
+                }
+            }
+            pending.extend(snapshot.metatable.clone());
+            tables.push(snapshot);
+        }
+        Ok(GlobalsSnapshot(tables))
+    }
+
+    /// 把记录的每个表改回快照时的字段和元表；快照之后新建的表不再能从全局表到达
+    fn restore(&self) -> LuaResult<()> {
+        self.0.iter().try_for_each(TableSnapshot::restore)
+    }
+}
+
+impl LuaContext {
+    /// 创建上下文并在安装 API 之后执行公共代码（lua_set_baseline）
+    fn new() -> Result<Self, String> {
+        let context = Self::with_apis()?;
+        // 公共代码在用户代码之前执行，它的输出不计入结果；每个新的 LuaContext 都要重新执行一次
+        if let Some(baseline) = RUN_CONFIG.with(|c| c.borrow().baseline.clone()) {
+            context.lua.load(&baseline)
+                .set_name("baseline")
+                .exec()
+                .map_err(|e| format!("baseline failed: {}", e))?;
+        }
+        Ok(context)
+    }
+
+    /// 创建只安装了 API 的上下文，不执行公共代码
+    fn with_apis() -> Result<Self, String> {
+        let output = Rc::new(RefCell::new(String::new()));
+        let lua = Lua::new();
+        let memory_limit = RUN_CONFIG.with(|c| c.borrow().memory_limit);
+        if memory_limit > 0 {
+            lua.set_memory_limit(memory_limit)
+                .map_err(|e| format!("Failed to set memory limit: {}", e))?;
+        }
+
+        install_print_collector(&lua, &output).map_err(|e| format!("Failed to install print collector: {}", e))?;
+        install_io_write_collector(&lua, &output).map_err(|e| format!("Failed to install io.write collector: {}", e))?;
+        install_warn_collector(&lua).map_err(|e| format!("Failed to install warn collector: {}", e))?;
+        install_require_loader(&lua).map_err(|e| format!("Failed to install require loader: {}", e))?;
+        install_rdf_api(&lua).map_err(|e| format!("Failed to install RDF API: {}", e))?;
+        install_json_api(&lua).map_err(|e| format!("Failed to install json API: {}", e))?;
+        mw::install_mw_api(&lua).map_err(|e| format!("Failed to install mw API: {}", e))?;
+
+        if RUN_CONFIG.with(|c| c.borrow().compat_mode) == CompatMode::Lua51 {
+            lua.load(LUA51_COMPAT_SHIMS)
+                .set_name("compat")
+                .exec()
+                .map_err(|e| format!("Failed to install compat shims: {}", e))?;
+        }
+
+        install_constants(&lua).map_err(|e| format!("Failed to install constants: {}", e))?;
+        install_traceback_handler(&lua).map_err(|e| format!("Failed to install traceback handler: {}", e))?;
+        sync_disabled_apis(&lua).map_err(|e| format!("Failed to disable APIs: {}", e))?;
+
+        Ok(LuaContext {
+            lua,
+            output,
+            memory_limit,
+            cancel_requested: None,
+            snapshots: RefCell::new(HashMap::new()),
+            next_snapshot: Cell::new(1),
+            baseline: RefCell::new(None),
+        })
+    }
+
+    /// 执行入口函数并生成结果信封，负责捕获输出、处理错误以及序列化返回值
+    fn run(&self, entry: impl FnOnce(&Lua) -> LuaResult<LuaMultiValue>) -> serde_json::Value {
+        let make_error = |msg: String| error_envelope(serde_json::Value::String(msg));
+        let (lua, output) = (&self.lua, &self.output);
+
+        if let Err(message) = check_host_reentry() {
+            return make_error(message);
+        }
+
+        output.borrow_mut().clear();
+        BINARY_OUTPUT.with(|b| b.borrow_mut().clear());
+        clear_run_state(lua);
+        if let Err(e) = sync_disabled_apis(lua) {
+            return make_error(format!("Failed to disable APIs: {}", e));
+        }
+        // 只取消进行中的运行，两次运行之间的取消请求被忽略
+        if let Some(cancel) = &self.cancel_requested {
+            cancel.set(false);
+        }
+
+        if let Err(e) = install_instruction_hook(lua, self.cancel_requested.clone()) {
+            return make_error(format!("Failed to install instruction hook: {}", e));
+        }
+
+        let values = match entry(lua) {
+            Ok(values) => values,
+            Err(e) => {
+                // mw.abort 指定的错误码和信息直接进入结果
+                let mut envelope = if let Some(abort) = mw::find_abort(&e) {
+                    let mut envelope = error_envelope(serde_json::Value::String(abort.message.clone()));
+                    envelope["code"] = serde_json::Value::String(abort.code.clone());
+                    envelope
+                } else if is_run_cancelled(&e) {
+                    let mut envelope = make_error(RunCancelledError.to_string());
+                    envelope["code"] = serde_json::Value::String(RUN_CANCELLED_CODE.to_string());
+                    envelope
+                } else if let Some(limit) = find_execution_limit(&e) {
+                    make_error(limit.to_string())
+                } else if is_memory_error(&e) {
+                    make_error(memory_error_message(self.memory_limit))
+                } else {
+                    // 其次使用 mw.setErrorHandler 注册的处理函数改写错误
+                    let mut envelope = match mw::apply_error_handler(lua, &e) {
+                        Some(error) => error_envelope(error),
+                        None => make_error(format!("runtime error: {}", e)),
+                    };
+                    if let Some(traceback) = lua.remove_app_data::<ErrorTraceback>() {
+                        envelope["traceback"] = serde_json::Value::String(traceback.0);
+                    }
+                    envelope
+                };
+                // 出错时同样附带本次运行收集的警告、日志、RDF 统计等
+                extend_envelope(lua, &mut envelope);
+                return envelope;
+            }
+        };
+
+        // 开启 lua_set_result_from_output 时结果就是捕获的输出，返回值被忽略
+        if RUN_CONFIG.with(|c| c.borrow().result_from_output) {
+            let captured_output = output.borrow().clone();
+            let mut envelope = success_envelope(serde_json::Value::String(captured_output.clone()), captured_output);
+            extend_envelope(lua, &mut envelope);
+            return envelope;
+        }
+
+        // mw.emitResult 输出的内容优先于代码块的返回值
+        let values = match mw::take_emitted_result(lua) {
+            Some(text) => {
+                if values.iter().any(|value| !value.is_nil()) {
+                    push_warning(lua, "return value ignored because mw.emitResult was used");
+                }
+                match lua.create_string(&text) {
+                    Ok(text) => LuaMultiValue::from_vec(vec![LuaValue::String(text)]),
+                    Err(e) => return make_error(format!("Cannot build emitted result: {}", e)),
+                }
+            }
+            None => values,
+        };
+
+        // 单个返回值保持原样，多个返回值输出为数组，没有返回值时为 null
+        // 末尾的 nil 不计入（Lua 5.4 的 require 会额外返回加载数据，`return require("m")` 仍然只得到模块）
+        let count = values.iter().rposition(|value| !value.is_nil()).map_or(0, |last| last + 1);
+        let infinity_as_string = RUN_CONFIG.with(|c| c.borrow().infinity_as_string);
+        let to_json = |value: &LuaValue| {
+            if infinity_as_string {
+                return_value_to_json(lua, &replace_infinities(lua, value).map_err(|e| e.to_string())?)
+            } else {
+                return_value_to_json(lua, value)
+            }
+        };
+        let converted = match count {
+            0 => Ok(serde_json::Value::Null),
+            1 => to_json(&values[0]),
+            _ => values
+                .iter()
+                .take(count)
+                .map(to_json)
+                .collect::<Result<Vec<_>, _>>()
+                .map(serde_json::Value::Array),
+        };
+        let mut result_value = match converted {
+            Ok(result_value) => result_value,
+            Err(e) => return make_error(format!("Cannot serialize return value: {}", e)),
+        };
+    
+        if let Err(e) = apply_bigint_mode(&mut result_value) {
+            return make_error(format!("Cannot serialize return value: {}", e));
+        }
+    
+        // 获取捕获的输出
+        let captured_output = output.borrow().clone();
+    
+        let mut envelope = success_envelope(result_value, captured_output);
+        extend_envelope(lua, &mut envelope);
+        envelope
+    }
+}
+
+/// 把值中的 ±inf 替换为字符串 "Infinity"/"-Infinity"（lua_set_infinity_as_string）
+/// 表先经 mw::deep_clone 复制（保留元表与循环引用）再替换，不修改脚本自己的表；
+/// 不含表和无穷值时原样返回
+fn replace_infinities(lua: &Lua, value: &LuaValue) -> LuaResult<LuaValue> {
+    let infinity = |n: f64| if n > 0.0 { "Infinity" } else { "-Infinity" };
+    let root = match value {
+        LuaValue::Number(n) if n.is_infinite() => return Ok(LuaValue::String(lua.create_string(infinity(*n))?)),
+        LuaValue::Table(_) => mw::deep_clone(lua, value.clone(), 0)?,
+        _ => return Ok(value.clone()),
+    };
+    let LuaValue::Table(table) = &root else {
+        return Ok(root);
+    };
+
+    let mut visited: HashSet<*const std::ffi::c_void> = HashSet::new();
+    let mut pending = vec![table.clone()];
+    while let Some(table) = pending.pop() {
+        if !visited.insert(table.to_pointer()) {
+            continue;
+        }
+        let mut replacements = Vec::new();
+        for pair in table.pairs::<LuaValue, LuaValue>() {
+            let (key, value) = pair?;
+            match value {
+                LuaValue::Number(n) if n.is_infinite() => replacements.push((key, infinity(n))),
+                LuaValue::Table(nested) => pending.push(nested),
+                _ => {}
+            }
+        }
+        for (key, text) in replacements {
+            table.raw_set(key, text)?;
+        }
+    }
+    Ok(root)
+}
+
+/// 把 Lua 值转换为 JSON：表按 serialize_table 的规则转换，其他值使用 mlua 的序列化
+pub(crate) fn lua_to_json(lua: &Lua, value: &LuaValue) -> Result<serde_json::Value, String> {
+    value_to_json(lua, value, &mut Vec::new())
+}
+
+fn value_to_json(lua: &Lua, value: &LuaValue, visiting: &mut Vec<*const std::ffi::c_void>) -> Result<serde_json::Value, String> {
+    match value {
+        LuaValue::Table(table) => serialize_table(lua, table, visiting),
+        _ => serde_json::to_value(value).map_err(|e| e.to_string()),
+    }
+}
+
+/// 把表转换为 JSON 数组或对象：
+/// - 键恰好是从 1 开始连续的整数 1..n 时为数组，按键的顺序输出
+/// - 其他情况（有空洞的稀疏数组、数组部分与哈希部分混合、非正整数键等）都为对象，不丢弃任何键；
+///   对象的键转为字符串：整数输出十进制（`{[1]=1,[3]=3}` 得到 {"1":1,"3":3}），
+///   浮点数和布尔值同样输出其文本形式，表、函数等其他类型的键报错
+/// - 空表带有 mlua 的数组元表（json.decode 得到的数组等）时为 []，否则为 {}
+///
+/// visiting 记录正在转换的表，循环引用时报错 "recursive table detected"；同一个表在不同位置出现多次不受影响
+fn serialize_table(lua: &Lua, table: &LuaTable, visiting: &mut Vec<*const std::ffi::c_void>) -> Result<serde_json::Value, String> {
+    let pointer = table.to_pointer();
+    if visiting.contains(&pointer) {
+        return Err("recursive table detected".to_string());
+    }
+    let mut entries = table
+        .pairs::<LuaValue, LuaValue>()
+        .collect::<LuaResult<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    let is_array = serializes_as_array(lua, table, entries.iter().map(|(key, _)| key));
+    visiting.push(pointer);
+    let result = if is_array {
+        entries.sort_by_key(|(key, _)| key.as_integer());
+        entries
+            .iter()
+            .map(|(_, value)| value_to_json(lua, value, visiting))
+            .collect::<Result<Vec<_>, _>>()
+            .map(serde_json::Value::Array)
+    } else {
+        entries
+            .iter()
+            .map(|(key, value)| Ok((table_key_to_string(key)?, value_to_json(lua, value, visiting)?)))
+            .collect::<Result<serde_json::Map<_, _>, String>>()
+            .map(serde_json::Value::Object)
+    };
+    visiting.pop();
+    result
+}
+
+/// serialize_table 判断表输出为数组的规则（mw.inspectTable 同样使用）：
+/// 非空的表键恰好是 1..n 时为数组，空表带有 mlua 的数组元表时为数组
+pub(crate) fn serializes_as_array<'a>(lua: &Lua, table: &LuaTable, keys: impl ExactSizeIterator<Item = &'a LuaValue>) -> bool {
+    // 键互不相同，因此 n 个键都落在 1..=n 中即说明键恰好是 1..n
+    let len = keys.len() as i64;
+    if len == 0 {
+        return table.metatable().is_some_and(|mt| mt == lua.array_metatable());
+    }
+    let mut keys = keys;
+    keys.all(|key| matches!(key, LuaValue::Integer(i) if (1..=len).contains(i)))
+}
+
+/// 对象形式的表的键转为字符串
+fn table_key_to_string(key: &LuaValue) -> Result<String, String> {
+    match key {
+        LuaValue::String(s) => Ok(s.to_string_lossy()),
+        LuaValue::Integer(i) => Ok(i.to_string()),
+        LuaValue::Number(n) => Ok(n.to_string()),
+        LuaValue::Boolean(b) => Ok(b.to_string()),
+        other => Err(format!("cannot serialize table key of type {}", other.type_name())),
+    }
+}
+
+/// 把一个返回值转换为 JSON
+fn return_value_to_json(lua: &Lua, value: &LuaValue) -> Result<serde_json::Value, String> {
+    match lua_to_json(lua, value) {
+        Ok(json_val) => Ok(json_val),
+        Err(e) => {
+            // 如果序列化失败（例如包含 userdata、thread 等不可序列化类型）
+            // 尝试基本类型的回退处理
+            match value {
+                LuaValue::Nil => Ok(serde_json::Value::Null),
+                LuaValue::Boolean(b) => Ok(serde_json::Value::Bool(*b)),
+                LuaValue::Integer(i) => Ok(serde_json::json!(i)),
+                LuaValue::Number(n) => Ok(serde_json::json!(n)),
+                LuaValue::String(s) => match s.to_str() {
+                    Ok(t) => {
+                        let str_ref: &str = &t;
+                        Ok(serde_json::Value::String(str_ref.to_string()))
+                    },
+                    Err(_) => Ok(serde_json::Value::String("<invalid utf8>".to_string())),
+                },
+                _ => Err(e.to_string()),
+            }
+        }
+    }
+}
+
+/// 本次运行中产生的警告，非空时以 "warnings" 数组加入结果信封
+#[derive(Default)]
+struct RunWarnings(Vec<String>);
+
+/// 记录一条警告
+fn push_warning(lua: &Lua, message: impl Into<String>) {
+    if lua.app_data_ref::<RunWarnings>().is_none() {
+        lua.set_app_data(RunWarnings::default());
+    }
+    if let Some(mut warnings) = lua.app_data_mut::<RunWarnings>() {
+        warnings.0.push(message.into());
+    }
+}
+
+/// 本次运行中已经报告过的弃用 API
+#[derive(Default)]
+struct ReportedDeprecations(HashSet<&'static str>);
+
+/// 记录一次弃用 API 的使用：每次运行中每个 API 只产生一条警告
+fn warn_deprecated(lua: &Lua, api: &'static str, replacement: &'static str) {
+    if lua.app_data_ref::<ReportedDeprecations>().is_none() {
+        lua.set_app_data(ReportedDeprecations::default());
+    }
+    let first_use = lua
+        .app_data_mut::<ReportedDeprecations>()
+        .is_some_and(|mut reported| reported.0.insert(api));
+    if first_use {
+        push_warning(lua, format!("{} is deprecated, use {} instead", api, replacement));
+    }
+}
+
+/// 为弃用的 API 创建转发到新函数的别名，调用时记录弃用警告
+fn deprecated_alias(lua: &Lua, api: &'static str, replacement: &'static str, target: LuaFunction) -> LuaResult<LuaFunction> {
+    lua.create_function(move |lua, args: LuaMultiValue| {
+        warn_deprecated(lua, api, replacement);
+        target.call::<LuaMultiValue>(args)
+    })
+}
+
+/// 计数钩子每隔多少条指令触发一次，报告的指令数按此粒度向下取整
+const INSTRUCTION_COUNT_INTERVAL: u32 = 100;
+
+/// 本次运行已执行的指令数（由计数钩子累加）
+struct InstructionCount(u64);
+
+/// 性能分析最多跟踪的不同函数数量，超出后新出现的函数不再记录
+const PROFILE_MAX_FUNCTIONS: usize = 1000;
+/// 结果中 "profile" 最多输出的函数数量
+const PROFILE_MAX_ENTRIES: usize = 50;
+
+#[derive(Default)]
+struct ProfileEntry {
+    calls: u64,
+    instructions: u64,
+}
+
+/// 本次运行的性能分析数据（"块名:定义行" -> 调用次数和抽样的指令数）
+#[derive(Default)]
+struct Profile(HashMap<String, ProfileEntry>);
+
+/// 钩子当前所在的 Lua 函数的性能分析键，C 函数和内部的 xpcall 包装返回 None
+fn profile_key(debug: &Debug) -> Option<String> {
+    let source = debug.source();
+    if source.what == "C" {
+        return None;
+    }
+    let short_src = source.short_src.as_deref().unwrap_or("?");
+    if short_src == TRACEBACK_SHORT_SRC {
+        return None;
+    }
+    Some(format!("{}:{}", short_src, source.line_defined.unwrap_or(0)))
+}
+
+/// 更新钩子当前所在函数的性能分析数据
+fn record_profile(lua: &Lua, debug: &Debug, update: impl FnOnce(&mut ProfileEntry)) {
+    let Some(key) = profile_key(debug) else {
+        return;
+    };
+    if lua.app_data_ref::<Profile>().is_none() {
+        lua.set_app_data(Profile::default());
+    }
+    if let Some(mut profile) = lua.app_data_mut::<Profile>() {
+        if !profile.0.contains_key(&key) && profile.0.len() >= PROFILE_MAX_FUNCTIONS {
+            return;
+        }
+        update(profile.0.entry(key).or_default());
+    }
+}
+
+/// 把性能分析数据加入结果信封，按指令数、调用次数从高到低排列
+fn add_profile(lua: &Lua, envelope: &mut serde_json::Value) {
+    let Some(profile) = lua.remove_app_data::<Profile>() else {
+        return;
+    };
+    let mut entries: Vec<(String, ProfileEntry)> = profile.0.into_iter().collect();
+    entries.sort_by(|(a_key, a), (b_key, b)| {
+        (b.instructions, b.calls).cmp(&(a.instructions, a.calls)).then_with(|| a_key.cmp(b_key))
+    });
+    let entries: Vec<serde_json::Value> = entries
+        .into_iter()
+        .take(PROFILE_MAX_ENTRIES)
+        .map(|(function, entry)| {
+            serde_json::json!({ "function": function, "calls": entry.calls, "instructions": entry.instructions })
+        })
+        .collect();
+    envelope["profile"] = serde_json::Value::Array(entries);
+}
+
+/// 超出执行限制时由钩子抛出，错误信息直接作为结果的 "error"，不经过 mw.setErrorHandler
+#[derive(Debug)]
+struct ExecutionLimitError(String);
+
+impl std::fmt::Display for ExecutionLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ExecutionLimitError {}
+
+/// lua_request_cancel 取消运行时结果中的错误码
+const RUN_CANCELLED_CODE: &str = "E_CANCELLED";
+
+/// 宿主取消运行时由钩子抛出
+#[derive(Debug)]
+struct RunCancelledError;
+
+impl std::fmt::Display for RunCancelledError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("run cancelled by host")
+    }
+}
+
+impl std::error::Error for RunCancelledError {}
+
+/// 错误链中是否有宿主取消运行的错误
+fn is_run_cancelled(error: &LuaError) -> bool {
+    error.chain().any(|e| e.downcast_ref::<RunCancelledError>().is_some())
+}
+
+/// 在错误链中查找执行限制错误
+fn find_execution_limit(error: &LuaError) -> Option<&ExecutionLimitError> {
+    error.chain().find_map(|e| e.downcast_ref::<ExecutionLimitError>())
+}
+
+/// 错误链中是否有内存分配失败（超出 lua_set_memory_limit 设置的上限）
+fn is_memory_error(error: &LuaError) -> bool {
+    error
+        .chain()
+        .any(|e| matches!(e.downcast_ref::<LuaError>(), Some(LuaError::MemoryError(_))))
+}
+
+/// 按配置安装指令钩子（对之后创建的协程同样生效）：
+/// 开启 lua_set_count_instructions 时统计指令数，设置 lua_set_instruction_limit 时在超出后中止运行，
+/// 设置 lua_set_timeout_ms 时在同一个钩子中检查截止时间，可取消的上下文还会检查取消标志；
+/// 开启 lua_set_profile 时钩子还在函数调用时触发，记录调用次数并把抽样的指令数记到当前函数上；
+/// 都不需要时移除上一次运行留下的钩子
+fn install_instruction_hook(lua: &Lua, cancel_requested: Option<Rc<Cell<bool>>>) -> LuaResult<()> {
+    let (count_instructions, limit, timeout_ms, profile) = RUN_CONFIG.with(|c| {
+        let config = c.borrow();
+        (config.count_instructions, config.instruction_limit, config.timeout_ms, config.profile)
+    });
+    if !count_instructions && limit == 0 && timeout_ms == 0 && cancel_requested.is_none() && !profile {
+        lua.remove_global_hook();
+        return Ok(());
+    }
+    // 宿主没有实现时钟时无法检查超时，只给出警告
+    let has_clock = host_import_available("clock");
+    if timeout_ms > 0 && !has_clock {
+        push_warning(lua, "lua_set_timeout_ms ignored: host capability 'clock' unavailable");
+    }
+    let deadline = (timeout_ms > 0 && has_clock).then(|| unsafe { js_now_ms() } + f64::from(timeout_ms));
+    // 限制较小时缩短间隔，保证不会多执行一整个间隔的指令
+    let interval = match limit {
+        0 => INSTRUCTION_COUNT_INTERVAL,
+        limit => limit.min(INSTRUCTION_COUNT_INTERVAL),
+    };
+    lua.set_app_data(InstructionCount(0));
+    let mut triggers = LuaHookTriggers::new().every_nth_instruction(interval);
+    if profile {
+        // 只需要调用事件：调用次数在调用时记录，指令数来自计数事件，不处理返回事件以减少开销
+        triggers = triggers.on_calls();
+    }
+    lua.set_global_hook(triggers, move |lua, debug| {
+        if profile {
+            if matches!(debug.event(), DebugEvent::Call | DebugEvent::TailCall) {
+                record_profile(lua, debug, |entry| entry.calls += 1);
+                return Ok(LuaVmState::Continue);
+            }
+            record_profile(lua, debug, |entry| entry.instructions += u64::from(interval));
+        }
+        let executed = match lua.app_data_mut::<InstructionCount>() {
+            Some(mut count) => {
+                count.0 += u64::from(interval);
+                count.0
+            }
+            None => return Ok(LuaVmState::Continue),
+        };
+        if cancel_requested.as_ref().is_some_and(|cancel| cancel.get()) {
+            return Err(abort_execution(lua, LuaError::external(RunCancelledError)));
+        }
+        if limit > 0 && executed >= u64::from(limit) {
+            return Err(abort_execution(lua, execution_limit_error("instruction limit exceeded")));
+        }
+        if deadline.is_some_and(|deadline| unsafe { js_now_ms() } >= deadline) {
+            let message = format!("execution timed out after {} ms", timeout_ms);
+            return Err(abort_execution(lua, execution_limit_error(&message)));
+        }
+        Ok(LuaVmState::Continue)
+    })
+}
+
+fn execution_limit_error(message: &str) -> LuaError {
+    LuaError::external(ExecutionLimitError(message.to_string()))
+}
+
+/// 中止本次运行：之后的每条指令都抛出同样的错误，这样被 pcall 捕获后也会在外层再次抛出
+fn abort_execution(lua: &Lua, error: LuaError) -> LuaError {
+    let rethrow = error.clone();
+    // 重新安装钩子失败时仍然返回本次的错误
+    let _ = lua.set_global_hook(LuaHookTriggers::new().every_nth_instruction(1), move |_, _| Err(rethrow.clone()));
+    error
+}
+
+/// 丢弃上一次运行留下的单次运行状态，持久上下文在每次运行前调用
+fn clear_run_state(lua: &Lua) {
+    lua.remove_app_data::<LoadedModules>();
+    lua.remove_app_data::<RdfStats>();
+    lua.remove_app_data::<RdfCacheGeneration>();
+    lua.remove_app_data::<EmittedTriples>();
+    lua.remove_app_data::<ActiveTransaction>();
+    lua.remove_app_data::<RunWarnings>();
+    lua.remove_app_data::<ReportedDeprecations>();
+    lua.remove_app_data::<InstructionCount>();
+    lua.remove_app_data::<Profile>();
+    lua.remove_app_data::<ErrorTraceback>();
+    mw::clear_run_state(lua);
+}
+
+/// 开启指令计数时把执行的指令数加入结果信封
+fn add_instruction_count(lua: &Lua, envelope: &mut serde_json::Value) {
+    let count = lua.remove_app_data::<InstructionCount>();
+    if !RUN_CONFIG.with(|c| c.borrow().count_instructions) {
+        return;
+    }
+    if let Some(count) = count {
+        envelope["instructions"] = serde_json::json!(count.0);
+    }
+}
+
+/// 把本次运行中收集的附加信息（响应头、日志、指标、RDF 统计、产生的三元组、警告等）加入结果信封，没有内容的字段不输出
+fn extend_envelope(lua: &Lua, envelope: &mut serde_json::Value) {
+    add_instruction_count(lua, envelope);
+    add_profile(lua, envelope);
+    if let Some(headers) = mw::take_response_headers(lua) {
+        envelope["headers"] = headers;
+    }
+    if let Some(log) = mw::take_log(lua) {
+        envelope["log"] = serde_json::Value::String(log);
+    }
+    if let Some(metrics) = mw::take_metrics(lua) {
+        envelope["metrics"] = metrics;
+    }
+    if let Some(stats) = lua.remove_app_data::<RdfStats>() {
+        envelope["rdfStats"] = serde_json::json!(stats);
+    }
+    if let Some(emitted) = lua.remove_app_data::<EmittedTriples>() {
+        envelope["emittedTriples"] = serde_json::Value::Array(emitted.0);
+    }
+    lua.remove_app_data::<ReportedDeprecations>();
+    if let Some(warnings) = lua.remove_app_data::<RunWarnings>() {
+        envelope["warnings"] = serde_json::json!(warnings.0);
+    }
+}
+
+/// 将结果信封转为交给宿主的 C 字符串（由 lua_free_result 释放）
+fn envelope_to_c_string(envelope: &serde_json::Value) -> *const c_char {
+    json_to_c_string(envelope.to_string())
+}
+
+/// 把序列化后的 JSON 转为 C 字符串。serde_json 把字符串中的控制字符（包括 Lua 字符串中的 \0）
+/// 转义为 \u0000，因此 JSON 文本中不会出现 NUL 字节，输出和返回值中的 NUL 都能原样交给宿主
+fn json_to_c_string(json: String) -> *const c_char {
+    CString::new(json)
+        .unwrap_or_else(|_| CString::new(r#"{"result":null,"error":"result contains a NUL byte"}"#).unwrap())
+        .into_raw()
+}
+
+/// 序列化结果信封，开启 lua_set_report_timings 时附加 "timings":{"serializeMs":N}
+/// 耗时要在序列化完成后才能得到，因此直接拼接到已序列化的对象末尾，避免大结果再序列化一次
+fn serialize_envelope(envelope: &serde_json::Value) -> String {
+    if !RUN_CONFIG.with(|c| c.borrow().report_timings) || !host_import_available("clock") {
+        return envelope.to_string();
+    }
+    let start = unsafe { js_now_epoch() };
+    let mut payload = envelope.to_string();
+    let serialize_ms = ((unsafe { js_now_epoch() } - start) * 1000.0).max(0.0);
+    let timings = serde_json::json!({ "serializeMs": serialize_ms });
+    if payload.pop() == Some('}') {
+        if !payload.ends_with('{') {
+            payload.push(',');
+        }
+        payload.push_str(&format!("\"timings\":{}}}", timings));
+    }
+    payload
+}
+
+/// 交付结果信封：头部模式下只返回大小信息，完整结果留待 lua_get_last_result 取回
+fn deliver_envelope(envelope: serde_json::Value) -> *const c_char {
+    if !RUN_CONFIG.with(|c| c.borrow().result_header_mode) {
+        return json_to_c_string(serialize_envelope(&envelope));
+    }
+
+    let result_size = envelope["result"].to_string().len();
+    let output_size = envelope["output"].as_str().map(str::len).unwrap_or(0);
+    let payload = serialize_envelope(&envelope);
+    let header = serde_json::json!({
+        "resultSize": result_size,
+        "outputSize": output_size,
+        "payloadSize": payload.len(),
+        "error": envelope["error"],
+    });
+    LAST_RESULT.with(|r| *r.borrow_mut() = Some(payload));
+    envelope_to_c_string(&header)
+}
+
+/// 执行代码，返回结果信封 {"result": ..., "output": "...", "error": null}
+/// 表的键恰好为 1..n 时输出为数组，否则（稀疏、混合等）输出为键转为字符串的对象，见 serialize_table
+/// 代码块返回一个值时 result 为该值；返回多个值时为这些值组成的数组（例如 `return a, b` 得到 [a, b]）；
+/// 没有返回值时为 null，末尾的 nil 不计入
+/// Lua 整数输出为 JSON 整数（`3`），浮点数即使是整值也带小数部分（`3.0`），非有限的浮点数（inf、nan）输出为 null
+/// （lua_set_infinity_as_string 开启时 inf 输出为 "Infinity"/"-Infinity"）
+#[no_mangle]
+pub extern "C" fn lua_run(code_ptr: *const c_char) -> *const c_char {
+    let envelope = match read_c_string(code_ptr) {
+        Ok(code) => run_code(code),
+        Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+    };
+    deliver_envelope(envelope)
+}
+
+/// 执行代码前把 context_json（JSON 对象）的每个顶层键设置为全局变量，例如
+/// {"PAGENAME": "Foo", "args": {"1": "x"}} 使代码可以直接使用 PAGENAME 和 args；
+/// 这些全局变量只在本次运行中存在。上下文不是合法的 JSON 对象时返回 "invalid context JSON: ..."
+#[no_mangle]
+pub extern "C" fn lua_run_with_context(code_ptr: *const c_char, context_json_ptr: *const c_char) -> *const c_char {
+    let envelope = match (read_c_string(code_ptr), read_c_string(context_json_ptr)) {
+        (Ok(code), Ok(context_json)) => match parse_context_json(&context_json) {
+            Ok(context) => run_code_with_context(code, context),
+            Err(message) => error_envelope(serde_json::Value::String(message)),
+        },
+        (Err(e), _) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+        (_, Err(e)) => error_envelope(serde_json::Value::String(format!("invalid context JSON: {}", e))),
+    };
+    deliver_envelope(envelope)
+}
+
+/// lua_run_json 的请求，未知字段被忽略
+#[derive(serde::Deserialize)]
+struct RunRequest {
+    #[serde(default)]
+    code: String,
+    /// 与 lua_run_with_context 的上下文相同
+    #[serde(default)]
+    context: serde_json::Map<String, serde_json::Value>,
+    /// 以下限制只对本次运行生效，省略时使用当前的配置
+    timeout_ms: Option<u32>,
+    instruction_limit: Option<u32>,
+    memory_limit: Option<usize>,
+}
+
+/// 按请求临时覆盖限制后运行代码，运行结束后恢复原来的配置
+fn run_request(request_json: &str) -> serde_json::Value {
+    let request: RunRequest = match serde_json::from_str(request_json) {
+        Ok(request) => request,
+        Err(e) => return error_envelope(serde_json::Value::String(format!("invalid request: {}", e))),
+    };
+    let previous = RUN_CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        let previous = (config.timeout_ms, config.instruction_limit, config.memory_limit);
+        config.timeout_ms = request.timeout_ms.unwrap_or(config.timeout_ms);
+        config.instruction_limit = request.instruction_limit.unwrap_or(config.instruction_limit);
+        config.memory_limit = request.memory_limit.unwrap_or(config.memory_limit);
+        previous
+    });
+    let envelope = run_code_with_context(request.code, request.context);
+    RUN_CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        (config.timeout_ms, config.instruction_limit, config.memory_limit) = previous;
+    });
+    envelope
+}
+
+/// 以一个 JSON 请求运行代码，返回与 lua_run 相同的结果信封：
+/// {"code": "...", "context": {...}, "timeout_ms": 1000, "instruction_limit": 1000000, "memory_limit": 1048576}
+/// 所有字段都可以省略；限制只对本次运行生效，省略的限制使用对应 setter 的当前配置
+#[no_mangle]
+pub extern "C" fn lua_run_json(request_ptr: *const c_char) -> *const c_char {
+    let envelope = match read_c_string(request_ptr) {
+        Ok(request_json) => run_request(&request_json),
+        Err(e) => error_envelope(serde_json::Value::String(format!("invalid request: {}", e))),
+    };
+    deliver_envelope(envelope)
+}
+
+/// 创建持久的执行上下文：安装全部 API 并执行公共代码（lua_set_baseline），
+/// 使用创建时的运行配置；失败时返回空指针。用 lua_run_in_context 运行代码，用 lua_request_cancel 取消运行，
+/// 用 lua_run_isolated 运行不保留模块的代码，用 lua_snapshot / lua_restore 保存和恢复全局状态，用 lua_free_context 释放
+#[no_mangle]
+pub extern "C" fn lua_new_context() -> *mut c_void {
+    match LuaContext::new() {
+        Ok(mut context) => {
+            context.cancel_requested = Some(Rc::new(Cell::new(false)));
+            let ctx = Box::into_raw(Box::new(context));
+            LIVE_CONTEXTS.with(|c| c.borrow_mut().insert(ctx as usize));
+            ctx as *mut c_void
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// 在 lua_new_context 创建的上下文中运行代码，返回与 lua_run 相同的结果信封
+/// 全局变量、package.loaded 和 State 在多次调用之间保留，输出在每次调用前清空
+#[no_mangle]
+pub extern "C" fn lua_run_in_context(ctx: *mut c_void, code_ptr: *const c_char) -> *const c_char {
+    let envelope = if ctx.is_null() {
+        error_envelope(serde_json::Value::String("invalid context".to_string()))
+    } else {
+        let context = unsafe { &*(ctx as *const LuaContext) };
+        match read_c_string(code_ptr) {
+            Ok(code) => {
+                let source = code.clone();
+                let mut envelope = context.run(move |lua| eval_code(lua, code));
+                add_source_context(&mut envelope, &source);
+                envelope
+            }
+            Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+        }
+    };
+    deliver_envelope(envelope)
+}
+
+/// 在上下文中运行代码，运行结束后把 package.loaded 恢复为运行前的内容：
+/// 本次运行中 require 的模块不会留在上下文中，下一次运行会重新加载（模块源码仍然使用缓存）；
+/// 全局变量、已安装的 API 和 State 与 lua_run_in_context 一样保留。
+/// 设置了 lua_set_context_baseline 时，每次运行前先把全局状态恢复到公共代码执行后的样子，
+/// 各次隔离运行都能使用公共代码定义的内容，但看不到其他运行留下的全局变量。返回与 lua_run 相同的结果信封
+#[no_mangle]
+pub extern "C" fn lua_run_isolated(ctx: *mut c_void, code_ptr: *const c_char) -> *const c_char {
+    let envelope = if ctx.is_null() {
+        error_envelope(serde_json::Value::String("invalid context".to_string()))
+    } else {
+        let context = unsafe { &*(ctx as *const LuaContext) };
+        match read_c_string(code_ptr) {
+            Ok(code) => {
+                let source = code.clone();
+                let mut envelope = context.run(move |lua| {
+                    if let Some(baseline) = context.baseline.borrow().as_ref() {
+                        baseline.restore()?;
+                    }
+                    let package: Table = lua.globals().get("package")?;
+                    let loaded = TableSnapshot::capture(package.get("loaded")?)?;
+                    let values = eval_code(lua, code);
+                    loaded.restore()?;
+                    values
+                });
+                add_source_context(&mut envelope, &source);
+                envelope
+            }
+            Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+        }
+    };
+    deliver_envelope(envelope)
+}
+
+/// 在上下文中执行一次公共代码（加载常用模块、定义辅助函数等）并把执行后的全局状态记录为
+/// lua_run_isolated 的起点；再次调用时在当前状态上执行新代码并替换记录，空字符串表示清除记录
+/// 公共代码的输出被丢弃；返回 0 表示成功，-1 表示上下文无效或代码执行出错（此时记录保持不变）
+#[no_mangle]
+pub extern "C" fn lua_set_context_baseline(ctx: *mut c_void, code_ptr: *const c_char) -> i32 {
+    if ctx.is_null() {
+        return -1;
+    }
+    let context = unsafe { &*(ctx as *const LuaContext) };
+    let Ok(code) = read_c_string(code_ptr) else {
+        return -1;
+    };
+    if code.trim().is_empty() {
+        context.baseline.replace(None);
+        return 0;
+    }
+    let executed = context.lua.load(&code).set_name("baseline").exec();
+    context.output.borrow_mut().clear();
+    match executed.and_then(|_| GlobalsSnapshot::capture(&context.lua)) {
+        Ok(snapshot) => {
+            context.baseline.replace(Some(snapshot));
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// 请求取消上下文中正在进行的运行（可以在宿主回调中调用），该运行在下一次指令钩子检查时中止，
+/// 结果为 {"error": "run cancelled by host", "code": "E_CANCELLED"}；上下文没有在运行时请求被忽略
+#[no_mangle]
+pub extern "C" fn lua_request_cancel(ctx: *mut c_void) {
+    if ctx.is_null() {
+        return;
+    }
+    let context = unsafe { &*(ctx as *const LuaContext) };
+    if let Some(cancel) = &context.cancel_requested {
+        cancel.set(true);
+    }
+}
+
+/// 记录上下文当前的全局状态（从全局表可以到达的所有表的内容，包括 package.loaded），
+/// 返回快照编号，失败时返回 0；快照随上下文一起释放，也可以用 lua_free_snapshot 提前释放
+#[no_mangle]
+pub extern "C" fn lua_snapshot(ctx: *mut c_void) -> u64 {
+    if ctx.is_null() {
+        return 0;
+    }
+    let context = unsafe { &*(ctx as *const LuaContext) };
+    let Ok(snapshot) = GlobalsSnapshot::capture(&context.lua) else {
+        return 0;
+    };
+    let id = context.next_snapshot.get();
+    context.next_snapshot.set(id + 1);
+    context.snapshots.borrow_mut().insert(id, snapshot);
+    id
+}
+
+/// 把上下文的全局状态恢复为 lua_snapshot 记录的快照，快照可以多次恢复
+/// 返回 0 表示成功，-1 表示上下文或快照编号无效或恢复失败
+#[no_mangle]
+pub extern "C" fn lua_restore(ctx: *mut c_void, snapshot: u64) -> i32 {
+    if ctx.is_null() {
+        return -1;
+    }
+    let context = unsafe { &*(ctx as *const LuaContext) };
+    let snapshots = context.snapshots.borrow();
+    let Some(snapshot) = snapshots.get(&snapshot) else {
+        return -1;
+    };
+    match snapshot.restore().and_then(|_| reapply_disabled_apis(&context.lua)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// 释放 lua_snapshot 记录的快照，返回 0 表示成功，-1 表示上下文或快照编号无效
+#[no_mangle]
+pub extern "C" fn lua_free_snapshot(ctx: *mut c_void, snapshot: u64) -> i32 {
+    if ctx.is_null() {
+        return -1;
+    }
+    let context = unsafe { &*(ctx as *const LuaContext) };
+    match context.snapshots.borrow_mut().remove(&snapshot) {
+        Some(_) => 0,
+        None => -1,
+    }
+}
+
+/// 释放 lua_new_context 创建的上下文：关闭其中的 Lua 状态并释放快照；已经释放的上下文和空指针被忽略
+/// 运行器没有缓冲在上下文中的写入（State 的写入立即交给宿主），释放时不需要额外的提交
+#[no_mangle]
+pub extern "C" fn lua_free_context(ctx: *mut c_void) {
+    if LIVE_CONTEXTS.with(|c| c.borrow_mut().remove(&(ctx as usize))) {
+        unsafe { drop(Box::from_raw(ctx as *mut LuaContext)) };
+    }
+}
+
+/// 尚未释放的上下文数量，用于宿主检查是否有忘记释放的上下文
+#[no_mangle]
+pub extern "C" fn lua_context_count() -> u32 {
+    LIVE_CONTEXTS.with(|c| c.borrow().len() as u32)
+}
+
+/// 释放所有尚未释放的上下文（例如宿主热重载时），效果与对每个上下文调用 lua_free_context 相同；
+/// 之后这些上下文的指针都不能再使用
+#[no_mangle]
+pub extern "C" fn lua_destroy_all_contexts() {
+    // 先清空登记表再逐个释放
+    let contexts: Vec<usize> = LIVE_CONTEXTS.with(|c| c.borrow_mut().drain().collect());
+    for ctx in contexts {
+        unsafe { drop(Box::from_raw(ctx as *mut LuaContext)) };
+    }
+}
+
+/// 把 JSON 数组转换为位置参数列表，空字符串表示没有参数
+fn json_args_to_lua(lua: &Lua, args_json: &str) -> LuaResult<Vec<LuaValue>> {
+    match json_to_lua_value(lua, if args_json.is_empty() { "[]" } else { args_json })? {
+        LuaValue::Table(args) => args.sequence_values::<LuaValue>().collect(),
+        _ => Err(LuaError::external("function arguments must be a JSON array")),
+    }
+}
+
+/// lua_run_batch 中的一个代码片段
+#[derive(serde::Deserialize)]
+struct BatchSnippet {
+    code: String,
+    /// 代码块名称（出现在错误信息中），默认为 "input"
+    name: Option<String>,
+    /// 作为 ... 传给代码块的参数（JSON 数组）
+    args: Option<serde_json::Value>,
+}
+
+/// 执行一个批量代码片段，args 作为代码块的可变参数传入
+fn eval_snippet(lua: &Lua, snippet: BatchSnippet) -> LuaResult<LuaMultiValue> {
+    let args = match &snippet.args {
+        Some(args) => json_args_to_lua(lua, &args.to_string())?,
+        None => Vec::new(),
+    };
+    let code = if RUN_CONFIG.with(|c| c.borrow().auto_display) {
+        auto_display_source(lua, &snippet.code).unwrap_or(snippet.code)
+    } else {
+        snippet.code
+    };
+    let chunk = lua
+        .load(&code)
+        .set_name(snippet.name.as_deref().unwrap_or("input"))
+        .into_function()?;
+    call_with_traceback(lua, chunk, LuaMultiValue::from_vec(args))
+}
+
+/// 依次运行一组代码片段，返回与输入顺序一致的结果信封数组；一个片段出错不影响其他片段
+fn run_batch(batch_json: &str) -> serde_json::Value {
+    let snippets: Vec<serde_json::Value> = match serde_json::from_str(batch_json) {
+        Ok(snippets) => snippets,
+        Err(e) => return error_envelope(serde_json::Value::String(format!("invalid batch: {}", e))),
+    };
+    let shared = if RUN_CONFIG.with(|c| c.borrow().batch_shared_context) {
+        match LuaContext::new() {
+            Ok(context) => Some(context),
+            Err(message) => return error_envelope(serde_json::Value::String(message)),
+        }
+    } else {
+        None
+    };
+
+    let envelopes = snippets
+        .into_iter()
+        .enumerate()
+        .map(|(index, snippet)| {
+            let snippet: BatchSnippet = match serde_json::from_value(snippet) {
+                Ok(snippet) => snippet,
+                Err(e) => {
+                    return error_envelope(serde_json::Value::String(format!("invalid snippet at index {}: {}", index, e)))
+                }
+            };
+            match &shared {
+                Some(context) => context.run(move |lua| eval_snippet(lua, snippet)),
+                None => run_entry(move |lua| eval_snippet(lua, snippet)),
+            }
+        })
+        .collect();
+    serde_json::Value::Array(envelopes)
+}
+
+/// 批量运行代码片段：json 为 [{"code": "...", "name"?: "...", "args"?: [...]}, ...]
+/// 返回结果信封的 JSON 数组（与 lua_run 的信封相同，不受头部模式影响）；
+/// 默认每个片段在新的环境中运行，lua_set_batch_shared_context(1) 时在同一个上下文中依次运行
+/// 输入不是数组时返回单个错误信封；结果需用 lua_free_result 释放
+#[no_mangle]
+pub extern "C" fn lua_run_batch(json_ptr: *const c_char) -> *const c_char {
+    let result = match read_c_string(json_ptr) {
+        Ok(batch_json) => run_batch(&batch_json),
+        Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read batch: {}", e))),
+    };
+    envelope_to_c_string(&result)
+}
+
+/// 加载模块并以位置参数调用其导出的函数，返回函数的第一个返回值
+fn call_module_function(lua: &Lua, module: &str, function: &str, args_json: &str) -> LuaResult<LuaValue> {
+    let args = json_args_to_lua(lua, args_json)?;
+
+    let require: LuaFunction = lua.globals().get("require")?;
+    let exports: LuaValue = require.call(module)?;
+    let LuaValue::Table(exports) = exports else {
+        return Err(LuaError::external(format!("module '{}' did not return a table", module)));
+    };
+    let LuaValue::Function(func) = exports.get::<LuaValue>(function)? else {
+        return Err(LuaError::external(format!("module '{}' has no function '{}'", module, function)));
+    };
+    func.call(LuaMultiValue::from_vec(args))
+}
+
+/// 预览 require(name) 解析后的模块名称，不会获取模块，也不创建 Lua 状态
+/// base 为发起 require 的模块（如 "mediawiki://wiki/Module:Main"），为空表示顶层代码；
+/// 应用当前的别名和命名空间规则，返回 {"resolved":"..."}；结果需用 lua_free_result 释放
+/// 第一个参数是 base 字符串而不是上下文句柄：MediaWiki base 栈只在 require 执行期间存在，
+/// 运行之间的上下文中没有可以预览的栈，由宿主直接传入要模拟的发起模块
+#[no_mangle]
+pub extern "C" fn lua_resolve_module(base_ptr: *const c_char, name_ptr: *const c_char) -> *const c_char {
+    let result = match (read_c_string(base_ptr), read_c_string(name_ptr)) {
+        (Ok(base), Ok(name)) => {
+            serde_json::json!({ "resolved": resolve_module_name(mediawiki_base(&base), &name) })
+        }
+        (Err(e), _) | (_, Err(e)) => serde_json::json!({ "error": format!("Failed to read arguments: {}", e) }),
+    };
+    envelope_to_c_string(&result)
+}
+
+/// 直接调用模块导出的函数（不使用 Scribunto frame）
+/// args_json 为 JSON 数组，按顺序作为位置参数传入；为空时不传参数
+/// 返回与 lua_run 相同的结果信封，result 为函数的第一个返回值
+#[no_mangle]
+pub extern "C" fn lua_run_function(
+    module_ptr: *const c_char,
+    func_ptr: *const c_char,
+    args_json_ptr: *const c_char,
+) -> *const c_char {
+    let read_args = || -> LuaResult<(String, String, String)> {
+        Ok((read_c_string(module_ptr)?, read_c_string(func_ptr)?, read_c_string(args_json_ptr)?))
+    };
+    let envelope = match read_args() {
+        Ok((module, function, args_json)) => {
+            run_entry(move |lua| {
+                call_module_function(lua, &module, &function, &args_json).map(|value| LuaMultiValue::from_vec(vec![value]))
+            })
+        }
+        Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read arguments: {}", e))),
+    };
+    deliver_envelope(envelope)
+}
+
+/// 对代码做词法分析而不执行，返回记号数组 [{"type","value","line","col"}, ...]
+/// type 为 keyword、name、number、string、operator 或 comment；
+/// 词法错误时返回 {"error":"..."}；结果需用 lua_free_result 释放
+#[no_mangle]
+pub extern "C" fn lua_tokenize(code_ptr: *const c_char) -> *const c_char {
+    let result = match read_c_string(code_ptr) {
+        Ok(code) => match lexer::tokenize(&code) {
+            Ok(tokens) => serde_json::to_value(tokens).unwrap_or_default(),
+            Err(e) => serde_json::json!({ "error": e }),
+        },
+        Err(e) => serde_json::json!({ "error": format!("Failed to read code: {}", e) }),
+    };
+    envelope_to_c_string(&result)
+}
+
+/// lua_describe_api 展开嵌套表的最大层数（mw.text.jsonEncode 为第 3 层）
+const DESCRIBE_MAX_DEPTH: usize = 4;
+
+/// 描述运行环境提供给模块的 API，用于生成文档和编辑器补全；结果需用 lua_free_result 释放
+/// 返回以点号路径为键的对象，例如
+/// {"State": {"type": "table"}, "State.insert": {"type": "function", "params": null, "vararg": null}, ...}
+/// 按当前配置（禁用的 API、兼容模式、常量）创建一个只安装了 API 的新环境，不执行公共代码（lua_set_baseline），
+/// 因此描述不依赖公共代码的副作用；只列出标准库之外的全局变量及其字段。
+/// Lua 实现的函数给出固定参数个数 params 和是否接受可变参数 vararg；
+/// Rust 实现的函数无法内省参数，两者为 null。创建环境失败时返回 {"error":"..."}
+#[no_mangle]
+pub extern "C" fn lua_describe_api() -> *const c_char {
+    let result = match LuaContext::with_apis().and_then(|context| describe_api(&context.lua).map_err(|e| e.to_string())) {
+        Ok(description) => description,
+        Err(e) => serde_json::json!({ "error": e }),
+    };
+    envelope_to_c_string(&result)
+}
+
+fn describe_api(lua: &Lua) -> LuaResult<serde_json::Value> {
+    // 与未安装任何 API 的环境比较，找出运行器额外提供的全局变量
+    let standard_lua = Lua::new();
+    let standard = standard_lua.globals();
+    let mut entries = serde_json::Map::new();
+    let mut visited = HashSet::new();
+    for pair in lua.globals().pairs::<LuaValue, LuaValue>() {
+        let (name, value) = pair?;
+        let LuaValue::String(name) = name else {
+            continue;
+        };
+        let name = name.to_string_lossy();
+        if standard.contains_key(name.as_str())? {
+            continue;
+        }
+        describe_value(lua, &name, &value, 1, &mut visited, &mut entries)?;
+    }
+    Ok(serde_json::Value::Object(entries))
+}
+
+/// 记录一个值的描述；表的字符串键字段以 "名称.字段" 继续展开，每个表只展开一次
+fn describe_value(
+    lua: &Lua,
+    name: &str,
+    value: &LuaValue,
+    depth: usize,
+    visited: &mut HashSet<*const std::ffi::c_void>,
+    entries: &mut serde_json::Map<String, serde_json::Value>,
+) -> LuaResult<()> {
+    let entry = match value {
+        LuaValue::Function(function) => {
+            let (params, vararg) = lua_function_arity(lua, function).unzip();
+            serde_json::json!({ "type": "function", "params": params, "vararg": vararg })
+        }
+        other => serde_json::json!({ "type": other.type_name() }),
+    };
+    entries.insert(name.to_string(), entry);
+
+    if let LuaValue::Table(table) = value {
+        if depth < DESCRIBE_MAX_DEPTH && visited.insert(table.to_pointer()) {
+            for pair in table.pairs::<LuaValue, LuaValue>() {
+                let (key, field) = pair?;
+                if let LuaValue::String(key) = key {
+                    let path = format!("{}.{}", name, key.to_string_lossy());
+                    describe_value(lua, &path, &field, depth + 1, visited, entries)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lua 函数的固定参数个数以及是否接受可变参数；Rust 实现的函数没有这些信息，返回 None
+fn lua_function_arity(lua: &Lua, function: &LuaFunction) -> Option<(u8, bool)> {
+    if function.info().what != "Lua" {
+        return None;
+    }
+    // lua_getinfo(">u") 弹出栈顶的函数并填写参数信息，随后压入两个结果作为返回值
+    unsafe {
+        lua.exec_raw::<(u8, bool)>(function.clone(), |state| {
+            let mut ar: mlua::ffi::lua_Debug = std::mem::zeroed();
+            mlua::ffi::lua_getinfo(state, c">u".as_ptr(), &mut ar);
+            mlua::ffi::lua_pushinteger(state, mlua::ffi::lua_Integer::from(ar.nparams));
+            mlua::ffi::lua_pushboolean(state, (ar.isvararg != 0) as std::ffi::c_int);
+        })
+    }
+    .ok()
+}
+
+/// 标准 base64 编码（带 = 填充）
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let n = group.iter().enumerate().fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// 运行代码并保存 base64 编码的结果信封（与 lua_run 返回的 JSON 相同，包括 timings），返回用于分块读取的句柄（从 1 开始）
+/// 通过 lua_result_total_size 和 lua_result_chunk 读取，读完后用 lua_free_chunked_result 释放；
+/// 分块读取本身就是按需取回完整结果的方式，因此不受 lua_set_result_header_mode 影响
+#[no_mangle]
+pub extern "C" fn lua_run_chunked(code_ptr: *const c_char) -> u32 {
+    let envelope = match read_c_string(code_ptr) {
+        Ok(code) => run_code(code),
+        Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+    };
+    let handle = NEXT_CHUNKED_HANDLE.with(|next| {
+        let handle = next.get();
+        next.set(handle.checked_add(1).unwrap_or(1));
+        handle
+    });
+    let encoded = base64_encode(serialize_envelope(&envelope).as_bytes());
+    CHUNKED_RESULTS.with(|r| r.borrow_mut().insert(handle, encoded.into_bytes()));
+    handle
+}
+
+/// 返回句柄对应结果（base64 文本）的总字节数，句柄无效时返回 0
+#[no_mangle]
+pub extern "C" fn lua_result_total_size(handle: u32) -> u32 {
+    CHUNKED_RESULTS.with(|r| r.borrow().get(&handle).map_or(0, |bytes| bytes.len() as u32))
+}
+
+/// 返回 base64 结果的第 index 块，字节数写入 len_out。块大小为 max_bytes 向下取整到 4 的倍数（最后一块可能更短），
+/// 因此每一块都是完整的 base64 文本，可以单独解码。
+/// 返回的指针直接指向保存的结果，在 lua_free_chunked_result 之前有效，宿主不需要释放；
+/// 句柄无效、max_bytes 小于 4 或 index 超出范围时返回空指针
+#[no_mangle]
+pub extern "C" fn lua_result_chunk(handle: u32, index: u32, max_bytes: u32, len_out: *mut u32) -> *const c_uchar {
+    let chunk = CHUNKED_RESULTS.with(|r| {
+        let results = r.borrow();
+        let bytes = results.get(&handle)?;
+        let chunk_size = max_bytes as usize / 4 * 4;
+        let start = (index as usize).checked_mul(chunk_size)?;
+        if chunk_size == 0 || start >= bytes.len() {
+            return None;
+        }
+        let end = bytes.len().min(start + chunk_size);
+        Some((bytes[start..end].as_ptr(), end - start))
+    });
+    match chunk {
+        Some((ptr, len)) => {
+            write_len_out(len_out, len);
+            ptr
+        }
+        None => {
+            write_len_out(len_out, 0);
+            std::ptr::null()
+        }
+    }
+}
+
+/// 释放 lua_run_chunked 保存的结果
+#[no_mangle]
+pub extern "C" fn lua_free_chunked_result(handle: u32) {
+    CHUNKED_RESULTS.with(|r| r.borrow_mut().remove(&handle));
+}
+
+/// 文本模式下分隔输出与返回值的行
+const TEXT_RETURN_SEPARATOR: &str = "-- return --";
+/// 文本模式下分隔输出与错误信息的行
+const TEXT_ERROR_SEPARATOR: &str = "-- error --";
+
+/// 将结果信封转为纯文本：捕获的输出、分隔行，以及字符串化的返回值（或错误信息）
+/// 字符串原样输出，nil 输出为 "nil"，其他值输出为 JSON
+fn envelope_to_text(envelope: &serde_json::Value) -> String {
+    let mut text = envelope["output"].as_str().unwrap_or_default().to_string();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+
+    let (separator, value) = match &envelope["error"] {
+        serde_json::Value::Null => (TEXT_RETURN_SEPARATOR, &envelope["result"]),
+        error => (TEXT_ERROR_SEPARATOR, error),
+    };
+    text.push_str(separator);
+    text.push('\n');
+    match value {
+        serde_json::Value::Null => text.push_str("nil"),
+        serde_json::Value::String(s) => text.push_str(s),
+        other => text.push_str(&other.to_string()),
+    }
+    text
+}
+
+/// 以文本模式运行代码，适用于不解析 JSON 的简单宿主
+/// 返回 "<输出>\n-- return --\n<返回值>"，出错时分隔行为 "-- error --"；结果需用 lua_free_result 释放
+#[no_mangle]
+pub extern "C" fn lua_run_text(code_ptr: *const c_char) -> *const c_char {
+    let envelope = match read_c_string(code_ptr) {
+        Ok(code) => run_code(code),
+        Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+    };
+    let text = envelope_to_text(&envelope).replace('\0', "\\0");
+    CString::new(text)
+        .unwrap_or_else(|_| CString::new(TEXT_ERROR_SEPARATOR).unwrap())
+        .into_raw()
+}
+
+/// 执行代码，返回与 lua_run 相同的 JSON 结果信封，但 result 是返回值的 YAML 文本，便于直接展示给用户
+/// 返回值先按 JSON 路径转换（NaN、循环引用等的处理相同）再由 yaml::to_yaml 输出；出错时 result 为 null
+#[no_mangle]
+pub extern "C" fn lua_run_yaml(code_ptr: *const c_char) -> *const c_char {
+    let mut envelope = match read_c_string(code_ptr) {
+        Ok(code) => run_code(code),
+        Err(e) => error_envelope(serde_json::Value::String(format!("Failed to read code: {}", e))),
+    };
+    if envelope["error"].is_null() {
+        envelope["result"] = serde_json::Value::String(yaml::to_yaml(&envelope["result"]));
+    }
+    deliver_envelope(envelope)
+}
+
+/// 把长度写入宿主传入的输出参数（空指针时忽略）
+fn write_len_out(len_out: *mut u32, len: usize) {
+    if let Some(len_out) = unsafe { len_out.as_mut() } {
+        *len_out = len as u32;
+    }
+}
+
+/// 取出最近一次运行中 io.writeBytes 写入的二进制输出，字节数写入 len_out
+/// 没有二进制输出时返回空指针；返回的缓冲区需用 lua_free_binary_output 释放
+#[no_mangle]
+pub extern "C" fn lua_get_binary_output(len_out: *mut u32) -> *const c_uchar {
+    let bytes = BINARY_OUTPUT.with(|b| std::mem::take(&mut *b.borrow_mut()));
+    write_len_out(len_out, bytes.len());
+    if bytes.is_empty() {
+        return std::ptr::null();
+    }
+    Box::into_raw(bytes.into_boxed_slice()) as *const c_uchar
+}
+
+/// 释放 lua_get_binary_output 返回的缓冲区
+#[no_mangle]
+pub extern "C" fn lua_free_binary_output(ptr: *const c_uchar, len: u32) {
+    if !ptr.is_null() {
+        unsafe {
+            let slice = std::ptr::slice_from_raw_parts_mut(ptr as *mut u8, len as usize);
+            drop(Box::from_raw(slice));
+        }
+    }
+}
+
+/// 开启（非 0）或关闭（0）结果头部模式
+/// 开启后 lua_run 只返回 {"resultSize":N,"outputSize":M,"payloadSize":P,"error":...}，
+/// 完整的结果信封通过 lua_get_last_result 取回
+#[no_mangle]
+pub extern "C" fn lua_set_result_header_mode(enabled: i32) {
+    RUN_CONFIG.with(|c| c.borrow_mut().result_header_mode = enabled != 0);
+}
+
+/// 取回头部模式下最近一次运行的完整结果信封（只能取回一次，之后返回 NULL）
+/// 返回的字符串同样需要用 lua_free_result 释放
+#[no_mangle]
+pub extern "C" fn lua_get_last_result() -> *const c_char {
+    match LAST_RESULT.with(|r| r.borrow_mut().take()) {
+        Some(payload) => CString::new(payload)
+            .map(|c| c.into_raw() as *const c_char)
+            .unwrap_or(std::ptr::null()),
+        None => std::ptr::null(),
+    }
+}
+
+/// 释放由 lua_run 返回的结果字符串
+/// 必须由 JS 调用以释放内存
+#[no_mangle]
+pub extern "C" fn lua_free_result(ptr: *const c_char) {
+    if !ptr.is_null() {
+        unsafe {
+            // 从原始指针恢复 CString，Drop 时自动释放内存
+            let _ = CString::from_raw(ptr as *mut c_char);
+        }
+    }
+}
+
+#[allow(unused)]
+fn main() {}
+
+#[cfg(test)]
+#[path = "tests.rs"]
+#[allow(clippy::module_inception)]
+mod tests;
 
 #[cfg(test)]
 #[path = "debug_test.rs"]
+#[allow(clippy::module_inception)]
 mod debug_test;
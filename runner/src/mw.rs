@@ -0,0 +1,899 @@
+//! `mw` 全局表：面向 MediaWiki 模块的辅助 API
+
+use mlua::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// 通过 mw.setErrorHandler 注册的错误处理函数
+struct ErrorHandler(LuaFunction);
+
+/// 通过 mw.setResponseHeader 设置的响应头（保持设置顺序，名称不区分大小写）
+#[derive(Default)]
+struct ResponseHeaders(Vec<(String, String)>);
+
+/// mw.log 收集的调试日志；超过 lua_set_log_limit 的上限后丢弃后续条目
+#[derive(Default)]
+struct LogBuffer {
+    text: String,
+    truncated: bool,
+}
+
+/// 通过 mw.emitResult 逐段写入的结果字符串
+#[derive(Default)]
+struct EmittedResult(String);
+
+/// 单次运行最多记录的不同指标名称数量（计数器与观测值合计）
+const MAX_METRICS: usize = 100;
+
+/// 观测值的汇总，只保留统计量以限制内存占用
+struct MetricSummary {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+/// mw.incrementMetric / mw.observeMetric 记录的指标
+#[derive(Default)]
+struct Metrics {
+    counters: BTreeMap<String, f64>,
+    observations: BTreeMap<String, MetricSummary>,
+}
+
+impl Metrics {
+    fn check_new_name(&self, name: &str) -> LuaResult<()> {
+        let known = self.counters.contains_key(name) || self.observations.contains_key(name);
+        if !known && self.counters.len() + self.observations.len() >= MAX_METRICS {
+            return Err(LuaError::external(format!("too many metrics (limit {}) while recording '{}'", MAX_METRICS, name)));
+        }
+        Ok(())
+    }
+}
+
+/// 修改本次运行的指标（首次使用时创建）
+fn with_metrics<R>(lua: &Lua, f: impl FnOnce(&mut Metrics) -> LuaResult<R>) -> LuaResult<R> {
+    if lua.app_data_ref::<Metrics>().is_none() {
+        lua.set_app_data(Metrics::default());
+    }
+    let mut metrics = lua.app_data_mut::<Metrics>().expect("Metrics was just inserted");
+    f(&mut metrics)
+}
+
+/// 达到日志上限时追加的标记（只追加一次）
+const LOG_TRUNCATED_MARKER: &str = "[log truncated]\n";
+
+/// 响应头名称必须是 RFC 7230 的 token
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// mw.abort 抛出的错误，携带模块指定的错误码
+#[derive(Debug)]
+pub(crate) struct AbortError {
+    pub(crate) code: String,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for AbortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for AbortError {}
+
+/// 在错误链中查找 mw.abort 抛出的错误
+pub(crate) fn find_abort(error: &LuaError) -> Option<&AbortError> {
+    error.chain().find_map(|e| e.downcast_ref::<AbortError>())
+}
+
+/// 安装 mw 全局表
+pub(crate) fn install_mw_api(lua: &Lua) -> LuaResult<()> {
+    let mw = lua.create_table()?;
+
+    // mw.setErrorHandler(fn) - 注册未捕获错误的处理函数，传入 nil 取消注册
+    // fn(errorTable) 的返回值会替换结果中的 error 字段
+    let set_error_handler_fn = lua.create_function(|lua, handler: Option<LuaFunction>| {
+        match handler {
+            Some(handler) => {
+                lua.set_app_data(ErrorHandler(handler));
+            }
+            None => {
+                lua.remove_app_data::<ErrorHandler>();
+            }
+        }
+        Ok(())
+    })?;
+    mw.set("setErrorHandler", set_error_handler_fn)?;
+
+    // mw.abort(code, message) - 以指定的错误码终止运行
+    // 结果中的 "code" 和 "error" 字段直接使用这两个值，不经过 mw.setErrorHandler
+    let abort_fn = lua.create_function(|_, (code, message): (String, Option<String>)| -> LuaResult<()> {
+        Err(LuaError::external(AbortError {
+            message: message.unwrap_or_else(|| code.clone()),
+            code,
+        }))
+    })?;
+    mw.set("abort", abort_fn)?;
+
+    // mw.setResponseHeader(name, value) - 设置响应元数据，出现在结果的 "headers" 字段中
+    // 同名（不区分大小写）的响应头会被覆盖，value 为 nil 时删除
+    let set_response_header_fn = lua.create_function(|lua, (name, value): (String, Option<String>)| {
+        if !is_valid_header_name(&name) {
+            return Err(LuaError::external(format!("invalid response header name '{}'", name)));
+        }
+        if value.as_deref().is_some_and(|v| v.contains(['\r', '\n'])) {
+            return Err(LuaError::external(format!("invalid value for response header '{}'", name)));
+        }
+        if lua.app_data_ref::<ResponseHeaders>().is_none() {
+            lua.set_app_data(ResponseHeaders::default());
+        }
+        let mut headers = lua.app_data_mut::<ResponseHeaders>().expect("ResponseHeaders was just inserted");
+        headers.0.retain(|(existing, _)| !existing.eq_ignore_ascii_case(&name));
+        if let Some(value) = value {
+            headers.0.push((name, value));
+        }
+        Ok(())
+    })?;
+    mw.set("setResponseHeader", set_response_header_fn)?;
+
+    // mw.log(...) - 记录调试日志，参数经 tostring 转换后以制表符连接
+    let log_fn = lua.create_function(|lua, values: LuaMultiValue| -> LuaResult<()> {
+        let tostring: LuaFunction = lua.globals().get("tostring")?;
+        let mut parts = Vec::with_capacity(values.len());
+        for value in values {
+            parts.push(tostring.call::<String>(value)?);
+        }
+        let mut entry = parts.join("\t");
+        entry.push('\n');
+
+        let limit = crate::RUN_CONFIG.with(|c| c.borrow().log_limit);
+        if lua.app_data_ref::<LogBuffer>().is_none() {
+            lua.set_app_data(LogBuffer::default());
+        }
+        let mut log = lua.app_data_mut::<LogBuffer>().expect("LogBuffer was just inserted");
+        if log.truncated {
+            return Ok(());
+        }
+        if limit > 0 && log.text.len() + entry.len() > limit {
+            log.truncated = true;
+            log.text.push_str(LOG_TRUNCATED_MARKER);
+        } else {
+            log.text.push_str(&entry);
+        }
+        Ok(())
+    })?;
+    mw.set("log", log_fn)?;
+
+    // mw.emitResult(str) - 逐段写入结果；使用后运行结果为写入内容的拼接，代码块的返回值被忽略
+    let emit_result_fn = lua.create_function(|lua, text: LuaString| -> LuaResult<()> {
+        let text = text.to_str()?.to_string();
+        if lua.app_data_ref::<EmittedResult>().is_none() {
+            lua.set_app_data(EmittedResult::default());
+        }
+        let mut emitted = lua.app_data_mut::<EmittedResult>().expect("EmittedResult was just inserted");
+        emitted.0.push_str(&text);
+        Ok(())
+    })?;
+    mw.set("emitResult", emit_result_fn)?;
+
+    // mw.incrementMetric(name, by) - 计数器累加（by 默认为 1）
+    let increment_metric_fn = lua.create_function(|lua, (name, by): (String, Option<f64>)| {
+        with_metrics(lua, |metrics| {
+            metrics.check_new_name(&name)?;
+            *metrics.counters.entry(name).or_insert(0.0) += by.unwrap_or(1.0);
+            Ok(())
+        })
+    })?;
+    mw.set("incrementMetric", increment_metric_fn)?;
+
+    // mw.observeMetric(name, value) - 记录观测值，结果中汇总为 count/sum/min/max
+    let observe_metric_fn = lua.create_function(|lua, (name, value): (String, f64)| {
+        with_metrics(lua, |metrics| {
+            metrics.check_new_name(&name)?;
+            let summary = metrics.observations.entry(name).or_insert(MetricSummary {
+                count: 0,
+                sum: 0.0,
+                min: value,
+                max: value,
+            });
+            summary.count += 1;
+            summary.sum += value;
+            summary.min = summary.min.min(value);
+            summary.max = summary.max.max(value);
+            Ok(())
+        })
+    })?;
+    mw.set("observeMetric", observe_metric_fn)?;
+
+    // mw.language - 目前只提供英文的内容语言对象
+    let language = lua.create_table()?;
+    let get_content_language_fn = lua.create_function(|lua, ()| create_language_object(lua))?;
+    language.set("getContentLanguage", get_content_language_fn.clone())?;
+    language.set("new", get_content_language_fn)?;
+    mw.set("language", language)?;
+
+    mw.set("ustring", create_ustring_table(lua)?)?;
+    mw.set("text", create_text_table(lua)?)?;
+
+    // mw.getCurrentUser() - 宿主提供的当前用户信息 {name, groups, isAnon, ...}
+    let get_current_user_fn = lua.create_function(|lua, ()| get_current_user(lua))?;
+    mw.set("getCurrentUser", get_current_user_fn)?;
+
+    // mw.clone(value) - 深拷贝（保留元表与循环引用），深度受 lua_set_max_structure_depth 限制
+    let clone_fn = lua.create_function(|lua, value: LuaValue| {
+        let max_depth = crate::RUN_CONFIG.with(|c| c.borrow().max_structure_depth);
+        deep_clone(lua, value, max_depth)
+    })?;
+    mw.set("clone", clone_fn)?;
+
+    // mw.uuid() - 生成 UUID 字符串，方式由 lua_set_uuid_strategy 决定
+    // 宿主通过 lua_seed_uuid 设置种子后，v4 UUID 改由确定性的伪随机数生成器产生
+    let uuid_fn = lua.create_function(|lua, ()| -> LuaResult<String> {
+        let bytes = match crate::RUN_CONFIG.with(|c| c.borrow().uuid_strategy) {
+            crate::UuidStrategy::Random => random_uuid_bytes(lua)?,
+            crate::UuidStrategy::Time => {
+                crate::require_host_import("clock")?;
+                time_ordered_uuid_bytes()
+            }
+        };
+        Ok(format_uuid(&bytes))
+    })?;
+    mw.set("uuid", uuid_fn)?;
+
+    // mw.inspectTable(t) - 返回表的结构信息，用于排查表被序列化为数组还是对象
+    let inspect_table_fn = lua.create_function(|lua, table: LuaTable| inspect_table(lua, &table))?;
+    mw.set("inspectTable", inspect_table_fn)?;
+
+    // mw.elapsedTime() - 返回第一次调用以来经过的秒数（使用宿主的 js_now_epoch），第一次调用返回 0
+    // os.clock 被替换为同一个函数，因为 Emscripten 下的 os.clock 不可靠
+    // 起始时间在第一次调用时才读取，不计时的运行不会调用 js_now_epoch
+    let start: std::cell::Cell<Option<f64>> = std::cell::Cell::new(None);
+    let elapsed_time_fn = lua.create_function(move |_, ()| {
+        crate::require_host_import("clock")?;
+        let now = unsafe { crate::js_now_epoch() };
+        let start = start.get().unwrap_or_else(|| {
+            start.set(Some(now));
+            now
+        });
+        Ok((now - start).max(0.0))
+    })?;
+    mw.set("elapsedTime", elapsed_time_fn.clone())?;
+    if let Some(os) = lua.globals().get::<Option<LuaTable>>("os")? {
+        os.set("clock", elapsed_time_fn)?;
+    }
+
+    // mw.getLimits() - 返回宿主当前配置的限制，未设置（0）的限制为 nil
+    let get_limits_fn = lua.create_function(|lua, ()| get_limits(lua))?;
+    mw.set("getLimits", get_limits_fn)?;
+
+    // mw.currentBase() - 当前 MediaWiki 模块栈顶的站点前缀（例如 "mediawiki://en.wikipedia.org/"），不在 MediaWiki 模块中时为 nil
+    let current_base_fn = lua.create_function(|lua, ()| Ok(current_base(lua)))?;
+    mw.set("currentBase", current_base_fn)?;
+
+    // mw.requireBase(expected) - 当前站点前缀与 expected 不同时报错（忽略末尾的 /），用于模块在错误的上下文中尽早失败
+    let require_base_fn = lua.create_function(|lua, expected: String| -> LuaResult<()> {
+        let current = current_base(lua);
+        if current.as_deref().map(|base| base.trim_end_matches('/')) == Some(expected.trim_end_matches('/')) {
+            return Ok(());
+        }
+        Err(LuaError::external(format!(
+            "mw.requireBase: expected MediaWiki base '{}', but the current base is {}",
+            expected,
+            current.map_or_else(|| "not set".to_string(), |base| format!("'{}'", base))
+        )))
+    })?;
+    mw.set("requireBase", require_base_fn)?;
+
+    lua.globals().set("mw", mw)?;
+    Ok(())
+}
+
+/// MediaWiki 模块栈顶的站点前缀
+fn current_base(lua: &Lua) -> Option<String> {
+    lua.app_data_ref::<crate::MediaWikiStack>()
+        .and_then(|stack| stack.0.last().cloned())
+}
+
+/// 读取宿主提供的用户上下文；宿主没有提供时返回匿名用户
+fn get_current_user(lua: &Lua) -> LuaResult<LuaValue> {
+    let ptr = if crate::host_import_available("user") {
+        unsafe { crate::js_get_user_context() }
+    } else {
+        std::ptr::null()
+    };
+    if !ptr.is_null() {
+        let context = crate::take_rdf_result(ptr).map_err(LuaError::external)?;
+        if !context.is_empty() && context != "null" {
+            return crate::json_to_lua_value(lua, &context);
+        }
+    }
+
+    let user = lua.create_table()?;
+    user.set("name", "")?;
+    user.set("groups", lua.create_sequence_from(Vec::<String>::new())?)?;
+    user.set("isAnon", true)?;
+    Ok(LuaValue::Table(user))
+}
+
+thread_local! {
+    /// 时间排序 UUID 的状态：上次使用的毫秒时间戳和递增计数器
+    static UUID_CLOCK: std::cell::Cell<(u64, u64)> = const { std::cell::Cell::new((0, 0)) };
+}
+
+/// lua_seed_uuid 设置种子时本次运行的伪随机数生成器状态（每次运行从种子重新开始）
+struct SeededUuidRng(u64);
+
+/// splitmix64：由状态产生下一个 64 位伪随机数
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// 生成 v4 UUID 的 16 个字节：设置了种子时使用确定性的伪随机数，否则使用宿主随机源
+fn random_uuid_bytes(lua: &Lua) -> LuaResult<[u8; 16]> {
+    let mut bytes = [0u8; 16];
+    if let Some(seed) = crate::RUN_CONFIG.with(|c| c.borrow().uuid_seed) {
+        if lua.app_data_ref::<SeededUuidRng>().is_none() {
+            lua.set_app_data(SeededUuidRng(seed));
+        }
+        let mut rng = lua.app_data_mut::<SeededUuidRng>().expect("uuid rng state");
+        bytes[..8].copy_from_slice(&splitmix64(&mut rng.0).to_be_bytes());
+        bytes[8..].copy_from_slice(&splitmix64(&mut rng.0).to_be_bytes());
+    } else {
+        crate::require_host_import("random")?;
+        if unsafe { crate::js_random_bytes(bytes.as_mut_ptr(), bytes.len() as u32) } != 0 {
+            return Err(LuaError::external("random source unavailable; use lua_set_uuid_strategy(\"time\")"));
+        }
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(bytes)
+}
+
+/// 生成 v7 UUID 的 16 个字节：48 位毫秒时间戳 + 递增计数器
+///
+/// 时间戳不会回退（取与上次相比的较大值），计数器在同一线程内单调递增，
+/// 因此连续生成的 UUID 严格递增；不同实例之间的唯一性依赖于时间戳，不能保证。
+fn time_ordered_uuid_bytes() -> [u8; 16] {
+    let now_ms = (unsafe { crate::js_now_epoch() } * 1000.0).max(0.0) as u64;
+    let (millis, counter) = UUID_CLOCK.with(|clock| {
+        let (last_ms, counter) = clock.get();
+        let state = (now_ms.max(last_ms), counter.wrapping_add(1));
+        clock.set(state);
+        state
+    });
+
+    let mut bytes = [0u8; 16];
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[8..].copy_from_slice(&counter.to_be_bytes());
+    bytes[6] = 0x70;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes
+}
+
+/// 按 8-4-4-4-12 格式输出小写十六进制 UUID
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// 深拷贝一个值：表（包括作为键的表）被逐层复制，元表沿用原表的元表，
+/// 同一个表在结果中只复制一次，因此循环引用会被保留
+///
+/// 使用显式栈而不是递归，嵌套很深的表也不会耗尽 Rust 栈；
+/// max_depth 大于 0 时，嵌套层数超出则报错 "structure too deep"
+pub(crate) fn deep_clone(lua: &Lua, value: LuaValue, max_depth: usize) -> LuaResult<LuaValue> {
+    let LuaValue::Table(root) = value else {
+        return Ok(value);
+    };
+
+    let mut clones: HashMap<*const std::ffi::c_void, LuaTable> = HashMap::new();
+    let mut pending: Vec<(LuaTable, LuaTable, usize)> = Vec::new();
+    let mut clone_of = |table: LuaTable, depth: usize, pending: &mut Vec<_>| -> LuaResult<LuaTable> {
+        if let Some(copy) = clones.get(&table.to_pointer()) {
+            return Ok(copy.clone());
+        }
+        if max_depth > 0 && depth > max_depth {
+            return Err(LuaError::external(format!("structure too deep (limit {})", max_depth)));
+        }
+        let copy = lua.create_table()?;
+        clones.insert(table.to_pointer(), copy.clone());
+        pending.push((table, copy.clone(), depth));
+        Ok(copy)
+    };
+
+    let root_copy = clone_of(root, 1, &mut pending)?;
+    while let Some((original, copy, depth)) = pending.pop() {
+        copy.set_metatable(original.metatable())?;
+        for pair in original.pairs::<LuaValue, LuaValue>() {
+            let (key, value) = pair?;
+            let key = match key {
+                LuaValue::Table(t) => LuaValue::Table(clone_of(t, depth + 1, &mut pending)?),
+                other => other,
+            };
+            let value = match value {
+                LuaValue::Table(t) => LuaValue::Table(clone_of(t, depth + 1, &mut pending)?),
+                other => other,
+            };
+            copy.raw_set(key, value)?;
+        }
+    }
+    Ok(LuaValue::Table(root_copy))
+}
+
+/// mw.getLimits 的实现：读取运行配置中的各项限制
+fn get_limits(lua: &Lua) -> LuaResult<LuaTable> {
+    let limits: [(&str, usize); 11] = crate::RUN_CONFIG.with(|c| {
+        let config = c.borrow();
+        [
+            ("instructions", config.instruction_limit as usize),
+            ("memoryBytes", config.memory_limit),
+            ("timeoutMs", config.timeout_ms as usize),
+            ("logBytes", config.log_limit),
+            ("outputBytes", config.output_limit),
+            ("maxModules", config.max_modules_per_run),
+            ("maxStructureDepth", config.max_structure_depth),
+            ("fetchTimeoutMs", config.fetch_timeout_ms as usize),
+            ("rdfMaxObjectBytes", config.rdf_max_object_bytes),
+            ("rdfMaxIriLen", config.rdf_max_iri_len),
+            ("rdfPageSize", config.rdf_page_size),
+        ]
+    });
+    let table = lua.create_table()?;
+    for (name, value) in limits {
+        if value > 0 {
+            table.set(name, value)?;
+        }
+    }
+    Ok(table)
+}
+
+/// 统计表的键，并按序列化时的规则判断它会输出为数组还是对象
+///
+/// 与结果序列化使用同一个判断（crate::serializes_as_array）：非空的表键恰好是 1..N 时输出为数组，
+/// 有空洞、混有其他键时输出为对象；空表带有数组元表时为数组。`isContiguous` 表示键恰好是 1..N。
+fn inspect_table(lua: &Lua, table: &LuaTable) -> LuaResult<LuaTable> {
+    let array_length = table.raw_len();
+    let mut keys = Vec::new();
+    let mut has_string_keys = false;
+    let mut max_index = 0;
+    let mut all_positive_integers = true;
+    for pair in table.pairs::<LuaValue, LuaValue>() {
+        let (key, _) = pair?;
+        match &key {
+            LuaValue::Integer(i) if *i >= 1 => max_index = max_index.max(*i as usize),
+            LuaValue::String(_) => {
+                has_string_keys = true;
+                all_positive_integers = false;
+            }
+            _ => all_positive_integers = false,
+        }
+        keys.push(key);
+    }
+    let total_keys = keys.len();
+    let serializes_as_array = crate::serializes_as_array(lua, table, keys.iter());
+
+    let info = lua.create_table()?;
+    info.set("arrayLength", array_length)?;
+    info.set("hasStringKeys", has_string_keys)?;
+    info.set("totalKeys", total_keys)?;
+    info.set("isContiguous", all_positive_integers && max_index == total_keys)?;
+    info.set("serializesAsArray", serializes_as_array)?;
+    Ok(info)
+}
+
+/// mw.ustring：按 Unicode 码点匹配的 Lua 模式（见 crate::ustring）
+///
+/// `.` 和字符类匹配一个完整的码点，位置按码点计算；字符串必须是合法的 UTF-8。
+fn create_ustring_table(lua: &Lua) -> LuaResult<LuaTable> {
+    let ustring = lua.create_table()?;
+
+    // mw.ustring.gmatch(s, pattern) - 与 string.gmatch 相同的迭代器，按码点匹配
+    let gmatch_fn = lua.create_function(|lua, (s, pattern): (LuaString, LuaString)| {
+        let src = utf8_chars("gmatch", 1, &s)?;
+        let pat = utf8_chars("gmatch", 2, &pattern)?;
+        let state = std::cell::RefCell::new((0, None));
+        lua.create_function(move |lua, ()| -> LuaResult<LuaMultiValue> {
+            let mut state = state.borrow_mut();
+            let (from, last_end) = *state;
+            let Some(found) = crate::ustring::find_next(&src, &pat, from, last_end).map_err(LuaError::external)? else {
+                return Ok(LuaMultiValue::new());
+            };
+            *state = (found.end, Some(found.end));
+            found
+                .captures
+                .into_iter()
+                .map(|capture| match capture {
+                    crate::ustring::Capture::Text(text) => lua.create_string(&text).map(LuaValue::String),
+                    crate::ustring::Capture::Position(position) => Ok(LuaValue::Integer(position as i64)),
+                })
+                .collect()
+        })
+    })?;
+    ustring.set("gmatch", gmatch_fn)?;
+
+    // mw.ustring.gcount(s, pattern) - 返回匹配次数，不构造替换字符串
+    // 计数规则与 string.gsub 相同：匹配互不重叠，空匹配后前进一个码点
+    let gcount_fn = lua.create_function(|_, (s, pattern): (LuaString, LuaString)| {
+        let src = utf8_chars("gcount", 1, &s)?;
+        let pat = utf8_chars("gcount", 2, &pattern)?;
+        crate::ustring::count_matches(&src, &pat).map_err(LuaError::external)
+    })?;
+    ustring.set("gcount", gcount_fn)?;
+
+    Ok(ustring)
+}
+
+/// 把 mw.ustring 函数的参数解码为码点序列，不是合法的 UTF-8 时报错
+fn utf8_chars(function: &str, position: usize, s: &LuaString) -> LuaResult<Vec<char>> {
+    match s.to_str() {
+        Ok(text) => Ok(text.chars().collect()),
+        Err(_) => Err(LuaError::external(format!(
+            "bad argument #{} to 'mw.ustring.{}' (string is not UTF-8)",
+            position, function
+        ))),
+    }
+}
+
+/// mw.text.jsonEncode 的标志：缩进输出（与 Scribunto 的取值相同）
+const JSON_PRETTY: i64 = 4;
+/// mw.text.jsonDecode 的标志：遇到重复的键时报错（默认保留最后一个）
+const JSON_DUP_ERROR: i64 = 8;
+
+/// mw.text：目前提供 JSON 编解码
+fn create_text_table(lua: &Lua) -> LuaResult<LuaTable> {
+    let text = lua.create_table()?;
+    text.set("JSON_PRETTY", JSON_PRETTY)?;
+    text.set("JSON_DUP_ERROR", JSON_DUP_ERROR)?;
+
+    // mw.text.jsonDecode(s, flags) - JSON 中的 null 解码为 nil
+    let json_decode_fn = lua.create_function(|lua, (json, flags): (String, Option<i64>)| {
+        let value = if flags.unwrap_or(0) & JSON_DUP_ERROR != 0 {
+            serde_json::from_str::<UniqueKeysValue>(&json).map(|v| v.0)
+        } else {
+            serde_json::from_str::<serde_json::Value>(&json)
+        }
+        .map_err(|e| LuaError::external(format!("mw.text.jsonDecode: {}", e)))?;
+        let options = LuaSerializeOptions::new().serialize_none_to_null(false).serialize_unit_to_null(false);
+        lua.to_value_with(&value, options)
+    })?;
+    text.set("jsonDecode", json_decode_fn)?;
+
+    // mw.text.jsonEncode(value, flags)
+    let json_encode_fn = lua.create_function(|lua, (value, flags): (LuaValue, Option<i64>)| {
+        let value = crate::lua_to_json_value(lua, &value)?;
+        let encoded = if flags.unwrap_or(0) & JSON_PRETTY != 0 {
+            serde_json::to_string_pretty(&value)
+        } else {
+            serde_json::to_string(&value)
+        };
+        encoded.map_err(|e| LuaError::external(format!("mw.text.jsonEncode: {}", e)))
+    })?;
+    text.set("jsonEncode", json_encode_fn)?;
+
+    Ok(text)
+}
+
+/// 解析时拒绝重复键的 JSON 值（serde_json::Value 对重复键保留最后一个）
+struct UniqueKeysValue(serde_json::Value);
+
+impl<'de> serde::Deserialize<'de> for UniqueKeysValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UniqueKeysVisitor).map(UniqueKeysValue)
+    }
+}
+
+struct UniqueKeysVisitor;
+
+impl<'de> serde::de::Visitor<'de> for UniqueKeysVisitor {
+    type Value = serde_json::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(serde_json::Value::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(serde_json::Value::Null)
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut items = Vec::new();
+        while let Some(UniqueKeysValue(item)) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(serde_json::Value::Array(items))
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut object = serde_json::Map::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if object.contains_key(&key) {
+                return Err(serde::de::Error::custom(format!("duplicate key '{}'", key)));
+            }
+            let UniqueKeysValue(value) = map.next_value()?;
+            object.insert(key, value);
+        }
+        Ok(serde_json::Value::Object(object))
+    }
+}
+
+const MONTH_NAMES: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+
+/// 创建语言对象 {code = "en", formatDate = ...}，用法: lang:formatDate(format, timestamp)
+fn create_language_object(lua: &Lua) -> LuaResult<LuaTable> {
+    let lang = lua.create_table()?;
+    lang.set("code", "en")?;
+    let format_date_fn = lua.create_function(|lua, (_this, format, timestamp): (LuaValue, String, Option<LuaValue>)| {
+        let unix = parse_timestamp(lua, timestamp)?;
+        // 由 os.date("!*t") 给出 UTC 的日期分量
+        let os_date: LuaFunction = lua.globals().get::<LuaTable>("os")?.get("date")?;
+        let fields: LuaTable = os_date.call(("!*t", unix))?;
+        let date = DateFields {
+            unix,
+            year: fields.get("year")?,
+            month: fields.get("month")?,
+            day: fields.get("day")?,
+            hour: fields.get("hour")?,
+            minute: fields.get("min")?,
+            second: fields.get("sec")?,
+            weekday: fields.get::<i64>("wday")? - 1,
+            yearday: fields.get::<i64>("yday")? - 1,
+        };
+        Ok(format_mediawiki_date(&format, &date))
+    })?;
+    lang.set("formatDate", format_date_fn)?;
+    Ok(lang)
+}
+
+/// 解析 formatDate 的时间参数：
+/// - nil 或 "now"：当前时间（os.time()）
+/// - 数字：Unix 时间戳（秒）
+/// - 字符串 "YYYYMMDDHHMMSS"（MediaWiki 内部格式）或 "@<unix>"
+fn parse_timestamp(lua: &Lua, timestamp: Option<LuaValue>) -> LuaResult<i64> {
+    let now = || -> LuaResult<i64> { lua.globals().get::<LuaTable>("os")?.get::<LuaFunction>("time")?.call(()) };
+    let text = match timestamp {
+        None | Some(LuaValue::Nil) => return now(),
+        Some(LuaValue::Integer(i)) => return Ok(i),
+        Some(LuaValue::Number(n)) => return Ok(n.floor() as i64),
+        Some(LuaValue::String(s)) => s.to_str()?.trim().to_string(),
+        Some(other) => {
+            return Err(LuaError::external(format!("formatDate: invalid timestamp type '{}'", other.type_name())));
+        }
+    };
+    if text.is_empty() || text == "now" {
+        return now();
+    }
+    if let Some(unix) = text.strip_prefix('@') {
+        return unix.parse().map_err(|_| LuaError::external(format!("formatDate: invalid timestamp '{}'", text)));
+    }
+    if text.len() == 14 && text.bytes().all(|b| b.is_ascii_digit()) {
+        let field = |range: std::ops::Range<usize>| text[range].parse::<i64>().unwrap_or(0);
+        let days = days_from_civil(field(0..4), field(4..6), field(6..8));
+        return Ok(days * 86400 + field(8..10) * 3600 + field(10..12) * 60 + field(12..14));
+    }
+    Err(LuaError::external(format!("formatDate: invalid timestamp '{}'", text)))
+}
+
+/// 公历日期距 1970-01-01 的天数（Howard Hinnant 的 days_from_civil 算法）
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+struct DateFields {
+    unix: i64,
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+    /// 0 = 星期日
+    weekday: i64,
+    /// 从 0 开始
+    yearday: i64,
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// 按 MediaWiki 的 {{#time}} 语法格式化日期（英文）
+///
+/// 支持的格式码：Y y L n m M F xg d j D l N w z t H G h g A a i s U c，
+/// `\x` 转义单个字符，`"..."` 输出引号内的原文；`xn` 在英文下没有效果，会被忽略。
+/// 其他 `x` 开头的扩展（如 xr、xh、xj* 等历法/数字系统）不支持，原样输出。
+/// 未识别的字符原样输出。
+fn format_mediawiki_date(format: &str, date: &DateFields) -> String {
+    let month_name = MONTH_NAMES[(date.month - 1).clamp(0, 11) as usize];
+    let weekday_name = WEEKDAY_NAMES[date.weekday.clamp(0, 6) as usize];
+    let hour12 = match date.hour % 12 {
+        0 => 12,
+        h => h,
+    };
+
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            'Y' => out.push_str(&date.year.to_string()),
+            'y' => out.push_str(&format!("{:02}", date.year.rem_euclid(100))),
+            'L' => out.push(if is_leap_year(date.year) { '1' } else { '0' }),
+            'n' => out.push_str(&date.month.to_string()),
+            'm' => out.push_str(&format!("{:02}", date.month)),
+            'M' => out.push_str(&month_name[..3]),
+            'F' => out.push_str(month_name),
+            'd' => out.push_str(&format!("{:02}", date.day)),
+            'j' => out.push_str(&date.day.to_string()),
+            'D' => out.push_str(&weekday_name[..3]),
+            'l' => out.push_str(weekday_name),
+            'N' => out.push_str(&(if date.weekday == 0 { 7 } else { date.weekday }).to_string()),
+            'w' => out.push_str(&date.weekday.to_string()),
+            'z' => out.push_str(&date.yearday.to_string()),
+            't' => out.push_str(&days_in_month(date.year, date.month).to_string()),
+            'H' => out.push_str(&format!("{:02}", date.hour)),
+            'G' => out.push_str(&date.hour.to_string()),
+            'h' => out.push_str(&format!("{:02}", hour12)),
+            'g' => out.push_str(&hour12.to_string()),
+            'A' => out.push_str(if date.hour < 12 { "AM" } else { "PM" }),
+            'a' => out.push_str(if date.hour < 12 { "am" } else { "pm" }),
+            'i' => out.push_str(&format!("{:02}", date.minute)),
+            's' => out.push_str(&format!("{:02}", date.second)),
+            'U' => out.push_str(&date.unix.to_string()),
+            'c' => out.push_str(&format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00",
+                date.year, date.month, date.day, date.hour, date.minute, date.second
+            )),
+            'x' => match chars.peek() {
+                Some('g') => {
+                    chars.next();
+                    out.push_str(month_name);
+                }
+                Some('n') => {
+                    chars.next();
+                }
+                _ => out.push('x'),
+            },
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            }
+            '"' => {
+                for quoted in chars.by_ref() {
+                    if quoted == '"' {
+                        break;
+                    }
+                    out.push(quoted);
+                }
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// 取出本次运行设置的响应头，没有设置时返回 None
+pub(crate) fn take_response_headers(lua: &Lua) -> Option<serde_json::Value> {
+    let headers = lua.remove_app_data::<ResponseHeaders>()?;
+    if headers.0.is_empty() {
+        return None;
+    }
+    let map = headers
+        .0
+        .into_iter()
+        .map(|(name, value)| (name, serde_json::Value::String(value)))
+        .collect();
+    Some(serde_json::Value::Object(map))
+}
+
+/// 取出本次运行中 mw.emitResult 写入的结果，没有调用过时返回 None
+pub(crate) fn take_emitted_result(lua: &Lua) -> Option<String> {
+    lua.remove_app_data::<EmittedResult>().map(|emitted| emitted.0)
+}
+
+/// 整数值输出为 JSON 整数，其余输出为浮点数
+fn metric_number(value: f64) -> serde_json::Value {
+    if value.fract() == 0.0 && value.abs() < crate::JS_SAFE_INTEGER_LIMIT as f64 {
+        serde_json::json!(value as i64)
+    } else {
+        serde_json::json!(value)
+    }
+}
+
+/// 取出本次运行记录的指标 {"counters":{...},"observations":{name:{count,sum,min,max}}}
+/// 没有记录时返回 None
+pub(crate) fn take_metrics(lua: &Lua) -> Option<serde_json::Value> {
+    let metrics = lua.remove_app_data::<Metrics>()?;
+    let counters: serde_json::Map<String, serde_json::Value> = metrics
+        .counters
+        .into_iter()
+        .map(|(name, value)| (name, metric_number(value)))
+        .collect();
+    let observations: serde_json::Map<String, serde_json::Value> = metrics
+        .observations
+        .into_iter()
+        .map(|(name, summary)| {
+            let value = serde_json::json!({
+                "count": summary.count,
+                "sum": metric_number(summary.sum),
+                "min": metric_number(summary.min),
+                "max": metric_number(summary.max),
+            });
+            (name, value)
+        })
+        .collect();
+    Some(serde_json::json!({ "counters": counters, "observations": observations }))
+}
+
+/// 取出本次运行中 mw.log 记录的日志，没有日志时返回 None
+pub(crate) fn take_log(lua: &Lua) -> Option<String> {
+    lua.remove_app_data::<LogBuffer>().map(|log| log.text).filter(|text| !text.is_empty())
+}
+
+/// 丢弃上一次运行留下的单次运行状态（响应头、emitResult、指标、日志），持久上下文在每次运行前调用
+pub(crate) fn clear_run_state(lua: &Lua) {
+    lua.remove_app_data::<ResponseHeaders>();
+    lua.remove_app_data::<EmittedResult>();
+    lua.remove_app_data::<Metrics>();
+    lua.remove_app_data::<LogBuffer>();
+    lua.remove_app_data::<SeededUuidRng>();
+}
+
+/// 调用 mw.setErrorHandler 注册的处理函数，返回替换后的错误（字符串或可序列化的值）
+/// 处理函数在保护模式下调用：它自身出错或返回 nil 时返回 None，由调用方保留原始错误
+pub(crate) fn apply_error_handler(lua: &Lua, error: &LuaError) -> Option<serde_json::Value> {
+    let handler = lua.app_data_ref::<ErrorHandler>()?.0.clone();
+    let error_table = lua.create_table().ok()?;
+    error_table.set("message", error.to_string()).ok()?;
+
+    match handler.call::<LuaValue>(error_table).ok()? {
+        LuaValue::Nil => None,
+        LuaValue::String(s) => Some(serde_json::Value::String(s.to_string_lossy())),
+        other => crate::lua_to_json(lua, &other).ok(),
+    }
+}
@@ -1,26 +1,411 @@
 #[cfg(test)]
 mod tests {
-    use crate::{lua_run, lua_free_result};
+    use crate::{lua_clear_module_cache, lua_describe_api, lua_disable_api, lua_enable_api, lua_free_binary_output, lua_free_chunked_result, lua_result_chunk, lua_result_total_size, lua_run_chunked, lua_free_result, lua_get_binary_output, lua_get_last_result, lua_mount_vfs, lua_new_context, lua_request_cancel, lua_run_in_context, lua_run_isolated, lua_set_context_baseline, lua_free_context, lua_context_count, lua_destroy_all_contexts, lua_snapshot, lua_restore, lua_free_snapshot, lua_set_iowrite_coerce, lua_resolve_module, lua_run, lua_run_batch, lua_run_function, lua_run_json, lua_run_text, lua_run_with_context, lua_run_yaml, lua_seed_uuid, lua_clear_uuid_seed, lua_set_constants, lua_set_auto_display, lua_set_baseline, lua_set_batch_shared_context, lua_set_bigint_mode, lua_set_compat_mode, lua_set_count_instructions, lua_set_custom_searcher, lua_set_empty_print_emits_newline, lua_set_fetch_timeout, lua_set_host_imports, lua_set_import_allowlist, lua_set_infinity_as_string, lua_set_instruction_limit, lua_set_log_limit, lua_set_output_limit, lua_set_rdf_max_iri_len, lua_set_rdf_max_object_bytes,
+        lua_set_max_modules_per_run, lua_set_memory_limit, lua_set_normalize_newlines, lua_set_max_structure_depth, lua_set_print_line_prefix, lua_set_print_line_suffix, lua_set_profile,
+        lua_set_rdf_atomic_replace, lua_set_rdf_boolean_mode, lua_set_rdf_cache_backend, lua_set_rdf_cache_ttl, lua_set_rdf_page_size, lua_set_rdf_prefix, lua_set_rdf_retry, lua_set_rdf_strict_iri, lua_set_report_timings, lua_set_require_alias, lua_set_result_from_output, lua_set_result_header_mode, lua_set_strip_module_wrappers, lua_set_timeout_ms, lua_set_uuid_strategy, lua_tokenize, lua_unmount_vfs};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
     use std::ffi::{CStr, CString, c_char, c_uchar};
 
-    // 模拟 WASM 导入函数（测试时不需要实际实现）
-    #[no_mangle]
-    extern "C" fn fetch_lua_module(_url_ptr: *const c_char, len_out: *mut u32) -> *const c_uchar {
+    // 模拟宿主环境：模块源码、RDF 三元组存储以及调用记录
+    #[derive(Default)]
+    struct MockHost {
+        modules: HashMap<String, String>,
+        fetches: Vec<String>,
+        last_fetch_error: Option<String>,
+        triples: Vec<serde_json::Value>,
+        rdf_error: Option<String>,
+        queries: Vec<serde_json::Value>,
+        user_context: Option<String>,
+        fetch_timeouts: Vec<u32>,
+        fetch_errors: HashMap<String, String>,
+        replaces: usize,
+        // 接下来的多少次 insert 返回可重试错误
+        retryable_failures: usize,
+        insert_attempts: usize,
+        sleeps: Vec<u32>,
+        // 获取模块时回调 lua_run 执行的代码及其结果（模拟宿主重入）
+        reenter_code: Option<String>,
+        reentry_results: Vec<serde_json::Value>,
+        // js_now_ms 返回的时钟，每次读取前进 1 毫秒
+        clock_ms: f64,
+        // js_now_epoch 每次读取前进的秒数（默认 0，时间固定）
+        epoch_step: f64,
+        epoch_offset: f64,
+        // js_resolve_module 的模块表（require 的名称 -> 源码）及调用记录
+        resolver_modules: HashMap<String, String>,
+        resolver_calls: Vec<String>,
+        // 下一次 insert 时通过 lua_request_cancel 取消的上下文（模拟宿主在回调中取消运行）
+        cancel_on_insert: Option<usize>,
+        // 事务调用记录，以及 begin 时保存的三元组快照（rollback 时恢复）
+        transactions: Vec<String>,
+        // 设置后 commit 返回该错误
+        commit_error: Option<String>,
+        snapshot: Option<Vec<serde_json::Value>>,
+        // 宿主键值存储及读写记录（读取的键；写入的键、有效期）
+        cache: HashMap<String, String>,
+        cache_gets: Vec<String>,
+        cache_sets: Vec<(String, u32)>,
+        flushes: usize,
+    }
+
+    thread_local! {
+        static MOCK: RefCell<MockHost> = RefCell::new(MockHost::default());
+    }
+
+    fn with_mock<R>(f: impl FnOnce(&mut MockHost) -> R) -> R {
+        MOCK.with(|m| f(&mut m.borrow_mut()))
+    }
+
+    /// 恢复模拟宿主的初始状态，使用模拟宿主的测试在开始时调用，避免依赖其他测试留下的状态
+    fn reset_mock() {
+        with_mock(|m| *m = MockHost::default());
+    }
+
+    fn leak_bytes(bytes: Vec<u8>, len_out: *mut u32) -> *const c_uchar {
         unsafe {
-            *len_out = 0;
+            *len_out = bytes.len() as u32;
+        }
+        Box::into_raw(bytes.into_boxed_slice()) as *const c_uchar
+    }
+
+    fn arg(ptr: *const c_char) -> String {
+        unsafe { CStr::from_ptr(ptr).to_string_lossy().into_owned() }
+    }
+
+    fn rdf_reply(reply: String) -> *const c_char {
+        CString::new(reply).unwrap().into_raw()
+    }
+
+    fn matches_pattern(triple: &serde_json::Value, pattern: &serde_json::Value) -> bool {
+        ["subject", "predicate", "object"].iter().all(|key| match pattern.get(*key) {
+            None | Some(serde_json::Value::Null) => true,
+            Some(expected) => triple.get(*key) == Some(expected),
+        })
+    }
+
+    // 模拟 WASM 导入函数
+    #[no_mangle]
+    extern "C" fn fetch_lua_module(url_ptr: *const c_char, len_out: *mut u32) -> *const c_uchar {
+        let name = arg(url_ptr);
+        if let Some(code) = with_mock(|m| m.reenter_code.take()) {
+            let result = run(&code);
+            with_mock(|m| m.reentry_results.push(result));
+        }
+        let source = with_mock(|m| {
+            m.fetches.push(name.clone());
+            let source = m.modules.get(&name).cloned();
+            if source.is_none() {
+                let error = m.fetch_errors.get(&name).cloned();
+                m.last_fetch_error = Some(error.unwrap_or_else(|| format!("module '{}' not found", name)));
+            }
+            source
+        });
+        match source {
+            Some(source) => leak_bytes(source.into_bytes(), len_out),
+            None => {
+                unsafe {
+                    *len_out = 0;
+                }
+                std::ptr::null()
+            }
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn free_lua_module(ptr: *const c_uchar, len: u32) {
+        if !ptr.is_null() {
+            unsafe {
+                let slice = std::ptr::slice_from_raw_parts_mut(ptr as *mut u8, len as usize);
+                drop(Box::from_raw(slice));
+            }
         }
-        std::ptr::null()
     }
 
     #[no_mangle]
-    extern "C" fn free_lua_module(_ptr: *const c_uchar, _len: u32) {}
+    extern "C" fn js_set_fetch_timeout(timeout_ms: u32) {
+        with_mock(|m| m.fetch_timeouts.push(timeout_ms));
+    }
+
+    #[no_mangle]
+    extern "C" fn js_now_ms() -> f64 {
+        with_mock(|m| {
+            m.clock_ms += 1.0;
+            m.clock_ms
+        })
+    }
+
+    #[no_mangle]
+    extern "C" fn js_sleep(ms: u32) {
+        with_mock(|m| m.sleeps.push(ms));
+    }
 
     #[no_mangle]
     extern "C" fn get_last_fetch_error(len_out: *mut u32) -> *const c_uchar {
-        unsafe {
-            *len_out = 0;
+        match with_mock(|m| m.last_fetch_error.take()) {
+            Some(message) => leak_bytes(message.into_bytes(), len_out),
+            None => {
+                unsafe {
+                    *len_out = 0;
+                }
+                std::ptr::null()
+            }
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_insert(subject_ptr: *const c_char, predicate_ptr: *const c_char, object_json_ptr: *const c_char) -> *const c_char {
+        let triple = serde_json::json!({
+            "subject": arg(subject_ptr),
+            "predicate": arg(predicate_ptr),
+            "object": serde_json::from_str::<serde_json::Value>(&arg(object_json_ptr)).unwrap(),
+        });
+        if let Some(ctx) = with_mock(|m| m.cancel_on_insert.take()) {
+            lua_request_cancel(ctx as *mut std::ffi::c_void);
+        }
+        rdf_reply(with_mock(|m| {
+            m.insert_attempts += 1;
+            if m.retryable_failures > 0 {
+                m.retryable_failures -= 1;
+                return "ERROR:RETRYABLE:store busy".to_string();
+            }
+            match &m.rdf_error {
+                Some(err) => format!("ERROR:{}", err),
+                None => {
+                    m.triples.push(triple);
+                    "OK".to_string()
+                }
+            }
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_delete(subject_ptr: *const c_char, predicate_ptr: *const c_char, object_json_ptr: *const c_char) -> *const c_char {
+        let pattern = serde_json::json!({
+            "subject": arg(subject_ptr),
+            "predicate": arg(predicate_ptr),
+            "object": serde_json::from_str::<serde_json::Value>(&arg(object_json_ptr)).unwrap(),
+        });
+        rdf_reply(with_mock(|m| match &m.rdf_error {
+            Some(err) => format!("ERROR:{}", err),
+            None => {
+                m.triples.retain(|t| !matches_pattern(t, &pattern));
+                "OK".to_string()
+            }
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_query(pattern_json_ptr: *const c_char) -> *const c_char {
+        let pattern: serde_json::Value = serde_json::from_str(&arg(pattern_json_ptr)).unwrap();
+        rdf_reply(with_mock(|m| {
+            m.queries.push(pattern.clone());
+            match &m.rdf_error {
+                Some(err) => format!("ERROR:{}", err),
+                None => {
+                    let offset = pattern["offset"].as_u64().unwrap_or(0) as usize;
+                    let limit = pattern["limit"].as_u64().map(|l| l as usize).unwrap_or(usize::MAX);
+                    let rows: Vec<_> = m.triples.iter()
+                        .filter(|t| matches_pattern(t, &pattern))
+                        .skip(offset)
+                        .take(limit)
+                        .cloned()
+                        .collect();
+                    serde_json::Value::Array(rows).to_string()
+                }
+            }
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_batch_insert(triples_json_ptr: *const c_char) -> *const c_char {
+        let triples: Vec<serde_json::Value> = serde_json::from_str(&arg(triples_json_ptr)).unwrap();
+        rdf_reply(with_mock(|m| match &m.rdf_error {
+            Some(err) => format!("ERROR:{}", err),
+            None => {
+                m.triples.extend(triples);
+                "OK".to_string()
+            }
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_replace(subject_ptr: *const c_char, predicate_ptr: *const c_char, object_json_ptr: *const c_char) -> *const c_char {
+        let (subject, predicate) = (arg(subject_ptr), arg(predicate_ptr));
+        let object = serde_json::from_str::<serde_json::Value>(&arg(object_json_ptr)).unwrap();
+        rdf_reply(with_mock(|m| match &m.rdf_error {
+            Some(err) => format!("ERROR:{}", err),
+            None => {
+                m.replaces += 1;
+                m.triples.retain(|t| t["subject"] != subject.as_str() || t["predicate"] != predicate.as_str());
+                m.triples.push(serde_json::json!({ "subject": subject, "predicate": predicate, "object": object }));
+                "OK".to_string()
+            }
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_count(pattern_json_ptr: *const c_char) -> *const c_char {
+        let pattern: serde_json::Value = serde_json::from_str(&arg(pattern_json_ptr)).unwrap();
+        rdf_reply(with_mock(|m| match &m.rdf_error {
+            Some(err) => format!("ERROR:{}", err),
+            None => m.triples.iter().filter(|t| matches_pattern(t, &pattern)).count().to_string(),
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_cache_get(key_ptr: *const c_char) -> *const c_char {
+        let key = arg(key_ptr);
+        match with_mock(|m| {
+            m.cache_gets.push(key.clone());
+            m.cache.get(&key).cloned()
+        }) {
+            Some(value) => rdf_reply(value),
+            None => std::ptr::null(),
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn js_cache_set(key_ptr: *const c_char, value_ptr: *const c_char, ttl_secs: u32) {
+        let (key, value) = (arg(key_ptr), arg(value_ptr));
+        with_mock(|m| {
+            m.cache_sets.push((key.clone(), ttl_secs));
+            m.cache.insert(key, value);
+        });
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_increment(subject_ptr: *const c_char, predicate_ptr: *const c_char, delta_json_ptr: *const c_char) -> *const c_char {
+        let (subject, predicate) = (arg(subject_ptr), arg(predicate_ptr));
+        let delta: serde_json::Value = serde_json::from_str(&arg(delta_json_ptr)).unwrap();
+        rdf_reply(with_mock(|m| {
+            let existing = m.triples.iter_mut().find(|t| t["subject"] == subject.as_str() && t["predicate"] == predicate.as_str());
+            let updated = match existing {
+                Some(triple) => {
+                    let Some(current) = triple["object"].as_f64() else {
+                        return "ERROR:cannot increment non-numeric value".to_string();
+                    };
+                    triple["object"] = match (triple["object"].as_i64(), delta.as_i64()) {
+                        (Some(a), Some(b)) => (a + b).into(),
+                        _ => (current + delta.as_f64().unwrap()).into(),
+                    };
+                    triple["object"].clone()
+                }
+                None => {
+                    m.triples.push(serde_json::json!({ "subject": subject, "predicate": predicate, "object": delta }));
+                    delta.clone()
+                }
+            };
+            updated.to_string()
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_flush() -> *const c_char {
+        rdf_reply(with_mock(|m| {
+            m.flushes += 1;
+            match &m.rdf_error {
+                Some(err) => format!("ERROR:{}", err),
+                None => "OK".to_string(),
+            }
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_begin() -> *const c_char {
+        rdf_reply(with_mock(|m| {
+            m.transactions.push("begin".to_string());
+            m.snapshot = Some(m.triples.clone());
+            "OK".to_string()
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_commit() -> *const c_char {
+        rdf_reply(with_mock(|m| {
+            m.transactions.push("commit".to_string());
+            if let Some(err) = &m.commit_error {
+                return format!("ERROR:{}", err);
+            }
+            m.snapshot = None;
+            "OK".to_string()
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_rollback() -> *const c_char {
+        rdf_reply(with_mock(|m| {
+            m.transactions.push("rollback".to_string());
+            if let Some(snapshot) = m.snapshot.take() {
+                m.triples = snapshot;
+            }
+            "OK".to_string()
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_exists(pattern_json_ptr: *const c_char) -> *const c_char {
+        let pattern: serde_json::Value = serde_json::from_str(&arg(pattern_json_ptr)).unwrap();
+        rdf_reply(with_mock(|m| match &m.rdf_error {
+            Some(err) => format!("ERROR:{}", err),
+            None => m.triples.iter().filter(|t| matches_pattern(t, &pattern)).count().to_string(),
+        }))
+    }
+
+    #[no_mangle]
+    extern "C" fn js_rdf_free(ptr: *const c_char) {
+        if !ptr.is_null() {
+            unsafe {
+                drop(CString::from_raw(ptr as *mut c_char));
+            }
         }
-        std::ptr::null()
+    }
+
+    #[no_mangle]
+    extern "C" fn js_get_user_context() -> *const c_char {
+        match with_mock(|m| m.user_context.clone()) {
+            Some(context) => rdf_reply(context),
+            None => std::ptr::null(),
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn js_resolve_module(name_ptr: *const c_char) -> *const c_char {
+        let name = arg(name_ptr);
+        match with_mock(|m| {
+            m.resolver_calls.push(name.clone());
+            m.resolver_modules.get(&name).cloned()
+        }) {
+            Some(source) => rdf_reply(source),
+            None if name.starts_with("broken") => rdf_reply("ERROR:resolver failed".to_string()),
+            None => std::ptr::null(),
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn js_random_bytes(buf: *mut u8, len: u32) -> i32 {
+        let bytes = unsafe { std::slice::from_raw_parts_mut(buf, len as usize) };
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(37).wrapping_add(11);
+        }
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn js_now_epoch() -> f64 {
+        with_mock(|m| {
+            m.epoch_offset += m.epoch_step;
+            1_700_000_000.5 + m.epoch_offset
+        })
+    }
+
+    /// 运行代码并解析返回的 JSON 结果
+    fn run(code: &str) -> serde_json::Value {
+        let code = CString::new(code).unwrap();
+        let result_ptr = lua_run(code.as_ptr());
+        let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+        lua_free_result(result_ptr);
+        serde_json::from_str(&result).unwrap()
     }
 
     #[test]
@@ -34,10 +419,6 @@ return "test complete"
         assert!(!result_ptr.is_null(), "Result pointer should not be null");
 
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         // 验证输出包含 print 的内容
         assert!(result.contains("Hello from Lua!"), 
@@ -48,7 +429,7 @@ return "test complete"
             "Output should contain return value, but got: {}", result);
 
         // 清理
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -61,10 +442,6 @@ return 42
 
         let result_ptr = lua_run(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         assert!(result.contains("Line 1"), "Should contain first print");
         assert!(result.contains("Value:"), "Should contain second print");
@@ -72,7 +449,7 @@ return 42
         assert!(result.contains("true"), "Should contain boolean");
         assert!(result.contains("42"), "Should contain return value");
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -82,12 +459,8 @@ print("Before return")
 return "after"
 "#).unwrap();
 
-        let result_ptr = lua_run(code.as_ptr());
+        let result_ptr = lua_run_text(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         // 应该包含 print 输出
         assert!(result.contains("Before return"), "Should contain print output");
@@ -98,7 +471,7 @@ return "after"
         // 应该包含返回值
         assert!(result.contains("after"), "Should contain return value");
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -109,15 +482,11 @@ print("Only print, no explicit return")
 
         let result_ptr = lua_run(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         assert!(result.contains("Only print, no explicit return"), 
             "Should contain print output");
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -131,17 +500,13 @@ return "done"
 
         let result_ptr = lua_run(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         assert!(result.contains("Iteration"), "Should contain print from loop");
         assert!(result.contains("1"), "Should contain first iteration");
         assert!(result.contains("2"), "Should contain second iteration");
         assert!(result.contains("3"), "Should contain third iteration");
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -155,10 +520,6 @@ return "test complete"
         assert!(!result_ptr.is_null(), "Result pointer should not be null");
 
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         // 验证输出包含 io.write 的内容
         assert!(result.contains("Hello from io.write!"), 
@@ -169,7 +530,7 @@ return "test complete"
             "Output should contain return value, but got: {}", result);
 
         // 清理
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -181,15 +542,11 @@ return "done"
 
         let result_ptr = lua_run(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         assert!(result.contains("Part 1 Part 2 123"), 
             "Should contain concatenated io.write output, but got: {}", result);
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -202,16 +559,12 @@ return "done"
 
         let result_ptr = lua_run(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         // io.write 不会自动添加换行，所以应该连在一起
         assert!(result.contains("Line1Line2"), 
             "io.write should not add newlines, but got: {}", result);
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
     }
 
     #[test]
@@ -225,15 +578,2409 @@ return "done"
 
         let result_ptr = lua_run(code.as_ptr());
         let result = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
-        
-        println!("=== Test Output ===");
-        println!("{}", result);
-        println!("===================");
 
         assert!(result.contains("From print"), "Should contain print output");
         assert!(result.contains("From io.write"), "Should contain io.write output");
         assert!(result.contains("Another print"), "Should contain second print");
 
-        lua_free_last(result_ptr);
+        lua_free_result(result_ptr);
+    }
+
+    #[test]
+    fn test_disable_and_enable_api() {
+        let name = CString::new("State").unwrap();
+        assert_eq!(lua_disable_api(name.as_ptr()), 0);
+        let result = run("return State == nil");
+        assert_eq!(result["result"], true, "State should be removed: {}", result);
+
+        assert_eq!(lua_enable_api(name.as_ptr()), 0);
+        let result = run("return State ~= nil and type(State.insert) == 'function'");
+        assert_eq!(result["result"], true, "State should be back: {}", result);
+
+        let unknown = CString::new("print").unwrap();
+        assert_eq!(lua_disable_api(unknown.as_ptr()), -1);
+    }
+
+    #[test]
+    fn test_disable_api_in_existing_context() {
+        let ctx = lua_new_context();
+        assert!(!ctx.is_null());
+        let run_in = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_in_context(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        let name = CString::new("State").unwrap();
+
+        assert_eq!(run_in("return State ~= nil")["result"], true);
+        assert_eq!(lua_disable_api(name.as_ptr()), 0);
+        let result = run_in("return State == nil");
+        assert_eq!(result["result"], true, "disabling applies to the existing context: {}", result);
+
+        assert_eq!(lua_enable_api(name.as_ptr()), 0);
+        let result = run_in("return type(State.insert)");
+        assert_eq!(result["result"], "function", "enabling restores the removed global: {}", result);
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_rdf_errors_name_offending_triple() {
+        reset_mock();
+        with_mock(|m| m.rdf_error = Some("store is read-only".to_string()));
+        let result = run(r#"
+local ok, err = pcall(State.insert, "ex:Alice", "ex:knows", "ex:Bob")
+return tostring(err)
+"#);
+        let message = result["result"].as_str().unwrap();
+        assert!(message.contains("RDF insert failed for <ex:Alice> <ex:knows>"), "got: {}", message);
+        assert!(message.contains("store is read-only"), "got: {}", message);
+
+        let result = run(r#"
+local ok, err = pcall(State.delete, "ex:Alice", "ex:age")
+return tostring(err)
+"#);
+        let message = result["result"].as_str().unwrap();
+        assert!(message.contains("RDF delete failed for <ex:Alice> <ex:age>"), "got: {}", message);
+
+        with_mock(|m| m.rdf_error = None);
+        let result = run(r#"
+local ok, err = pcall(State.batchInsert, {
+    {subject = "ex:a", predicate = "ex:p", object = 1},
+    {subject = "ex:b", object = 2},
+})
+return tostring(err)
+"#);
+        let message = result["result"].as_str().unwrap();
+        assert!(message.contains("RDF batchInsert failed at index 2"), "got: {}", message);
+    }
+
+    #[test]
+    fn test_auto_display_last_expression() {
+        let code = "local x = 2\nx + 2\n";
+
+        let result = run(code);
+        assert_eq!(result["result"], serde_json::Value::Null, "got: {}", result);
+        assert!(result["error"].is_string(), "bare expression is a syntax error without auto display");
+
+        lua_set_auto_display(1);
+        let result = run(code);
+        assert_eq!(result["result"], 4, "got: {}", result);
+
+        // 最后一条语句不是表达式时回退到原始代码
+        let result = run("local y = 1\ny = y + 1\n");
+        assert_eq!(result["result"], serde_json::Value::Null, "got: {}", result);
+        assert_eq!(result["error"], serde_json::Value::Null, "got: {}", result);
+
+        // 已有 return 的代码保持不变
+        let result = run("return 7");
+        assert_eq!(result["result"], 7, "got: {}", result);
+
+        // 多行循环的最后一行不是独立的表达式，循环照常执行完
+        let result = run("for i = 1, 3 do\n  print(i) end");
+        assert_eq!(result["output"], "1\n2\n3\n", "got: {}", result);
+        assert_eq!(result["result"], serde_json::Value::Null, "got: {}", result);
+
+        // 最后一行是注释时不改写；表达式后的行尾注释不影响改写
+        let result = run("local x = 1\n-- done");
+        assert_eq!(result["result"], serde_json::Value::Null, "got: {}", result);
+        assert_eq!(result["error"], serde_json::Value::Null, "got: {}", result);
+        let result = run("local x = 1\nx + 1 -- two");
+        assert_eq!(result["result"], 2, "got: {}", result);
+        lua_set_auto_display(0);
+    }
+
+    #[test]
+    fn test_state_exists() {
+        let result = run(r#"return State.exists("ex:Alice", "ex:age")"#);
+        assert_eq!(result["result"], false, "got: {}", result);
+
+        let result = run(r#"
+State.insert("ex:Alice", "ex:age", 30)
+return {
+    any = State.exists("ex:Alice", "ex:age"),
+    exact = State.exists("ex:Alice", "ex:age", 30),
+    other = State.exists("ex:Alice", "ex:age", 31),
+}
+"#);
+        assert_eq!(result["result"], serde_json::json!({"any": true, "exact": true, "other": false}), "got: {}", result);
+    }
+
+    #[test]
+    fn test_error_handler_rewrites_message() {
+        let result = run(r#"
+mw.setErrorHandler(function(err)
+    return "translated: " .. err.message:match("boom")
+end)
+error("boom")
+"#);
+        assert_eq!(result["error"], "translated: boom", "got: {}", result);
+
+        // 处理函数自身出错时保留原始错误
+        let result = run(r#"
+mw.setErrorHandler(function(err) error("handler broke") end)
+error("original failure")
+"#);
+        let message = result["error"].as_str().unwrap();
+        assert!(message.contains("original failure"), "got: {}", message);
+    }
+
+    #[test]
+    fn test_bigint_mode() {
+        reset_mock();
+        let code = "return {big = 1 << 60, small = 42, float = 2^60}";
+
+        let result = run(code);
+        assert_eq!(result["result"]["big"], 1i64 << 60, "got: {}", result);
+
+        let mode = CString::new("string").unwrap();
+        assert_eq!(lua_set_bigint_mode(mode.as_ptr()), 0);
+        let result = run(code);
+        assert_eq!(result["result"]["big"], (1i64 << 60).to_string(), "got: {}", result);
+        assert_eq!(result["result"]["small"], 42, "got: {}", result);
+        assert!(result["result"]["float"].is_f64(), "floats are unaffected: {}", result);
+
+        run(r#"State.insert("ex:s", "ex:big", -(1 << 60))"#);
+        let stored = with_mock(|m| m.triples[0]["object"].clone());
+        assert_eq!(stored, (-(1i64 << 60)).to_string());
+
+        let mode = CString::new("error").unwrap();
+        assert_eq!(lua_set_bigint_mode(mode.as_ptr()), 0);
+        let result = run(code);
+        assert!(result["error"].as_str().unwrap().contains("safe integer range"), "got: {}", result);
+
+        let mode = CString::new("number").unwrap();
+        assert_eq!(lua_set_bigint_mode(mode.as_ptr()), 0);
+    }
+
+    #[test]
+    fn test_mount_vfs() {
+        reset_mock();
+        let modules = CString::new(r#"{
+            "greet": "local util = require('util') return { hello = function(n) return util.wrap('hi ' .. n) end }",
+            "util": "return { wrap = function(s) return '[' .. s .. ']' end }"
+        }"#).unwrap();
+        assert_eq!(lua_mount_vfs(modules.as_ptr()), 0);
+
+        let result = run("return require('greet').hello('wiki')");
+        assert_eq!(result["result"], "[hi wiki]", "got: {}", result);
+        assert!(with_mock(|m| m.fetches.is_empty()), "mounted modules must not hit fetch_lua_module");
+
+        lua_unmount_vfs();
+        let result = run("return require('util')");
+        assert!(result["error"].is_string(), "got: {}", result);
+        assert_eq!(with_mock(|m| m.fetches.clone()), vec!["util".to_string()]);
+
+        let invalid = CString::new("[1, 2]").unwrap();
+        assert_eq!(lua_mount_vfs(invalid.as_ptr()), -1);
+    }
+
+    #[test]
+    fn test_result_header_mode() {
+        lua_set_result_header_mode(1);
+        let header = run(r#"
+print(string.rep("x", 100))
+return string.rep("abc", 10000)
+"#);
+        assert_eq!(header["error"], serde_json::Value::Null, "got: {}", header);
+        assert!(header.get("result").is_none(), "header must not carry the payload");
+
+        let payload_ptr = lua_get_last_result();
+        assert!(!payload_ptr.is_null());
+        let payload = unsafe { CStr::from_ptr(payload_ptr).to_string_lossy().into_owned() };
+        lua_free_result(payload_ptr);
+        assert!(lua_get_last_result().is_null(), "the payload can only be fetched once");
+        lua_set_result_header_mode(0);
+
+        let envelope: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(header["payloadSize"], payload.len());
+        assert_eq!(header["resultSize"], envelope["result"].to_string().len());
+        assert_eq!(header["outputSize"], 101);
+        assert_eq!(envelope["result"].as_str().unwrap().len(), 30000);
+    }
+
+    #[test]
+    fn test_query_predicate_pattern() {
+        let result = run(r#"
+State.insert("ex:Alice", "schema:name", "Alice")
+State.insert("ex:Alice", "schema:age", 30)
+State.insert("ex:Alice", "foaf:knows", "ex:Bob")
+local rows = State.query({subject = "ex:Alice", predicatePattern = "^schema:"})
+local predicates = {}
+for i, row in ipairs(rows) do predicates[i] = row.predicate end
+table.sort(predicates)
+return predicates
+"#);
+        assert_eq!(result["result"], serde_json::json!(["schema:age", "schema:name"]), "got: {}", result);
+
+        let result = run(r#"
+local ok, err = pcall(State.query, {predicatePattern = "[schema"})
+return tostring(err)
+"#);
+        let message = result["result"].as_str().unwrap();
+        assert!(message.contains("invalid predicatePattern"), "got: {}", message);
+    }
+
+    #[test]
+    fn test_run_text_mode() {
+        let run_text = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_text(code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            text
+        };
+
+        assert_eq!(run_text("print('a')\nio.write('b')\nreturn 42"), "a\nb\n-- return --\n42");
+        assert_eq!(run_text("return {1, 2}"), "-- return --\n[1,2]");
+        assert_eq!(run_text("print('only')"), "only\n-- return --\nnil");
+
+        let text = run_text("error('bad thing')");
+        assert!(text.starts_with("-- error --\n"), "got: {}", text);
+        assert!(text.contains("bad thing"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_query_iter_pages() {
+        reset_mock();
+        lua_set_rdf_page_size(3);
+        let result = run(r#"
+for i = 1, 5 do
+    State.insert("ex:list", "ex:item", i)
+end
+local seen = {}
+for triple in State.queryIter({subject = "ex:list"}) do
+    seen[#seen + 1] = triple.object
+end
+return seen
+"#);
+        assert_eq!(result["result"], serde_json::json!([1, 2, 3, 4, 5]), "got: {}", result);
+
+        let pages: Vec<(u64, u64)> = with_mock(|m| {
+            m.queries.iter().map(|q| (q["limit"].as_u64().unwrap(), q["offset"].as_u64().unwrap())).collect()
+        });
+        assert_eq!(pages, vec![(3, 0), (3, 3)]);
+        lua_set_rdf_page_size(0);
+    }
+
+    #[test]
+    fn test_rdf_validator() {
+        reset_mock();
+        let result = run(r#"
+State.setValidator(function(subject, predicate, object)
+    if predicate == "ex:forbidden" then
+        return false, "predicate not in schema"
+    end
+    return true
+end)
+State.insert("ex:a", "ex:allowed", 1)
+local ok, err = pcall(State.insert, "ex:a", "ex:forbidden", 2)
+local batch_ok, batch_err = pcall(State.batchInsert, {
+    {subject = "ex:b", predicate = "ex:allowed", object = 3},
+    {subject = "ex:b", predicate = "ex:forbidden", object = 4},
+})
+return {ok = ok, err = tostring(err), batch_err = tostring(batch_err)}
+"#);
+        let value = &result["result"];
+        assert_eq!(value["ok"], false, "got: {}", result);
+        let err = value["err"].as_str().unwrap();
+        assert!(err.contains("rejected by validator for <ex:a> <ex:forbidden>"), "got: {}", err);
+        assert!(err.contains("predicate not in schema"), "got: {}", err);
+        assert!(value["batch_err"].as_str().unwrap().contains("index 2"), "got: {}", result);
+
+        let stored: Vec<String> = with_mock(|m| m.triples.iter().map(|t| t["predicate"].as_str().unwrap().to_string()).collect());
+        assert_eq!(stored, vec!["ex:allowed".to_string()]);
+    }
+
+    #[test]
+    fn test_language_format_date() {
+        let result = run(r#"
+local lang = mw.language.getContentLanguage()
+return {
+    lang:formatDate("Y-m-d", 1700000000),
+    lang:formatDate("Y-m-d H:i:s", "20240229134507"),
+    lang:formatDate("l, j F Y \\a\\t g:i A", "20240229134507"),
+    lang:formatDate('"Day" z "of" Y (L)', "20240229134507"),
+}
+"#);
+        assert_eq!(result["result"], serde_json::json!([
+            "2023-11-14",
+            "2024-02-29 13:45:07",
+            "Thursday, 29 February 2024 at 1:45 PM",
+            "Day 59 of 2024 (1)",
+        ]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_max_modules_per_run() {
+        reset_mock();
+        with_mock(|m| {
+            for i in 1..=10 {
+                let source = if i < 10 {
+                    format!("return 1 + require('chain{}')", i + 1)
+                } else {
+                    "return 1".to_string()
+                };
+                m.modules.insert(format!("chain{}", i), source);
+            }
+        });
+
+        let result = run("return require('chain1')");
+        assert_eq!(result["result"], 10, "got: {}", result);
+
+        lua_set_max_modules_per_run(5);
+        let result = run("return require('chain1')");
+        let message = result["error"].as_str().unwrap();
+        assert!(message.contains("too many modules loaded (limit 5)"), "got: {}", message);
+        assert!(message.contains("chain6"), "got: {}", message);
+
+        // 重复 require 同一个模块不计入限制
+        let result = run("require('chain9') require('chain9') return package.loaded.chain10");
+        assert_eq!(result["result"], 1, "got: {}", result);
+        lua_set_max_modules_per_run(0);
+    }
+
+    #[test]
+    fn test_ustring_gcount() {
+        let result = run(r#"
+local s = "人口 123，面积 45.6 平方公里，海拔 7890"
+local matched = 0
+for _ in mw.ustring.gmatch(s, "%d+") do matched = matched + 1 end
+return {mw.ustring.gcount(s, "%d+"), matched, mw.ustring.gcount("aaaa", "aa"), mw.ustring.gcount(s, "公里")}
+"#);
+        assert_eq!(result["result"], serde_json::json!([4, 4, 2, 1]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_ustring_matches_code_points() {
+        let result = run(r#"
+local chars = {}
+for c in mw.ustring.gmatch("héllo 世界", ".") do chars[#chars + 1] = c end
+local words = {}
+for word, pos in mw.ustring.gmatch("naïve café 東京", "(%a+)()") do words[#words + 1] = word .. "@" .. pos end
+return {
+    chars = chars,
+    words = words,
+    count = mw.ustring.gcount("世界世界", "."),
+    set = mw.ustring.gcount("日本語テキスト", "[日本]"),
+    balanced = mw.ustring.gcount("（a）（b）", "%b（）"),
+}
+"#);
+        let result = &result["result"];
+        assert_eq!(result["chars"], serde_json::json!(["h", "é", "l", "l", "o", " ", "世", "界"]), "got: {}", result);
+        assert_eq!(result["words"], serde_json::json!(["naïve@6", "café@11", "東京@14"]), "got: {}", result);
+        assert_eq!(result["count"], 4, "got: {}", result);
+        assert_eq!(result["set"], 2, "got: {}", result);
+        assert_eq!(result["balanced"], 2, "got: {}", result);
+
+        let result = run(r#"local ok, err = pcall(mw.ustring.gcount, "x", "[a") return tostring(err)"#);
+        assert!(result["result"].as_str().unwrap().contains("missing ']'"), "got: {}", result);
+
+        let result = run(r#"local ok, err = pcall(mw.ustring.gcount, "a", "%0") return tostring(err)"#);
+        assert!(result["result"].as_str().unwrap().contains("invalid capture index %0"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_print_line_prefix_and_suffix() {
+        let prefix = CString::new("[info] ").unwrap();
+        let suffix = CString::new(" <").unwrap();
+        lua_set_print_line_prefix(prefix.as_ptr());
+        lua_set_print_line_suffix(suffix.as_ptr());
+        let result = run(r#"
+print("first")
+print("second\nthird", 3)
+io.write("raw")
+io.write(" text\n")
+"#);
+        assert_eq!(result["output"], "[info] first <\n[info] second <\n[info] third\t3 <\nraw text\n", "got: {}", result);
+
+        let empty = CString::new("").unwrap();
+        lua_set_print_line_prefix(empty.as_ptr());
+        lua_set_print_line_suffix(empty.as_ptr());
+        let result = run("print('plain')");
+        assert_eq!(result["output"], "plain\n");
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let result = run(r#"
+mw.setResponseHeader("Content-Type", "text/plain")
+mw.setResponseHeader("content-type", "application/json")
+mw.setResponseHeader("Cache-Control", "max-age=60")
+return '{"ok":true}'
+"#);
+        assert_eq!(result["headers"], serde_json::json!({
+            "content-type": "application/json",
+            "Cache-Control": "max-age=60",
+        }), "got: {}", result);
+
+        let result = run("return 1");
+        assert!(result.get("headers").is_none(), "got: {}", result);
+
+        let result = run(r#"mw.setResponseHeader("Bad Header", "x")"#);
+        assert!(result["error"].as_str().unwrap().contains("invalid response header name"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_strip_module_wrappers() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("wrapped".to_string(), "\n<nowiki>\nreturn { value = '<nowiki>kept</nowiki>' }\n</NOWIKI>\n\n".to_string());
+        });
+
+        let result = run("return require('wrapped').value");
+        assert!(result["error"].is_string(), "wrapped source should not load as-is: {}", result);
+
+        lua_set_strip_module_wrappers(1);
+        let result = run("return require('wrapped').value");
+        assert_eq!(result["result"], "<nowiki>kept</nowiki>", "got: {}", result);
+        lua_set_strip_module_wrappers(0);
+    }
+
+    #[test]
+    fn test_mw_abort_sets_code() {
+        let result = run(r#"
+local function render()
+    mw.abort("E_CUSTOM", "nope")
+end
+render()
+"#);
+        assert_eq!(result["code"], "E_CUSTOM", "got: {}", result);
+        assert_eq!(result["error"], "nope", "got: {}", result);
+
+        // pcall 仍然可以捕获 abort
+        let result = run(r#"
+local ok = pcall(mw.abort, "E_CUSTOM", "nope")
+return ok
+"#);
+        assert_eq!(result["result"], false, "got: {}", result);
+        assert!(result.get("code").is_none(), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_batch_insert_dedup() {
+        reset_mock();
+        let result = run(r#"
+return State.batchInsert({
+    {subject = "A", predicate = "p", object = {x = 1, y = 2}},
+    {subject = "B", predicate = "p", object = "v"},
+    {subject = "A", predicate = "p", object = {y = 2, x = 1}},
+    {subject = "B", predicate = "p", object = "v"},
+    {subject = "B", predicate = "q", object = "v"},
+}, {dedup = true})
+"#);
+        assert_eq!(result["result"], 2, "got: {}", result);
+        let triples = with_mock(|m| m.triples.clone());
+        let keys: Vec<(String, String)> = triples
+            .iter()
+            .map(|t| (t["subject"].as_str().unwrap().to_string(), t["predicate"].as_str().unwrap().to_string()))
+            .collect();
+        assert_eq!(keys, vec![
+            ("A".to_string(), "p".to_string()),
+            ("B".to_string(), "p".to_string()),
+            ("B".to_string(), "q".to_string()),
+        ]);
+
+        // 未开启 dedup 时原样发送
+        with_mock(|m| m.triples.clear());
+        let result = run(r#"
+return State.batchInsert({
+    {subject = "A", predicate = "p", object = 1},
+    {subject = "A", predicate = "p", object = 1},
+})
+"#);
+        assert_eq!(result["result"], 0, "got: {}", result);
+        assert_eq!(with_mock(|m| m.triples.len()), 2);
+    }
+
+    fn run_function(module: &str, function: &str, args: &str) -> serde_json::Value {
+        let module = CString::new(module).unwrap();
+        let function = CString::new(function).unwrap();
+        let args = CString::new(args).unwrap();
+        let ptr = lua_run_function(module.as_ptr(), function.as_ptr(), args.as_ptr());
+        let text = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        lua_free_result(ptr);
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn test_run_function_calls_module_export() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("mathlib".to_string(), "return { add = function(a, b) return a + b end }".to_string());
+        });
+        let result = run_function("mathlib", "add", "[2,3]");
+        assert_eq!(result["result"], 5, "got: {}", result);
+        assert_eq!(result["error"], serde_json::Value::Null);
+
+        let result = run_function("mathlib", "sub", "[2,3]");
+        let message = result["error"].as_str().unwrap();
+        assert!(message.contains("module 'mathlib' has no function 'sub'"), "got: {}", message);
+    }
+
+    #[test]
+    fn test_empty_print() {
+        let result = run("print()");
+        assert_eq!(result["output"], "\n", "got: {}", result);
+
+        lua_set_empty_print_emits_newline(0);
+        let result = run("print() print('a') print()");
+        lua_set_empty_print_emits_newline(1);
+        assert_eq!(result["output"], "a\n", "got: {}", result);
+    }
+
+    #[test]
+    fn test_constants_are_read_only() {
+        let json = CString::new(r#"{"siteName":"Wiki","limits":{"pages":10}}"#).unwrap();
+        assert_eq!(lua_set_constants(json.as_ptr()), 0);
+        let result = run(r#"
+local ok, err = pcall(function() CONFIG.siteName = "Other" end)
+local nested_ok = pcall(function() CONFIG.limits.pages = 20 end)
+return { name = CONFIG.siteName, pages = CONFIG.limits.pages, ok = ok, err = tostring(err), nested_ok = nested_ok }
+"#);
+        let invalid = CString::new("[1,2]").unwrap();
+        assert_eq!(lua_set_constants(invalid.as_ptr()), -1);
+        assert_eq!(result["result"]["name"], "Wiki", "got: {}", result);
+        assert_eq!(result["result"]["pages"], 10);
+        assert_eq!(result["result"]["ok"], false);
+        assert_eq!(result["result"]["nested_ok"], false);
+        assert!(result["result"]["err"].as_str().unwrap().contains("read-only CONFIG field 'siteName'"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_max_object_bytes() {
+        reset_mock();
+        lua_set_rdf_max_object_bytes(16);
+        let result = run(r#"
+State.insert("A", "small", "ok")
+local insert_ok, insert_err = pcall(State.insert, "A", "big", { text = string.rep("x", 100) })
+local set_ok = pcall(State.set, "A", "big", string.rep("x", 100))
+local batch_ok, batch_err = pcall(State.batchInsert, {
+    {subject = "A", predicate = "p", object = 1},
+    {subject = "A", predicate = "p", object = string.rep("x", 100)},
+})
+return { insert_ok = insert_ok, insert_err = tostring(insert_err), set_ok = set_ok, batch_ok = batch_ok, batch_err = tostring(batch_err) }
+"#);
+        lua_set_rdf_max_object_bytes(0);
+        let result = &result["result"];
+        assert_eq!(result["insert_ok"], false, "got: {}", result);
+        assert!(result["insert_err"].as_str().unwrap().contains("RDF object too large (111 bytes, limit 16)"), "got: {}", result);
+        assert_eq!(result["set_ok"], false);
+        assert_eq!(result["batch_ok"], false);
+        assert!(result["batch_err"].as_str().unwrap().contains("batchInsert (index 2)"), "got: {}", result);
+        assert_eq!(with_mock(|m| m.triples.len()), 1);
+    }
+
+    #[test]
+    fn test_mw_inspect_table() {
+        let result = run(r#"
+return {
+    holes = mw.inspectTable({1, 2, nil, 4}),
+    list = mw.inspectTable({"a", "b"}),
+    mixed = mw.inspectTable({1, name = "x"}),
+}
+"#);
+        let holes = &result["result"]["holes"];
+        assert_eq!(holes["isContiguous"], false, "got: {}", result);
+        assert_eq!(holes["totalKeys"], 3);
+        assert_eq!(holes["arrayLength"], 4);
+        assert_eq!(holes["hasStringKeys"], false);
+        assert_eq!(holes["serializesAsArray"], false, "holes force object form");
+
+        let list = &result["result"]["list"];
+        assert_eq!(list["isContiguous"], true, "got: {}", result);
+        assert_eq!(list["serializesAsArray"], true);
+
+        let mixed = &result["result"]["mixed"];
+        assert_eq!(mixed["hasStringKeys"], true, "got: {}", result);
+        assert_eq!(mixed["isContiguous"], false);
+        assert_eq!(mixed["totalKeys"], 2);
+        assert_eq!(mixed["serializesAsArray"], false, "mixed tables serialize as objects");
+
+        // 与实际序列化结果一致
+        let result = run("local t = {1, 2, x = 1} return mw.inspectTable(t).serializesAsArray, t");
+        assert_eq!(result["result"], serde_json::json!([false, {"1": 1, "2": 2, "x": 1}]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_mw_get_current_user() {
+        reset_mock();
+        with_mock(|m| m.user_context = None);
+        let result = run("local user = mw.getCurrentUser() return { name = user.name, anon = user.isAnon, groups = #user.groups }");
+        assert_eq!(result["result"], serde_json::json!({ "name": "", "anon": true, "groups": 0 }), "got: {}", result);
+
+        with_mock(|m| {
+            m.user_context = Some(r#"{"name":"Alice","groups":["user","sysop"],"isAnon":false}"#.to_string());
+        });
+        let result = run("local user = mw.getCurrentUser() return { name = user.name, anon = user.isAnon, group = user.groups[2] }");
+        with_mock(|m| m.user_context = None);
+        assert_eq!(result["result"], serde_json::json!({ "name": "Alice", "anon": false, "group": "sysop" }), "got: {}", result);
+    }
+
+    #[test]
+    fn test_mw_log_limit() {
+        let result = run("mw.log('a', 1, nil) mw.log(true)");
+        assert_eq!(result["log"], "a\t1\tnil\ntrue\n", "got: {}", result);
+
+        lua_set_log_limit(12);
+        let result = run("for i = 1, 5 do mw.log('line' .. i) end");
+        lua_set_log_limit(0);
+        assert_eq!(result["log"], "line1\nline2\n[log truncated]\n", "got: {}", result);
+
+        let result = run("return 1");
+        assert!(result.get("log").is_none(), "got: {}", result);
+    }
+
+    #[test]
+    fn test_io_write_bytes() {
+        let result = run(r#"io.writeBytes("\255\0ab") io.writeBytes("\x89PNG") io.write("text")"#);
+        assert_eq!(result["output"], "text", "got: {}", result);
+
+        let mut len = 0u32;
+        let ptr = lua_get_binary_output(&mut len);
+        let bytes = unsafe { std::slice::from_raw_parts(ptr, len as usize) }.to_vec();
+        lua_free_binary_output(ptr, len);
+        assert_eq!(bytes, b"\xFF\0ab\x89PNG");
+
+        // 取出后缓冲区清空
+        assert!(lua_get_binary_output(&mut len).is_null());
+        assert_eq!(len, 0);
+    }
+
+    #[test]
+    fn test_compat_mode_51() {
+        let result = run("return unpack == nil");
+        assert_eq!(result["result"], true, "got: {}", result);
+
+        let mode = CString::new("5.1").unwrap();
+        assert_eq!(lua_set_compat_mode(mode.as_ptr()), 0);
+        let result = run(r#"
+local a, b, c = unpack({1, 2, 3})
+return { a, b, c, math.pow(2, 10), math.mod(7, 3), table.maxn({1, nil, 3}), loadstring("return 5")() }
+"#);
+        let invalid = CString::new("5.2").unwrap();
+        assert_eq!(lua_set_compat_mode(invalid.as_ptr()), -1);
+        let mode = CString::new("5.4").unwrap();
+        assert_eq!(lua_set_compat_mode(mode.as_ptr()), 0);
+        assert_eq!(result["result"], serde_json::json!([1, 2, 3, 1024.0, 1, 3, 5]), "got: {}", result);
+    }
+
+    fn tokenize(code: &str) -> serde_json::Value {
+        let code = CString::new(code).unwrap();
+        let ptr = lua_tokenize(code.as_ptr());
+        let text = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        lua_free_result(ptr);
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("local x = 1"), serde_json::json!([
+            { "type": "keyword", "value": "local", "line": 1, "col": 1 },
+            { "type": "name", "value": "x", "line": 1, "col": 7 },
+            { "type": "operator", "value": "=", "line": 1, "col": 9 },
+            { "type": "number", "value": "1", "line": 1, "col": 11 },
+        ]));
+
+        let tokens = tokenize("--[==[ long\ncomment ]==]\ns = 'a\\'b' .. [[x]]\n-- tail\nn = 0x1p4 + 1e-3");
+        let summary: Vec<String> = tokens
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| format!("{}:{}@{}", t["type"].as_str().unwrap(), t["value"].as_str().unwrap(), t["line"]))
+            .collect();
+        assert_eq!(summary, vec![
+            "comment:--[==[ long\ncomment ]==]@1",
+            "name:s@3",
+            "operator:=@3",
+            "string:'a\\'b'@3",
+            "operator:..@3",
+            "string:[[x]]@3",
+            "comment:-- tail@4",
+            "name:n@5",
+            "operator:=@5",
+            "number:0x1p4@5",
+            "operator:+@5",
+            "number:1e-3@5",
+        ]);
+
+        let error = tokenize("x = 'unterminated");
+        assert!(error["error"].as_str().unwrap().contains("unfinished string"), "got: {}", error);
+    }
+
+    #[test]
+    fn test_require_alias() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("mediawiki://wiki/Module:Main".to_string(), "return require('Foo')".to_string());
+            m.modules.insert("mediawiki://wiki/Module:Bar".to_string(), "return 42".to_string());
+        });
+        let (from, to) = (CString::new("Foo").unwrap(), CString::new("Bar").unwrap());
+        assert_eq!(lua_set_require_alias(from.as_ptr(), to.as_ptr()), 0);
+        let result = run("return require('mediawiki://wiki/Module:Main')");
+        let empty = CString::new("").unwrap();
+        assert_eq!(lua_set_require_alias(from.as_ptr(), empty.as_ptr()), 0);
+        assert_eq!(lua_set_require_alias(empty.as_ptr(), to.as_ptr()), -1);
+
+        assert_eq!(result["result"], 42, "got: {}", result);
+        let fetches = with_mock(|m| m.fetches.clone());
+        assert!(fetches.contains(&"mediawiki://wiki/Module:Bar".to_string()), "got: {:?}", fetches);
+        assert!(!fetches.iter().any(|f| f.contains("Foo")), "got: {:?}", fetches);
+    }
+
+    #[test]
+    fn test_count_instructions() {
+        let result = run("return 1");
+        assert!(result.get("instructions").is_none(), "got: {}", result);
+
+        lua_set_count_instructions(1);
+        let small = run("local s = 0 for i = 1, 1000 do s = s + i end return s");
+        let large = run("local s = 0 for i = 1, 100000 do s = s + i end return s");
+        let failed = run("for i = 1, 1000 do end error('boom')");
+        lua_set_count_instructions(0);
+
+        let small = small["instructions"].as_u64().unwrap();
+        let large = large["instructions"].as_u64().unwrap();
+        assert!((1000..100_000).contains(&small), "small loop: {}", small);
+        assert!(large > small * 50, "small: {}, large: {}", small, large);
+        assert!(failed["instructions"].as_u64().unwrap() > 0, "got: {}", failed);
+    }
+
+    #[test]
+    fn test_rdf_validate_iri_and_strict_mode() {
+        reset_mock();
+        let result = run(r#"
+local good = State.validateIri("https://example.org/page#1")
+local bad, reason = State.validateIri("not an iri")
+local spaced = State.validateIri("http://example.org/a b")
+return { good = good, bad = bad, reason = reason, spaced = spaced }
+"#);
+        let result = &result["result"];
+        assert_eq!(result["good"], true, "got: {}", result);
+        assert_eq!(result["bad"], false);
+        assert_eq!(result["reason"], "missing scheme");
+        assert_eq!(result["spaced"], false);
+
+        with_mock(|m| m.triples.clear());
+        lua_set_rdf_strict_iri(1);
+        let result = run(r#"
+State.insert("urn:page:1", "http://schema.org/name", "ok")
+local ok, err = pcall(State.insert, "bad subject", "http://schema.org/name", "x")
+return { ok = ok, err = tostring(err) }
+"#);
+        lua_set_rdf_strict_iri(0);
+        let result = &result["result"];
+        assert_eq!(result["ok"], false, "got: {}", result);
+        assert!(result["err"].as_str().unwrap().contains("subject is not a valid IRI"), "got: {}", result);
+        assert_eq!(with_mock(|m| m.triples.len()), 1);
+    }
+
+    #[test]
+    fn test_fetch_timeout_passed_to_host() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("slow".to_string(), "return 1".to_string());
+        });
+        // 没有设置超时时不调用 js_set_fetch_timeout
+        run("return require('slow')");
+        assert!(with_mock(|m| m.fetch_timeouts.is_empty()));
+        lua_set_fetch_timeout(250);
+        let result = run("return require('slow')");
+        assert_eq!(result["result"], 1, "got: {}", result);
+        assert_eq!(with_mock(|m| m.fetch_timeouts.clone()), vec![250]);
+
+        // 宿主返回 TIMEOUT: 时给出明确的超时错误
+        with_mock(|m| m.fetch_errors.insert("hang".to_string(), "TIMEOUT: no response".to_string()));
+        let result = run("return require('hang')");
+        lua_set_fetch_timeout(0);
+        let message = result["error"].as_str().unwrap();
+        assert!(message.contains("module fetch timed out after 250 ms: no response"), "got: {}", message);
+
+        // 取消超时后告知宿主一次 0，之后不再调用
+        run("return require('slow')");
+        run("return require('slow')");
+        assert_eq!(with_mock(|m| m.fetch_timeouts.clone()), vec![250, 250, 0]);
+    }
+
+    #[test]
+    fn test_mw_emit_result() {
+        let result = run(r#"
+mw.emitResult("<ul>")
+for i = 1, 3 do mw.emitResult("<li>" .. i .. "</li>") end
+mw.emitResult("</ul>")
+"#);
+        assert_eq!(result["result"], "<ul><li>1</li><li>2</li><li>3</li></ul>", "got: {}", result);
+        assert!(result.get("warnings").is_none(), "got: {}", result);
+
+        let result = run("mw.emitResult('emitted') return 'returned'");
+        assert_eq!(result["result"], "emitted", "got: {}", result);
+        assert_eq!(result["warnings"], serde_json::json!(["return value ignored because mw.emitResult was used"]));
+    }
+
+    #[test]
+    fn test_rdf_query_map() {
+        reset_mock();
+        with_mock(|m| {
+            m.triples = vec![
+                serde_json::json!({ "subject": "Alice", "predicate": "age", "object": 30 }),
+                serde_json::json!({ "subject": "Bob", "predicate": "age", "object": 25 }),
+                serde_json::json!({ "subject": "Alice", "predicate": "age", "object": 31 }),
+                serde_json::json!({ "subject": "Bob", "predicate": "name", "object": "Robert" }),
+            ];
+        });
+        let result = run(r#"
+local ages = State.queryMap({ predicate = "age" }, "subject", { valueField = "object" })
+local by_subject = State.queryMap({})
+local collected = State.queryMap({ predicate = "age" }, nil, { valueField = "object", collect = true })
+local ok, err = pcall(State.queryMap, {}, "bogus")
+return {
+    ages = ages,
+    bob_name = by_subject.Bob.object,
+    collected = collected,
+    err = tostring(err),
+}
+"#);
+        with_mock(|m| m.triples.clear());
+        let result = &result["result"];
+        assert_eq!(result["ages"], serde_json::json!({ "Alice": 31, "Bob": 25 }), "got: {}", result);
+        assert_eq!(result["bob_name"], "Robert");
+        assert_eq!(result["collected"], serde_json::json!({ "Alice": [30, 31], "Bob": [25] }));
+        assert!(result["err"].as_str().unwrap().contains("invalid keyField 'bogus'"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_set_atomic_replace() {
+        reset_mock();
+        with_mock(|m| {
+            m.triples = vec![serde_json::json!({ "subject": "A", "predicate": "p", "object": 1 })];
+            m.replaces = 0;
+        });
+        run("State.set('A', 'p', 2)");
+        assert_eq!(with_mock(|m| m.replaces), 0);
+
+        lua_set_rdf_atomic_replace(1);
+        let result = run("State.set('A', 'p', 3) return State.get('A', 'p')");
+        lua_set_rdf_atomic_replace(0);
+        assert_eq!(result["result"], 3, "got: {}", result);
+        assert_eq!(with_mock(|m| m.replaces), 1);
+        assert_eq!(with_mock(|m| m.triples.len()), 1);
+    }
+
+    #[test]
+    fn test_warn_collected() {
+        let result = run(r#"
+warn("careful")
+warn("multi ", "part")
+warn("@off")
+warn("hidden")
+warn("@on")
+warn("shown")
+return 1
+"#);
+        assert_eq!(result["warnings"], serde_json::json!(["careful", "multi part", "shown"]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_mw_clone_deep_structures() {
+        let build = r#"
+local root = {}
+local node = root
+for i = 1, 1000 do
+    node.child = { level = i }
+    node = node.child
+end
+"#;
+        let result = run(&(build.to_string() + r#"
+local copy = mw.clone(root)
+local depth, cursor = 0, copy
+while cursor.child do depth = depth + 1 cursor = cursor.child end
+local cyclic = { name = "c" }
+cyclic.self = cyclic
+local cyclic_copy = mw.clone(cyclic)
+return { depth = depth, distinct = copy.child ~= root.child, cycle = cyclic_copy.self == cyclic_copy and cyclic_copy ~= cyclic }
+"#));
+        assert_eq!(result["result"], serde_json::json!({ "depth": 1000, "distinct": true, "cycle": true }), "got: {}", result);
+
+        lua_set_max_structure_depth(100);
+        let result = run(&(build.to_string() + r#"
+local ok, err = pcall(mw.clone, root)
+return { ok = ok, err = tostring(err) }
+"#));
+        lua_set_max_structure_depth(0);
+        assert_eq!(result["result"]["ok"], false, "got: {}", result);
+        assert!(result["result"]["err"].as_str().unwrap().contains("structure too deep (limit 100)"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_query_intern() {
+        reset_mock();
+        let predicate = format!("http://example.org/{}", "p".repeat(200));
+        with_mock(|m| {
+            m.triples = (0..2000)
+                .map(|i| serde_json::json!({ "subject": format!("s{}", i % 10), "predicate": predicate, "object": i }))
+                .collect();
+        });
+        let result = run(r#"
+local function measure(intern)
+    collectgarbage("collect")
+    local before = collectgarbage("count")
+    local rows = State.query({ intern = intern })
+    collectgarbage("collect")
+    return rows, collectgarbage("count") - before
+end
+local plain, plain_kb = measure(false)
+local plain_first = plain[1]
+plain = nil
+local interned, interned_kb = measure(true)
+return {
+    count = #interned,
+    same = interned[1].predicate == plain_first.predicate and interned[2000].object == 1999,
+    subject = interned[11].subject,
+    smaller = interned_kb * 2 < plain_kb,
+}
+"#);
+        with_mock(|m| m.triples.clear());
+        assert_eq!(result["result"], serde_json::json!({ "count": 2000, "same": true, "subject": "s0", "smaller": true }), "got: {}", result);
+    }
+
+    #[test]
+    fn test_mw_uuid_strategies() {
+        let result = run("return mw.uuid()");
+        let uuid = result["result"].as_str().unwrap().to_string();
+        assert_eq!(uuid.len(), 36, "got: {}", uuid);
+        assert_eq!(&uuid[14..15], "4", "got: {}", uuid);
+
+        let strategy = CString::new("time").unwrap();
+        assert_eq!(lua_set_uuid_strategy(strategy.as_ptr()), 0);
+        let result = run("return { mw.uuid(), mw.uuid() }");
+        let strategy = CString::new("random").unwrap();
+        assert_eq!(lua_set_uuid_strategy(strategy.as_ptr()), 0);
+        let invalid = CString::new("v1").unwrap();
+        assert_eq!(lua_set_uuid_strategy(invalid.as_ptr()), -1);
+
+        let first = result["result"][0].as_str().unwrap();
+        let second = result["result"][1].as_str().unwrap();
+        assert_ne!(first, second);
+        assert!(first < second, "{} should sort before {}", first, second);
+        assert_eq!(&first[14..15], "7", "got: {}", first);
+        // 时间戳部分为 1700000000500 毫秒
+        assert_eq!(&first[..13], "018bcfe5-69f4", "got: {}", first);
+    }
+
+    fn resolve_module(base: &str, name: &str) -> serde_json::Value {
+        let (base, name) = (CString::new(base).unwrap(), CString::new(name).unwrap());
+        let ptr = lua_resolve_module(base.as_ptr(), name.as_ptr());
+        let text = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string();
+        lua_free_result(ptr);
+        serde_json::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_module_preview() {
+        reset_mock();
+        let (from, to) = (CString::new("Legacy").unwrap(), CString::new("Module:Current").unwrap());
+        assert_eq!(lua_set_require_alias(from.as_ptr(), to.as_ptr()), 0);
+        let aliased = resolve_module("mediawiki://wiki/Module:Main", "Legacy");
+        let plain = resolve_module("mediawiki://wiki/Module:Main", "Other");
+        let top_level = resolve_module("", "Legacy");
+        let empty = CString::new("").unwrap();
+        lua_set_require_alias(from.as_ptr(), empty.as_ptr());
+
+        assert_eq!(aliased["resolved"], "mediawiki://wiki/Module:Current");
+        assert_eq!(plain["resolved"], "mediawiki://wiki/Module:Other");
+        assert_eq!(top_level["resolved"], "Module:Current");
+        assert!(with_mock(|m| m.fetches.is_empty()));
+    }
+
+    #[test]
+    fn test_run_chunked_reassembles_result() {
+        let code = r#"
+local rows = {}
+for i = 1, 5000 do rows[i] = { id = i, name = "行" .. i } end
+print("done")
+return rows
+"#;
+        let code_c = CString::new(code).unwrap();
+        let ptr = lua_run(code_c.as_ptr());
+        let expected = unsafe { CStr::from_ptr(ptr) }.to_bytes().to_vec();
+        lua_free_result(ptr);
+
+        let handle = lua_run_chunked(code_c.as_ptr());
+        let total = lua_result_total_size(handle) as usize;
+        assert_eq!(total, expected.len().div_ceil(3) * 4);
+
+        let mut assembled = Vec::new();
+        let mut index = 0;
+        loop {
+            let mut len = 0u32;
+            // 4099 向下取整为 4096，每块都可以单独解码
+            let chunk = lua_result_chunk(handle, index, 4099, &mut len);
+            if chunk.is_null() {
+                break;
+            }
+            assert!(len <= 4096 && len.is_multiple_of(4));
+            assembled.extend(decode_base64(unsafe { std::slice::from_raw_parts(chunk, len as usize) }));
+            index += 1;
+        }
+        let mut len = 0u32;
+        assert!(lua_result_chunk(handle, 0, 3, &mut len).is_null());
+        lua_free_chunked_result(handle);
+
+        assert_eq!(index as usize, total.div_ceil(4096));
+        assert_eq!(assembled, expected);
+        assert_eq!(lua_result_total_size(handle), 0);
+    }
+
+    fn decode_base64(text: &[u8]) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut bytes = Vec::new();
+        for group in text.chunks(4) {
+            let digits: Vec<u32> = group.iter().take_while(|&&c| c != b'=').map(|c| ALPHABET.iter().position(|a| a == c).unwrap() as u32).collect();
+            let n = digits.iter().enumerate().fold(0, |n, (i, d)| n | d << (18 - 6 * i));
+            bytes.extend(n.to_be_bytes()[1..digits.len()].iter());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_io_write_coerce_policy() {
+        let result = run("io.write('a', true)");
+        let message = result["error"].as_str().unwrap();
+        assert!(message.contains("bad argument #2 to 'write' (string expected, got boolean)"), "got: {}", message);
+
+        lua_set_iowrite_coerce(1);
+        let result = run("io.write('a', true, ' ', nil, 1)");
+        lua_set_iowrite_coerce(0);
+        assert_eq!(result["output"], "atrue nil1", "got: {}", result);
+    }
+
+    #[test]
+    fn test_mw_metrics() {
+        let result = run(r#"
+mw.incrementMetric("infobox.fields")
+mw.incrementMetric("infobox.fields", 4)
+mw.observeMetric("render.ms", 12)
+mw.observeMetric("render.ms", 3.5)
+return true
+"#);
+        assert_eq!(result["metrics"], serde_json::json!({
+            "counters": { "infobox.fields": 5 },
+            "observations": { "render.ms": { "count": 2, "sum": 15.5, "min": 3.5, "max": 12 } },
+        }), "got: {}", result);
+
+        let result = run("for i = 1, 101 do mw.incrementMetric('m' .. i) end");
+        assert!(result["error"].as_str().unwrap().contains("too many metrics (limit 100)"), "got: {}", result);
+        assert!(run("return 1").get("metrics").is_none());
+    }
+
+    #[test]
+    fn test_mw_text_json_decode_duplicate_keys() {
+        let result = run(r#"
+local json = '{"a":1,"nested":{"b":true,"c":null},"a":2}'
+local last = mw.text.jsonDecode(json)
+local ok, err = pcall(mw.text.jsonDecode, json, mw.text.JSON_DUP_ERROR)
+local nested_ok = pcall(mw.text.jsonDecode, '[{"x":1,"x":1}]', mw.text.JSON_DUP_ERROR)
+return { a = last.a, c_is_nil = last.nested.c == nil, ok = ok, err = tostring(err), nested_ok = nested_ok,
+    encoded = mw.text.jsonEncode({ k = { 1, 2 } }) }
+"#);
+        let result = &result["result"];
+        assert_eq!(result["a"], 2, "got: {}", result);
+        assert_eq!(result["c_is_nil"], true);
+        assert_eq!(result["ok"], false);
+        assert!(result["err"].as_str().unwrap().contains("duplicate key 'a'"), "got: {}", result);
+        assert_eq!(result["nested_ok"], false);
+        assert_eq!(result["encoded"], r#"{"k":[1,2]}"#);
+    }
+
+    #[test]
+    fn test_baseline_shared_by_runs() {
+        let baseline = CString::new("print('setup') function helper(x) return 'helped ' .. x end").unwrap();
+        assert_eq!(lua_set_baseline(baseline.as_ptr()), 0);
+        let first = run("leaked = true return helper('one')");
+        let second = run("return { helper('two'), leaked == nil }");
+        let invalid = CString::new("function (").unwrap();
+        assert_eq!(lua_set_baseline(invalid.as_ptr()), -1);
+        let empty = CString::new("").unwrap();
+        assert_eq!(lua_set_baseline(empty.as_ptr()), 0);
+
+        assert_eq!(first["result"], "helped one", "got: {}", first);
+        assert_eq!(first["output"], "");
+        assert_eq!(second["result"], serde_json::json!(["helped two", true]), "got: {}", second);
+        assert_eq!(run("return helper == nil")["result"], true);
+    }
+
+    #[test]
+    fn test_rdf_stats_in_envelope() {
+        let result = run(r#"
+State.insert("ex:stats", "ex:a", 1)
+State.insert("ex:stats", "ex:b", 2)
+State.insert("ex:stats", "ex:c", 3)
+State.delete("ex:stats", "ex:c")
+return #State.query({subject = "ex:stats"})
+"#);
+        assert_eq!(result["result"], 2, "got: {}", result);
+        assert_eq!(
+            result["rdfStats"],
+            serde_json::json!({"inserted": 3, "deleted": 1, "queried": 1, "queryRows": 2, "cacheHits": 0})
+        );
+
+        // 统计按次运行重置，未使用 State 时不输出
+        let result = run("return 1");
+        assert!(result.get("rdfStats").is_none(), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_retry() {
+        reset_mock();
+        // 未设置重试时，可重试错误也直接失败
+        with_mock(|m| m.retryable_failures = 1);
+        let result = run(r#"local ok, err = pcall(State.insert, "ex:retry", "ex:p", 1) return tostring(err)"#);
+        let message = result["result"].as_str().unwrap();
+        assert!(message.contains("store busy") && !message.contains("RETRYABLE"), "got: {}", message);
+
+        lua_set_rdf_retry(3, 10);
+        with_mock(|m| {
+            m.retryable_failures = 2;
+            m.insert_attempts = 0;
+        });
+        let result = run(r#"State.insert("ex:retry", "ex:p", 1) return State.get("ex:retry", "ex:p")"#);
+        assert_eq!(result["result"], 1, "got: {}", result);
+        with_mock(|m| {
+            assert_eq!(m.insert_attempts, 3);
+            assert_eq!(m.sleeps, vec![10, 20]);
+        });
+
+        // 不可重试的错误立即失败
+        with_mock(|m| {
+            m.rdf_error = Some("permission denied".to_string());
+            m.insert_attempts = 0;
+        });
+        let result = run(r#"local ok, err = pcall(State.insert, "ex:retry", "ex:p", 2) return tostring(err)"#);
+        assert!(result["result"].as_str().unwrap().contains("permission denied"), "got: {}", result);
+        with_mock(|m| {
+            assert_eq!(m.insert_attempts, 1);
+            m.rdf_error = None;
+        });
+        lua_set_rdf_retry(0, 0);
+    }
+
+    #[test]
+    fn test_to_json_ld() {
+        let schema = CString::new("schema").unwrap();
+        let iri = CString::new("https://schema.org/").unwrap();
+        assert_eq!(lua_set_rdf_prefix(schema.as_ptr(), iri.as_ptr()), 0);
+        let result = run(r#"
+State.insert("ex:Alice", "schema:name", "Alice")
+State.insert("ex:Alice", "schema:knows", "ex:Bob")
+State.insert("ex:Alice", "schema:knows", "ex:Carol")
+State.insert("ex:Bob", "schema:name", "Bob")
+State.insert("ex:Bob", "schema:address", {city = "Paris"})
+return State.toJsonLd()
+"#);
+        assert_eq!(
+            result["result"],
+            serde_json::json!({
+                "@context": {"schema": "https://schema.org/"},
+                "@graph": [
+                    {"@id": "ex:Alice", "schema:name": "Alice", "schema:knows": ["ex:Bob", "ex:Carol"]},
+                    {"@id": "ex:Bob", "schema:name": "Bob", "schema:address": {"@value": {"city": "Paris"}, "@type": "@json"}},
+                ],
+            }),
+            "got: {}",
+            result
+        );
+
+        let empty = CString::new("").unwrap();
+        assert_eq!(lua_set_rdf_prefix(schema.as_ptr(), empty.as_ptr()), 0);
+        let result = run(r#"return State.toJsonLd({subject = "ex:Bob", predicate = "schema:name"})"#);
+        assert_eq!(result["result"], serde_json::json!({"@graph": [{"@id": "ex:Bob", "schema:name": "Bob"}]}));
+    }
+
+    #[test]
+    fn test_result_from_output() {
+        let code = "print('line one')\nio.write('line two')\nreturn {answer = 42}";
+        lua_set_result_from_output(1);
+        let result = run(code);
+        lua_set_result_from_output(0);
+        assert_eq!(result["result"], "line one\nline two", "got: {}", result);
+        assert_eq!(result["output"], "line one\nline two");
+
+        let result = run(code);
+        assert_eq!(result["result"], serde_json::json!({"answer": 42}));
+    }
+
+    #[test]
+    fn test_persistent_context() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("counter".to_string(), "return { hits = 0 }".to_string());
+        });
+        let ctx = lua_new_context();
+        assert!(!ctx.is_null());
+        let run_in = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_in_context(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+
+        let result = run_in("total = 1\nlocal c = require('counter')\nc.hits = c.hits + 1\nprint('first')");
+        assert_eq!(result["output"], "first\n", "got: {}", result);
+
+        // 全局变量和已加载的模块保留，输出在每次运行前清空
+        let result = run_in("total = total + 1\nlocal c = require('counter')\nc.hits = c.hits + 1\nreturn {total, c.hits}");
+        assert_eq!(result["result"], serde_json::json!([2, 2]), "got: {}", result);
+        assert_eq!(result["output"], "");
+        assert_eq!(with_mock(|m| m.fetches.clone()), vec!["counter".to_string()]);
+
+        // 单次运行的状态（例如 mw.log）不会带到下一次运行
+        let result = run_in("mw.log('once') error('boom')");
+        assert!(result["error"].as_str().unwrap().contains("boom"), "got: {}", result);
+        let result = run_in("return 1");
+        assert!(result.get("log").is_none(), "got: {}", result);
+
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_snapshot_restore_context() {
+        let ctx = lua_new_context();
+        assert!(!ctx.is_null());
+        let run_in = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_in_context(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+
+        run_in("answer = 42 config = { mode = 'a' } config.self = config function get_mode() return config.mode end");
+        let snapshot = lua_snapshot(ctx);
+        assert_ne!(snapshot, 0);
+
+        let result = run_in("answer = 0 config.mode = 'b' config.extra = true added = {} string.shout = string.upper return get_mode()");
+        assert_eq!(result["result"], "b", "got: {}", result);
+
+        // 全局变量和嵌套表都回到快照时的状态，循环引用和闭包引用的表保持同一个表
+        for _ in 0..2 {
+            assert_eq!(lua_restore(ctx, snapshot), 0);
+            let result = run_in("local r = {answer, config.mode, config.extra == nil, added == nil, config.self == config, get_mode(), string.shout == nil} answer = -1 return r");
+            assert_eq!(result["result"], serde_json::json!([42, "a", true, true, true, "a", true]), "got: {}", result);
+        }
+
+        assert_eq!(lua_restore(ctx, snapshot + 1), -1);
+        assert_eq!(lua_free_snapshot(ctx, snapshot), 0);
+        assert_eq!(lua_restore(ctx, snapshot), -1);
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_run_isolated_reloads_modules() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("loadcount".to_string(), "load_count = (load_count or 0) + 1 return { loads = load_count }".to_string());
+        });
+        let ctx = lua_new_context();
+        assert!(!ctx.is_null());
+        let run_with = |run: extern "C" fn(*mut std::ffi::c_void, *const c_char) -> *const c_char, code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = run(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+
+        // 每次隔离运行都重新加载模块，同一次运行中的 require 仍然使用 package.loaded
+        for expected in 1..=2 {
+            let result = run_with(lua_run_isolated, "local a, b = require('loadcount'), require('loadcount') return {a.loads, a == b}");
+            assert_eq!(result["result"], serde_json::json!([expected, true]), "got: {}", result);
+        }
+        let result = run_with(lua_run_in_context, "return package.loaded.loadcount == nil");
+        assert_eq!(result["result"], true, "got: {}", result);
+
+        // 出错的隔离运行同样恢复 package.loaded；普通运行加载的模块在隔离运行中可以继续使用
+        let result = run_with(lua_run_isolated, "require('loadcount') error('boom')");
+        assert!(result["error"].as_str().unwrap().contains("boom"), "got: {}", result);
+        run_with(lua_run_in_context, "require('loadcount')");
+        let result = run_with(lua_run_isolated, "return require('loadcount').loads");
+        assert_eq!(result["result"], 4, "got: {}", result);
+        assert_eq!(with_mock(|m| m.fetches.clone()), vec!["loadcount".to_string()], "module source stays cached");
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_context_baseline_for_isolated_runs() {
+        let ctx = lua_new_context();
+        assert!(!ctx.is_null());
+        let run_isolated = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_isolated(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        let baseline = CString::new("setup_runs = (setup_runs or 0) + 1 print('setup') function helper(x) return 'helped ' .. x end").unwrap();
+        assert_eq!(lua_set_context_baseline(ctx, baseline.as_ptr()), 0);
+        let invalid = CString::new("error('bad setup')").unwrap();
+        assert_eq!(lua_set_context_baseline(ctx, invalid.as_ptr()), -1);
+
+        let first = run_isolated("leaked = true helper = nil return setup_runs");
+        let second = run_isolated("return { helper('two'), leaked == nil, setup_runs }");
+        assert_eq!(first["result"], 1, "got: {}", first);
+        assert_eq!(first["output"], "");
+        assert_eq!(second["result"], serde_json::json!(["helped two", true, 1]), "got: {}", second);
+
+        let empty = CString::new("").unwrap();
+        assert_eq!(lua_set_context_baseline(ctx, empty.as_ptr()), 0);
+        run_isolated("leaked = true");
+        assert_eq!(run_isolated("return leaked")["result"], true);
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_destroy_all_contexts() {
+        let contexts: Vec<_> = (0..3).map(|_| lua_new_context()).collect();
+        assert!(contexts.iter().all(|ctx| !ctx.is_null()));
+        assert_eq!(lua_context_count(), 3);
+        lua_free_context(contexts[0]);
+        lua_free_context(contexts[0]);
+        assert_eq!(lua_context_count(), 2, "freeing twice is ignored");
+
+        lua_destroy_all_contexts();
+        assert_eq!(lua_context_count(), 0);
+        lua_destroy_all_contexts();
+        let ctx = lua_new_context();
+        assert_eq!(lua_context_count(), 1);
+        lua_free_context(ctx);
+        assert_eq!(lua_context_count(), 0);
+    }
+
+    #[test]
+    fn test_host_reentry_during_fetch() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("reentrant".to_string(), "return 1".to_string());
+            m.reenter_code = Some("return require('other')".to_string());
+        });
+        let result = run("local ok, err = pcall(require, 'reentrant') return tostring(err)");
+        let message = result["result"].as_str().unwrap();
+        assert!(message.contains("host re-entered during module fetch"), "got: {}", message);
+
+        let reentry = with_mock(|m| m.reentry_results.remove(0));
+        assert_eq!(reentry["error"], "host re-entered during module fetch");
+
+        // 没有重入时正常加载
+        let result = run("return require('reentrant')");
+        assert_eq!(result["result"], 1, "got: {}", result);
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        lua_set_instruction_limit(10_000);
+        let result = run("while true do end");
+        assert_eq!(result, serde_json::json!({"result": null, "error": "instruction limit exceeded"}));
+
+        // pcall 不能吞掉超限错误
+        let result = run("while true do pcall(function() while true do end end) end");
+        assert_eq!(result["error"], "instruction limit exceeded");
+
+        let result = run("local n = 0 for i = 1, 100 do n = n + i end return n");
+        assert_eq!(result["result"], 5050, "got: {}", result);
+        assert!(result.get("instructions").is_none(), "got: {}", result);
+
+        // 限制不会残留到之后的运行
+        lua_set_instruction_limit(0);
+        let result = run("local n = 0 for i = 1, 100000 do n = n + 1 end return n");
+        assert_eq!(result["result"], 100000, "got: {}", result);
+    }
+
+    #[test]
+    fn test_execution_timeout() {
+        lua_set_timeout_ms(50);
+        let result = run("while true do end");
+        assert_eq!(result, serde_json::json!({"result": null, "error": "execution timed out after 50 ms"}));
+
+        let result = run("return 1 + 1");
+        assert_eq!(result["result"], 2, "got: {}", result);
+        lua_set_timeout_ms(0);
+    }
+
+    #[test]
+    fn test_rdf_max_iri_len() {
+        lua_set_rdf_max_iri_len(16);
+        let result = run(r#"
+local ok, err = pcall(State.insert, "ex:" .. string.rep("x", 20), "ex:p", 1)
+local query_ok, query_err = pcall(State.query, {predicate = "ex:" .. string.rep("p", 20)})
+State.insert("ex:short", "ex:p", 1)
+return {tostring(err), tostring(query_err), State.get("ex:short", "ex:p")}
+"#);
+        lua_set_rdf_max_iri_len(0);
+        let insert_error = result["result"][0].as_str().unwrap();
+        assert!(insert_error.contains("RDF insert failed: IRI too long (subject is 23 bytes, limit 16)"), "got: {}", insert_error);
+        let query_error = result["result"][1].as_str().unwrap();
+        assert!(query_error.contains("IRI too long (predicate is 23 bytes"), "got: {}", query_error);
+        assert_eq!(result["result"][2], 1);
+    }
+
+    #[test]
+    fn test_os_clock_uses_host_clock() {
+        reset_mock();
+        with_mock(|m| m.epoch_step = 0.25);
+        // 不计时的运行不读取宿主时钟
+        run("return 1");
+        assert_eq!(with_mock(|m| m.epoch_offset), 0.0);
+        let result = run(r#"
+local first = os.clock()
+local second = os.clock()
+return {first, second, mw.elapsedTime()}
+"#);
+        with_mock(|m| m.epoch_step = 0.0);
+        assert_eq!(result["result"], serde_json::json!([0.0, 0.25, 0.5]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        lua_set_memory_limit(4 * 1024 * 1024);
+        let result = run("local t = {} for i = 1, 1e8 do t[i] = string.rep('x', 64) .. i end return #t");
+        let small = run("local t = {} for i = 1, 100 do t[i] = i end return #t");
+        lua_set_memory_limit(0);
+        assert_eq!(result, serde_json::json!({"result": null, "error": "memory limit exceeded (4194304 bytes)"}));
+        assert_eq!(small["result"], 100, "got: {}", small);
+
+        // 不限制内存时分配失败的错误不带字节数
+        assert_eq!(crate::memory_error_message(0), "memory limit exceeded");
+    }
+
+    #[test]
+    fn test_error_envelope_includes_run_state() {
+        reset_mock();
+        let result = run(r#"
+mw.log("before failure")
+State.insert("Page:Err", "ex:p", 1)
+return setmetatable({}, { __index = function() end }).x.y
+"#);
+        assert!(result["error"].as_str().unwrap().contains("attempt to index"), "got: {}", result);
+        assert_eq!(result["log"], "before failure\n", "got: {}", result);
+        assert_eq!(result["rdfStats"]["inserted"], 1, "got: {}", result);
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let run_batch = |batch: &str| {
+            let batch = CString::new(batch).unwrap();
+            let result_ptr = lua_run_batch(batch.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        let batch = r#"[
+            {"code": "shared = 1 print('one') return 1"},
+            {"code": "error('bad snippet')", "name": "second"},
+            {"code": "local a, b = ... return a + b + (shared or 0)", "args": [2, 3]}
+        ]"#;
+
+        let results = run_batch(batch);
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["result"], 1);
+        assert_eq!(results[0]["output"], "one\n");
+        let error = results[1]["error"].as_str().unwrap();
+        assert!(error.contains("second") && error.contains("bad snippet"), "got: {}", error);
+        assert_eq!(results[2]["result"], 5);
+
+        // 共享上下文时全局变量在片段之间保留
+        lua_set_batch_shared_context(1);
+        let results = run_batch(batch);
+        lua_set_batch_shared_context(0);
+        assert_eq!(results[2]["result"], 6, "got: {}", results);
+
+        let result = run_batch("{}");
+        assert!(result["error"].as_str().unwrap().starts_with("invalid batch"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_print_formats_tables() {
+        let result = run(r#"
+print({1, 2, key = "value", ["not a name"] = true})
+print({nested = {deep = {deeper = {deepest = {}}}}})
+local cyclic = {name = "loop"}
+cyclic.self = cyclic
+print(cyclic)
+print(setmetatable({}, {__tostring = function() return "custom" end}))
+print({})
+"#);
+        assert_eq!(
+            result["output"],
+            concat!(
+                "{1, 2, [\"not a name\"] = true, key = \"value\"}\n",
+                "{nested = {deep = {deeper = {deepest = {...}}}}}\n",
+                "{name = \"loop\", self = <cycle>}\n",
+                "custom\n",
+                "{}\n",
+            ),
+            "got: {}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_deprecated_alias_warning() {
+        let result = run(r#"
+State.put("ex:old", "ex:p", 1)
+State.put("ex:old", "ex:q", 2)
+return State.get("ex:old", "ex:q")
+"#);
+        assert_eq!(result["result"], 2, "got: {}", result);
+        assert_eq!(result["warnings"], serde_json::json!(["State.put is deprecated, use State.insert instead"]));
+
+        let result = run("return 1");
+        assert!(result.get("warnings").is_none(), "got: {}", result);
+    }
+
+    #[test]
+    fn test_tostring_metamethod_in_print_and_io_write() {
+        let result = run(r#"
+local t = setmetatable({}, {__tostring = function() return "custom" end})
+print(t, {inner = t})
+io.write(t, "|", 1, "\n")
+"#);
+        assert_eq!(result["output"], "custom\t{inner = custom}\ncustom|1\n", "got: {}", result);
+
+        // 没有 __tostring 的表仍然不能传给 io.write
+        let result = run("io.write({})");
+        assert!(result["error"].as_str().unwrap().contains("string expected, got table"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_get_limits() {
+        lua_set_log_limit(2048);
+        lua_set_instruction_limit(1_000_000);
+        let result = run("local limits = mw.getLimits() return {limits.logBytes, limits.instructions, limits.memoryBytes == nil, limits.outputBytes == nil}");
+        lua_set_log_limit(0);
+        lua_set_instruction_limit(0);
+        assert_eq!(result["result"], serde_json::json!([2048, 1_000_000, true, true]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_output_limit() {
+        lua_set_output_limit(16);
+        let limits = run("return mw.getLimits().outputBytes");
+        let result = run(r#"
+print("0123456789")
+local ok, err = pcall(io.write, "0123456789")
+io.write("ab")
+return {ok, tostring(err)}
+"#);
+        let overflow = run("print(string.rep('x', 100))");
+        lua_set_output_limit(0);
+
+        assert_eq!(limits["result"], 16, "got: {}", limits);
+        assert_eq!(result["output"], "0123456789\nab", "got: {}", result);
+        assert_eq!(result["result"][0], false, "got: {}", result);
+        assert!(result["result"][1].as_str().unwrap().contains("output limit exceeded (16 bytes)"), "got: {}", result);
+        assert!(overflow["error"].as_str().unwrap().contains("output limit exceeded"), "got: {}", overflow);
+        assert_eq!(run("print(string.rep('x', 100))")["error"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_multiple_return_values() {
+        assert_eq!(run("return 'text'")["result"], "text");
+        assert_eq!(run("return 'text', {flag = true}")["result"], serde_json::json!(["text", {"flag": true}]));
+        assert_eq!(run("return 1, nil, 3")["result"], serde_json::json!([1, null, 3]));
+        assert_eq!(run("return 1, nil")["result"], 1);
+        let result = run("local x = 1");
+        assert_eq!(result["result"], serde_json::Value::Null, "got: {}", result);
+    }
+
+    #[test]
+    fn test_import_allowlist() {
+        let set_allowlist = |json: &str| {
+            let json = CString::new(json).unwrap();
+            lua_set_import_allowlist(json.as_ptr())
+        };
+        assert_eq!(set_allowlist(r#"["rdf_read", "teleport"]"#), -1);
+        assert_eq!(set_allowlist(r#"["rdf_read"]"#), 0);
+        let result = run(r#"
+local rows = State.query({subject = "ex:allow"})
+local ok, err = pcall(State.insert, "ex:allow", "ex:p", 1)
+local require_ok, require_err = pcall(require, "anything")
+return {#rows, tostring(err), tostring(require_err)}
+"#);
+        assert_eq!(set_allowlist(""), 0);
+        assert_eq!(result["result"][0], 0, "got: {}", result);
+        assert!(result["result"][1].as_str().unwrap().contains("capability 'rdf_write' not permitted"), "got: {}", result);
+        assert!(result["result"][2].as_str().unwrap().contains("capability 'fetch' not permitted"), "got: {}", result);
+
+        let result = run(r#"State.insert("ex:allow", "ex:p", 1) return State.get("ex:allow", "ex:p")"#);
+        assert_eq!(result["result"], 1, "got: {}", result);
+    }
+
+    #[test]
+    fn test_stderr_goes_to_warnings() {
+        let result = run(r#"
+io.write("out\n")
+io.stderr:write("diagnostic ", 1, "\n"):write("second\n")
+warn("from warn")
+return true
+"#);
+        assert_eq!(result["output"], "out\n");
+        assert_eq!(result["warnings"], serde_json::json!(["diagnostic 1", "second", "from warn"]), "got: {}", result);
+    }
+
+    #[test]
+    fn test_custom_searcher() {
+        reset_mock();
+        with_mock(|m| {
+            m.resolver_modules.insert("pkg@2".to_string(), "return { version = 2 }".to_string());
+        });
+        lua_set_custom_searcher(1);
+        let result = run(r#"
+local missing_ok, missing = pcall(require, "absent")
+local broken_ok, broken = pcall(require, "broken")
+return {require("pkg@2").version, tostring(missing), tostring(broken)}
+"#);
+        lua_set_custom_searcher(0);
+        assert_eq!(result["result"][0], 2, "got: {}", result);
+        assert!(result["result"][1].as_str().unwrap().contains("module 'absent' not found"), "got: {}", result);
+        assert!(result["result"][2].as_str().unwrap().contains("resolver failed"), "got: {}", result);
+        assert!(with_mock(|m| m.fetches.is_empty()), "custom searcher must not call fetch_lua_module");
+        assert!(with_mock(|m| m.resolver_calls.contains(&"pkg@2".to_string())));
+    }
+
+    #[test]
+    fn test_error_traceback() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert(
+                "broken_helper".to_string(),
+                "local M = {}\nfunction M.run()\n  local missing = nil\n  missing()\nend\nreturn M".to_string(),
+            );
+        });
+        let result = run("local helper = require('broken_helper')\nhelper.run()");
+        assert!(result["error"].as_str().unwrap().contains("attempt to call"), "got: {}", result);
+        let traceback = result["traceback"].as_str().unwrap();
+        assert!(traceback.starts_with("stack traceback:"), "got: {}", traceback);
+        assert!(traceback.contains("[string \"broken_helper\"]:4"), "got: {}", traceback);
+        assert!(traceback.contains("[string \"input\"]:2"), "got: {}", traceback);
+
+        // 被 pcall 捕获的错误不会留下调用栈
+        let result = run("pcall(error, 'caught') error('uncaught')");
+        assert!(result["traceback"].as_str().unwrap().contains("input"), "got: {}", result);
+        let result = run("pcall(error, 'caught') return 1");
+        assert!(result.get("traceback").is_none(), "got: {}", result);
+    }
+
+    #[test]
+    fn test_report_serialize_timing() {
+        reset_mock();
+        let code = "local rows = {} for i = 1, 2000 do rows[i] = { id = i, name = 'row ' .. i } end return rows";
+        let result = run(code);
+        assert!(result.get("timings").is_none(), "got: {}", result);
+
+        lua_set_report_timings(1);
+        with_mock(|m| m.epoch_step = 0.25);
+        let result = run(code);
+        with_mock(|m| m.epoch_step = 0.0);
+        lua_set_report_timings(0);
+
+        assert_eq!(result["result"].as_array().unwrap().len(), 2000);
+        assert_eq!(result["result"][1999]["name"], "row 2000");
+        assert_eq!(result["timings"]["serializeMs"].as_f64(), Some(250.0), "got: {}", result["timings"]);
+    }
+
+    #[test]
+    fn test_module_source_cache() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("shared".to_string(), "return { value = 1 }".to_string());
+        });
+        let ctx = lua_new_context();
+        let run_in = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_in_context(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        let reload = "package.loaded['shared'] = nil\nreturn require('shared').value";
+
+        // 重新 require 已卸载的模块时使用缓存的源码
+        let result = run_in(&format!("require('shared')\n{}", reload));
+        assert_eq!(result["result"], 1, "got: {}", result);
+        assert_eq!(with_mock(|m| m.fetches.clone()), vec!["shared".to_string()]);
+
+        // 清空缓存后重新向宿主获取编辑后的源码
+        with_mock(|m| m.modules.insert("shared".to_string(), "return { value = 2 }".to_string()));
+        lua_clear_module_cache();
+        let result = run_in(reload);
+        assert_eq!(result["result"], 2, "got: {}", result);
+        assert_eq!(with_mock(|m| m.fetches.len()), 2);
+
+        // 获取失败不缓存
+        let missing = "return pcall(require, 'not_there_yet')";
+        run_in(missing);
+        run_in(missing);
+        assert_eq!(with_mock(|m| m.fetches.iter().filter(|f| *f == "not_there_yet").count()), 2);
+
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_json_library_round_trip() {
+        let result = run(r#"
+local data = {
+  name = "Page",
+  tags = { "a", "b", "c" },
+  nested = { level = { deeper = { 1, 2, { x = true } } } },
+  ratio = 1.5,
+}
+local encoded = json.encode(data)
+local decoded = json.decode(encoded)
+local pretty = json.encode({ list = { 1, 2 } }, true)
+return {
+  encoded = encoded,
+  same = json.encode(decoded) == encoded,
+  deeper = decoded.nested.level.deeper[3].x,
+  tag = decoded.tags[2],
+  count = #decoded.tags,
+  pretty = pretty,
+  null_is_sentinel = json.decode("[null]")[1] == json.null,
+}
+"#);
+        let result = &result["result"];
+        assert_eq!(
+            result["encoded"],
+            r#"{"name":"Page","nested":{"level":{"deeper":[1,2,{"x":true}]}},"ratio":1.5,"tags":["a","b","c"]}"#
+        );
+        assert_eq!(result["same"], true);
+        assert_eq!(result["deeper"], true);
+        assert_eq!(result["tag"], "b");
+        assert_eq!(result["count"], 3);
+        assert_eq!(result["pretty"], "{\n  \"list\": [\n    1,\n    2\n  ]\n}");
+        assert_eq!(result["null_is_sentinel"], true);
+
+        let result = run("return json.decode('{broken')");
+        assert!(result["error"].as_str().unwrap().contains("JSON parse error"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_state_emit_records_triples() {
+        reset_mock();
+        let result = run(r#"
+State.emit("Page:A", "ex:title", "Alpha")
+State.emit("Page:A", "ex:meta", { rank = 2 })
+State.insert("Page:B", "ex:title", "not emitted")
+return #State.query({ subject = "Page:A" })
+"#);
+        assert_eq!(result["result"], 2, "got: {}", result);
+        assert_eq!(
+            result["emittedTriples"],
+            serde_json::json!([
+                { "subject": "Page:A", "predicate": "ex:title", "object": "Alpha" },
+                { "subject": "Page:A", "predicate": "ex:meta", "object": { "rank": 2 } },
+            ])
+        );
+        assert_eq!(with_mock(|m| m.triples.len()), 3);
+
+        let result = run("return 1");
+        assert!(result.get("emittedTriples").is_none(), "got: {}", result);
+
+        let mode = CString::new("typed").unwrap();
+        assert_eq!(lua_set_rdf_boolean_mode(mode.as_ptr()), 0);
+        let result = run(r#"State.emit("Page:C", "ex:published", true)"#);
+        let mode = CString::new("native").unwrap();
+        assert_eq!(lua_set_rdf_boolean_mode(mode.as_ptr()), 0);
+        let stored = with_mock(|m| m.triples.last().unwrap()["object"].clone());
+        assert_eq!(result["emittedTriples"][0]["object"], stored, "got: {}", result);
+        assert_eq!(stored["@value"], "true");
+    }
+
+    #[test]
+    fn test_request_cancel_context_run() {
+        reset_mock();
+        let ctx = lua_new_context();
+        let run_in = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_in_context(ctx, code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+
+        // 宿主在 insert 回调中取消，pcall 无法吞掉取消错误
+        with_mock(|m| m.cancel_on_insert = Some(ctx as usize));
+        let result = run_in("State.insert('Page:A', 'ex:p', 1)\nwhile true do pcall(function() while true do end end) end");
+        assert_eq!(result["code"], "E_CANCELLED", "got: {}", result);
+        assert_eq!(result["error"], "run cancelled by host");
+
+        // 取消只影响进行中的运行
+        lua_request_cancel(ctx);
+        let result = run_in("local s = 0 for i = 1, 1000 do s = s + i end return s");
+        assert_eq!(result["result"], 500500, "got: {}", result);
+
+        lua_free_context(ctx);
+    }
+
+    #[test]
+    fn test_rdf_query_limit_and_offset() {
+        reset_mock();
+        let result = run(r#"
+for i = 1, 5 do State.insert("Page:Big", "ex:item", i) end
+local limited = State.query({ subject = "Page:Big", limit = 2 })
+local skipped = State.query({ subject = "Page:Big", limit = 2, offset = 4 })
+local all = State.query({ subject = "Page:Big" })
+return { limited = #limited, first = limited[1].object, skipped = #skipped, all = #all }
+"#);
+        assert_eq!(result["result"], serde_json::json!({ "limited": 2, "first": 1, "skipped": 1, "all": 5 }), "got: {}", result);
+        let queries = with_mock(|m| m.queries.clone());
+        assert_eq!(queries[0]["limit"], 2);
+        assert!(queries[0].get("offset").is_none(), "got: {}", queries[0]);
+        assert!(queries[2].get("limit").is_none(), "got: {}", queries[2]);
+
+        let result = run(r#"return State.query({ subject = "Page:Big", offset = -1 })"#);
+        assert!(result["error"].as_str().unwrap().contains("offset must be non-negative"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_normalize_module_newlines() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("crlf_mod".to_string(), "local M = {}\r\nfunction M.run()\r\n  error('crlf failure')\r\nend\r\nreturn M".to_string());
+            // 混用 \n\r 的源码：Lua 把 \n\r 当作一个换行，编辑器中是两行
+            m.modules.insert("mixed_mod".to_string(), "local M = {}\n\r\rfunction M.run()\r  error('mixed failure')\rend\nreturn M".to_string());
+        });
+        let code = "local ok, err = pcall(require('crlf_mod').run)\nlocal ok2, err2 = pcall(require('mixed_mod').run)\nreturn { err, err2 }";
+
+        lua_set_normalize_newlines(1);
+        let result = run(code);
+        lua_set_normalize_newlines(0);
+        let errors = &result["result"];
+        assert!(errors[0].as_str().unwrap().contains("crlf_mod\"]:3: crlf failure"), "got: {}", result);
+        assert!(errors[1].as_str().unwrap().contains("mixed_mod\"]:5: mixed failure"), "got: {}", result);
+
+        let result = run(code);
+        assert!(result["result"][1].as_str().unwrap().contains("mixed_mod\"]:4: mixed failure"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_query_order_by() {
+        reset_mock();
+        let result = run(r#"return #State.query({ subject = "Page:Sorted", orderBy = "object", desc = true, limit = 3 })"#);
+        assert_eq!(result["result"], 0, "got: {}", result);
+        let query = with_mock(|m| m.queries[0].clone());
+        assert_eq!(query["orderBy"], "object");
+        assert_eq!(query["desc"], true);
+        assert_eq!(query["limit"], 3);
+
+        let result = run(r#"return State.query({ subject = "Page:Sorted", orderBy = "foo" })"#);
+        assert!(result["error"].as_str().unwrap().contains("invalid orderBy field"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_transaction() {
+        reset_mock();
+        let result = run(r#"
+local value = State.transaction(function()
+  State.insert("Page:T", "ex:kept", 1)
+  return "committed"
+end)
+local ok, err = pcall(State.transaction, function()
+  State.insert("Page:T", "ex:lost", 2)
+  error("abort transaction")
+end)
+local nested_ok, nested_err = pcall(State.transaction, function()
+  State.transaction(function() end)
+end)
+return { value = value, err = tostring(err), nested = tostring(nested_err), count = #State.query({ subject = "Page:T" }) }
+"#);
+        let result = &result["result"];
+        assert_eq!(result["value"], "committed", "got: {}", result);
+        assert!(result["err"].as_str().unwrap().contains("abort transaction"), "got: {}", result);
+        assert!(result["nested"].as_str().unwrap().contains("nested transaction"), "got: {}", result);
+        assert_eq!(result["count"], 1);
+        assert_eq!(
+            with_mock(|m| m.transactions.clone()),
+            vec!["begin", "commit", "begin", "rollback", "begin", "rollback"]
+        );
+
+        reset_mock();
+        with_mock(|m| m.commit_error = Some("disk full".to_string()));
+        let result = run(r#"
+local ok, err = pcall(State.transaction, function()
+  State.insert("Page:T", "ex:unsaved", 3)
+end)
+return { err = tostring(err), count = #State.query({ subject = "Page:T" }) }
+"#);
+        assert!(result["result"]["err"].as_str().unwrap().contains("RDF transaction commit failed: disk full"), "got: {}", result);
+        assert_eq!(result["result"]["count"], 0, "got: {}", result);
+        assert_eq!(with_mock(|m| m.transactions.clone()), vec!["begin", "commit", "rollback"]);
+    }
+
+    #[test]
+    fn test_run_yaml_result() {
+        let run_yaml = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run_yaml(code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+        let code = r#"
+print("shown")
+return { title = "Page", tags = { "a", "b" }, meta = { rank = 2, draft = false, note = "x: y" } }
+"#;
+        let envelope = run_yaml(code);
+        assert_eq!(envelope["output"], "shown\n");
+        assert_eq!(
+            envelope["result"],
+            "meta:\n  draft: false\n  note: \"x: y\"\n  rank: 2\ntags:\n- a\n- b\ntitle: Page\n"
+        );
+
+        let envelope = run_yaml(r#"return { { 1, 2 }, { k = "v", list = { "x" } }, "true", "007", "", "two\nlines", "-dash", 1.5 }"#);
+        assert_eq!(
+            envelope["result"],
+            "- - 1\n  - 2\n- k: v\n  list:\n  - x\n- \"true\"\n- \"007\"\n- \"\"\n- \"two\\nlines\"\n- \"-dash\"\n- 1.5\n",
+            "got: {}", envelope
+        );
+        assert_eq!(run_yaml("return 'plain text'")["result"], "plain text\n");
+
+        let envelope = run_yaml("error('boom')");
+        assert!(envelope["result"].is_null(), "got: {}", envelope);
+        assert!(envelope["error"].as_str().unwrap().contains("boom"), "got: {}", envelope);
+    }
+
+    #[test]
+    fn test_rdf_count() {
+        reset_mock();
+        let result = run(r#"
+for i = 1, 4 do State.insert("Page:Counted", "ex:item", i) end
+State.insert("Page:Counted", "ex:title", "Counted")
+local pattern = { subject = "Page:Counted", predicate = "ex:item" }
+return { count = State.count(pattern), rows = #State.query(pattern), all = State.count({ subject = "Page:Counted" }), none = State.count({ subject = "Page:Missing" }) }
+"#);
+        assert_eq!(result["result"], serde_json::json!({ "count": 4, "rows": 4, "all": 5, "none": 0 }), "got: {}", result);
+    }
+
+    #[test]
+    fn test_error_source_context() {
+        reset_mock();
+        let result = run("local a = 1\nlocal b = nil\nlocal c = b.field\nreturn c");
+        assert_eq!(
+            result["context"],
+            serde_json::json!(["2: local b = nil", "3 -> local c = b.field", "4: return c"]),
+            "got: {}",
+            result
+        );
+
+        // 第一行的语法错误只有后面的行
+        let result = run("local = 1\nreturn 2");
+        assert_eq!(result["context"], serde_json::json!(["1 -> local = 1", "2: return 2"]), "got: {}", result);
+
+        // 模块中的错误和成功的运行不带源码
+        with_mock(|m| m.modules.insert("failing_ctx".to_string(), "error('in module')".to_string()));
+        let result = run("require('failing_ctx')");
+        assert!(result.get("context").is_none(), "got: {}", result);
+        assert!(run("return 1").get("context").is_none());
+    }
+
+    #[test]
+    fn test_rdf_has() {
+        reset_mock();
+        let result = run(r#"
+State.insert("Page:Has", "ex:title", "Present")
+return {
+  any = State.has("Page:Has", "ex:title"),
+  exact = State.has("Page:Has", "ex:title", "Present"),
+  wrong = State.has("Page:Has", "ex:title", "Absent"),
+  missing = State.has("Page:Has", "ex:other"),
+  alias = rawequal(State.has, State.exists),
+}
+"#);
+        assert_eq!(result["result"], serde_json::json!({ "any": true, "exact": true, "wrong": false, "missing": false, "alias": true }), "got: {}", result);
+        // 不通过 query 传回三元组
+        assert!(with_mock(|m| m.queries.is_empty()));
+    }
+
+    #[test]
+    fn test_rdf_query_cache_backend() {
+        reset_mock();
+        let code = r#"return #State.query({ subject = "Page:Cached" })"#;
+
+        lua_set_rdf_cache_backend(1);
+        lua_set_rdf_cache_ttl(60);
+        let first = run(code);
+        let second = run(code);
+        let queries_before_write = with_mock(|m| m.queries.len());
+        let after_write = run(r#"State.insert("Page:Cached", "ex:p", 1) return #State.query({ subject = "Page:Cached" })"#);
+        let generation_gets_before = with_mock(|m| m.cache_gets.iter().filter(|k| *k == "rdf-query-generation").count());
+        let many_writes = run(r#"
+for i = 1, 3 do
+  State.insert("Page:Cached", "ex:n", i)
+  State.query({ subject = "Page:Cached" })
+end
+State.delete("Page:Cached", "ex:n")
+State.batchInsert({ { subject = "Page:Cached", predicate = "ex:m", object = 1 } })
+return #State.query({ subject = "Page:Cached" })
+"#);
+        let generation_gets = with_mock(|m| m.cache_gets.iter().filter(|k| *k == "rdf-query-generation").count());
+        let repeated = run(r#"State.query({ subject = "Page:Cached" }) return #State.query({ subject = "Page:Cached" })"#);
+        lua_set_rdf_cache_backend(0);
+        lua_set_rdf_cache_ttl(0);
+
+        assert_eq!(first["result"], 0);
+        assert_eq!(second["result"], 0);
+        // 第二次运行命中缓存，没有调用查询
+        assert_eq!(queries_before_write, 1);
+        // 写入后版本号递增，旧结果不再命中
+        assert_eq!(after_write["result"], 1, "got: {}", after_write);
+        let ttls: Vec<u32> = with_mock(|m| m.cache_sets.iter().filter(|(k, _)| k.starts_with("rdf-query:")).map(|(_, t)| *t).collect());
+        assert!(ttls.iter().all(|&t| t == 60), "got: {:?}", ttls);
+        // 命中缓存不计入 queried，单独计入 cacheHits
+        assert_eq!(second["rdfStats"]["queried"], 0, "got: {}", second);
+        assert_eq!(second["rdfStats"]["cacheHits"], 1, "got: {}", second);
+        // 版本号每次运行只从宿主读取一次，之后的写入在本地递增
+        assert_eq!(many_writes["result"], 2, "got: {}", many_writes);
+        assert_eq!(generation_gets - generation_gets_before, 1);
+        assert_eq!(with_mock(|m| m.cache["rdf-query-generation"].clone()), "6");
+        assert_eq!(repeated["rdfStats"]["queried"], 0, "got: {}", repeated);
+        assert_eq!(repeated["rdfStats"]["cacheHits"], 2, "got: {}", repeated);
+    }
+
+    #[test]
+    fn test_mw_require_base() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert("mediawiki://wiki/Module:Guarded".to_string(), "mw.requireBase('mediawiki://other/')\nreturn 1".to_string());
+            m.modules.insert("mediawiki://wiki/Module:Where".to_string(), "mw.requireBase('mediawiki://wiki')\nreturn mw.currentBase()".to_string());
+        });
+        let result = run("return { top = mw.currentBase(), inside = require('mediawiki://wiki/Module:Where'), after = mw.currentBase() }");
+        assert_eq!(result["result"], serde_json::json!({ "inside": "mediawiki://wiki/" }), "got: {}", result);
+
+        let result = run("return require('mediawiki://wiki/Module:Guarded')");
+        let error = result["error"].as_str().unwrap();
+        assert!(
+            error.contains("expected MediaWiki base 'mediawiki://other/', but the current base is 'mediawiki://wiki/'"),
+            "got: {}",
+            error
+        );
+
+        let result = run("mw.requireBase('mediawiki://wiki/')");
+        assert!(result["error"].as_str().unwrap().contains("the current base is not set"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_increment() {
+        reset_mock();
+        let result = run(r#"
+local first = State.increment("Page:Counter", "ex:views")
+local second = State.increment("Page:Counter", "ex:views", 5)
+local fractional = State.increment("Page:Counter", "ex:views", 0.5)
+State.insert("Page:Counter", "ex:title", "Counter")
+local ok, err = pcall(State.increment, "Page:Counter", "ex:title")
+local bad_ok, bad_err = pcall(State.increment, "Page:Counter", "ex:views", "2")
+return { first = first, second = second, integer = math.type(second), fractional = fractional, err = tostring(err), bad = tostring(bad_err) }
+"#);
+        let result = &result["result"];
+        assert_eq!(result["first"], 1, "got: {}", result);
+        assert_eq!(result["second"], 6);
+        assert_eq!(result["integer"], "integer");
+        assert_eq!(result["fractional"], 6.5);
+        assert!(result["err"].as_str().unwrap().contains("cannot increment non-numeric value"), "got: {}", result);
+        assert!(result["bad"].as_str().unwrap().contains("delta must be a number"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_run_with_context_globals() {
+        let run_with = |code: &str, context: &str| {
+            let (code, context) = (CString::new(code).unwrap(), CString::new(context).unwrap());
+            let result_ptr = lua_run_with_context(code.as_ptr(), context.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+
+        let result = run_with("return PAGENAME .. ':' .. args['1']", r#"{"PAGENAME":"Foo","args":{"1":"x"}}"#);
+        assert_eq!(result["result"], "Foo:x", "got: {}", result);
+
+        // 上下文变量不会留到下一次运行
+        assert_eq!(run("return PAGENAME == nil")["result"], true);
+
+        let result = run_with("return 1", "{not json");
+        assert!(result["error"].as_str().unwrap().starts_with("invalid context JSON: "), "got: {}", result);
+        let result = run_with("return 1", "[1, 2]");
+        assert!(result["error"].as_str().unwrap().starts_with("invalid context JSON: "), "got: {}", result);
+    }
+
+    #[test]
+    fn test_run_json_request() {
+        let run_json = |request: &str| {
+            let request = CString::new(request).unwrap();
+            let result_ptr = lua_run_json(request.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            serde_json::from_str::<serde_json::Value>(&text).unwrap()
+        };
+
+        let result = run_json(r#"{"code": "while true do end", "timeout_ms": 50}"#);
+        assert_eq!(result["error"], "execution timed out after 50 ms", "got: {}", result);
+
+        // 限制只对该请求生效，未知字段被忽略
+        let result = run_json(r#"{
+            "code": "local l = mw.getLimits() print(PAGE) return { l.instructions, l.memoryBytes, l.timeoutMs }",
+            "context": {"PAGE": "Main"},
+            "instruction_limit": 1000000,
+            "memory_limit": 8388608,
+            "priority": "high"
+        }"#);
+        assert_eq!(result["result"], serde_json::json!([1000000, 8388608]), "got: {}", result);
+        assert_eq!(result["output"], "Main\n");
+        assert_eq!(run("return mw.getLimits().timeoutMs == nil")["result"], true);
+
+        let result = run_json("{}");
+        assert_eq!(result, serde_json::json!({ "result": null, "output": "", "error": null }));
+
+        let result = run_json("{\"code\": 1}");
+        assert!(result["error"].as_str().unwrap().starts_with("invalid request: "), "got: {}", result);
+    }
+
+    #[test]
+    fn test_rdf_flush() {
+        reset_mock();
+        with_mock(|m| m.flushes = 0);
+        let result = run("State.insert('Page:F', 'ex:p', 1)\nState.flush()\nreturn 'flushed'");
+        assert_eq!(result["result"], "flushed", "got: {}", result);
+        assert_eq!(with_mock(|m| m.flushes), 1);
+
+        with_mock(|m| m.rdf_error = Some("store offline".to_string()));
+        let result = run("State.flush()");
+        with_mock(|m| m.rdf_error = None);
+        assert!(result["error"].as_str().unwrap().contains("RDF flush failed: store offline"), "got: {}", result);
+        assert_eq!(with_mock(|m| m.flushes), 2);
+    }
+
+    #[test]
+    fn test_profile_ranks_hot_function() {
+        reset_mock();
+        with_mock(|m| {
+            m.modules.insert(
+                "hot_mod".to_string(),
+                "local M = {}\nlocal function hot(n)\n  local s = 0\n  for i = 1, n do s = s + i end\n  return s\nend\nfunction M.run()\n  local total = 0\n  for i = 1, 200 do total = total + hot(100) end\n  return total\nend\nreturn M".to_string(),
+            );
+        });
+        assert!(run("return 1").get("profile").is_none());
+
+        lua_set_profile(1);
+        let result = run("return require('hot_mod').run()");
+        lua_set_profile(0);
+
+        assert_eq!(result["result"], 200 * 5050, "got: {}", result);
+        let profile = result["profile"].as_array().unwrap();
+        assert_eq!(profile[0]["function"], "[string \"hot_mod\"]:2", "got: {:?}", profile);
+        assert_eq!(profile[0]["calls"], 200);
+        assert!(profile[0]["instructions"].as_u64().unwrap() > profile[1]["instructions"].as_u64().unwrap(), "got: {:?}", profile);
+        assert!(profile.iter().any(|entry| entry["function"] == "[string \"hot_mod\"]:7" && entry["calls"] == 1), "got: {:?}", profile);
+    }
+
+    #[test]
+    fn test_nul_bytes_round_trip() {
+        let result = run("print('a\\0b') io.write('c\\0') return 'x\\0y'");
+        assert_eq!(result["output"], "a\u{0}b\nc\u{0}", "got: {}", result);
+        assert_eq!(result["result"], "x\u{0}y");
+        assert!(result["error"].is_null());
+
+        let code = CString::new("return 'x\\0y'").unwrap();
+        let result_ptr = lua_run(code.as_ptr());
+        let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+        lua_free_result(result_ptr);
+        assert!(text.contains(r#""x\u0000y""#), "got: {}", text);
+    }
+
+    #[test]
+    fn test_integer_and_float_results() {
+        let raw = |code: &str| {
+            let code = CString::new(code).unwrap();
+            let result_ptr = lua_run(code.as_ptr());
+            let text = unsafe { CStr::from_ptr(result_ptr).to_string_lossy().into_owned() };
+            lua_free_result(result_ptr);
+            text
+        };
+        assert!(raw("return 3").contains(r#""result":3}"#));
+        assert!(raw("return 3.0").contains(r#""result":3.0}"#));
+        assert!(raw("return 3 // 1, 3 / 1, 2^53").contains(r#""result":[3,3.0,9007199254740992.0]"#));
+        assert!(raw("return { count = 2, ratio = 2.0 }").contains(r#"{"count":2,"ratio":2.0}"#));
+
+        let result = run("return 3.0");
+        assert!(result["result"].is_f64(), "got: {}", result);
+        assert!(run("return 3")["result"].is_i64());
+    }
+
+    #[test]
+    fn test_rdf_boolean_mode() {
+        reset_mock();
+        let mode = CString::new("typed").unwrap();
+        assert_eq!(lua_set_rdf_boolean_mode(mode.as_ptr()), 0);
+        let result = run(r#"
+State.insert("ex:s", "ex:published", true)
+return State.get("ex:s", "ex:published")
+"#);
+        assert_eq!(result["result"], true, "typed literal decodes back to a boolean: {}", result);
+        let stored = with_mock(|m| m.triples[0]["object"].clone());
+        assert_eq!(stored, serde_json::json!({
+            "@value": "true",
+            "@type": "http://www.w3.org/2001/XMLSchema#boolean"
+        }));
+
+        let mode = CString::new("string").unwrap();
+        assert_eq!(lua_set_rdf_boolean_mode(mode.as_ptr()), 0);
+        let result = run(r#"
+State.insert("ex:t", "ex:draft", false)
+return State.query({subject = "ex:t"})[1].object
+"#);
+        assert_eq!(result["result"], false, "got: {}", result);
+        let stored = with_mock(|m| m.triples.last().unwrap()["object"].clone());
+        assert_eq!(stored, "false");
+
+        let mode = CString::new("bogus").unwrap();
+        assert_eq!(lua_set_rdf_boolean_mode(mode.as_ptr()), -1);
+        let mode = CString::new("native").unwrap();
+        assert_eq!(lua_set_rdf_boolean_mode(mode.as_ptr()), 0);
+    }
+
+    #[test]
+    fn test_seeded_uuid() {
+        let code = "return {mw.uuid(), mw.uuid()}";
+        lua_seed_uuid(42);
+        let first = run(code);
+        assert_eq!(first["result"], serde_json::json!([
+            "bdd73226-2feb-4e95-a8ef-e333b266f103",
+            "47526757-130f-4f52-981c-e1ff0e4ae394"
+        ]), "got: {}", first);
+        let second = run(code);
+        assert_eq!(second["result"], first["result"], "every run restarts from the seed");
+
+        lua_seed_uuid(7);
+        let other = run(code);
+        assert_ne!(other["result"], first["result"]);
+        lua_clear_uuid_seed();
+    }
+
+    #[test]
+    fn test_non_finite_numbers() {
+        let code = "local t = {x = math.huge, y = 0/0, nested = {-math.huge, 1.5}} return t, math.huge";
+
+        let result = run(code);
+        assert_eq!(result["error"], serde_json::Value::Null, "got: {}", result);
+        assert_eq!(result["result"], serde_json::json!([
+            {"x": null, "y": null, "nested": [null, 1.5]},
+            null
+        ]));
+
+        lua_set_infinity_as_string(1);
+        let result = run(code);
+        assert_eq!(result["result"], serde_json::json!([
+            {"x": "Infinity", "y": null, "nested": ["-Infinity", 1.5]},
+            "Infinity"
+        ]), "got: {}", result);
+        lua_set_infinity_as_string(0);
+    }
+
+    #[test]
+    fn test_table_serialization_rules() {
+        // 键恰好为 1..n 时为数组
+        let result = run("return {10, 20, 30}, {[3] = 'c', [1] = 'a', [2] = 'b'}");
+        assert_eq!(result["result"], serde_json::json!([[10, 20, 30], ["a", "b", "c"]]), "got: {}", result);
+
+        // 有空洞的稀疏数组为对象，整数键转为十进制字符串
+        let result = run("return {[1] = 1, [3] = 3}, {1, nil, 3}");
+        assert_eq!(result["result"], serde_json::json!([{"1": 1, "3": 3}, {"1": 1, "3": 3}]), "got: {}", result);
+
+        // 数组部分与哈希部分混合时为对象，不丢弃任何键
+        let result = run("return {1, 2, x = 3}, {[0] = 'zero', [1] = 'one'}, {[1.5] = 'f', [true] = 'b'}");
+        assert_eq!(result["result"], serde_json::json!([
+            {"1": 1, "2": 2, "x": 3},
+            {"0": "zero", "1": "one"},
+            {"1.5": "f", "true": "b"}
+        ]), "got: {}", result);
+
+        // json.encode 与 State 写入使用同样的规则
+        let result = run("return json.encode({[1] = 1, [3] = 3})");
+        assert_eq!(result["result"], r#"{"1":1,"3":3}"#, "got: {}", result);
+
+        // 空表为对象，json.decode 得到的空数组仍为数组；循环引用报错，同一个表出现多次不受影响
+        let result = run("local shared = {1} return {}, json.decode('[]'), {shared, shared}");
+        assert_eq!(result["result"], serde_json::json!([{}, [], [[1], [1]]]), "got: {}", result);
+        let result = run("local t = {} t.self = t return t");
+        assert!(result["error"].as_str().unwrap().contains("recursive table detected"), "got: {}", result);
+    }
+
+    #[test]
+    fn test_describe_api() {
+        // 公共代码不会被执行，它定义的全局变量和副作用都不出现在描述中
+        let baseline = CString::new("print('setup') function greet(name, greeting) end").unwrap();
+        assert_eq!(lua_set_baseline(baseline.as_ptr()), 0);
+        let ptr = lua_describe_api();
+        let description: serde_json::Value = serde_json::from_str(&unsafe { CStr::from_ptr(ptr) }.to_string_lossy()).unwrap();
+        lua_free_result(ptr);
+        let empty = CString::new("").unwrap();
+        lua_set_baseline(empty.as_ptr());
+
+        assert_eq!(description["State"]["type"], "table");
+        assert_eq!(description["State.insert"]["type"], "function", "got: {}", description);
+        assert_eq!(description["mw.text.jsonEncode"]["type"], "function", "got: {}", description);
+        assert!(description["State.insert"]["params"].is_null(), "Rust functions have no introspectable arity");
+        assert!(description.get("greet").is_none(), "got: {}", description);
+        // 标准库不在描述中
+        assert!(description.get("string").is_none() && description.get("print").is_none());
+
+        // Lua 实现的函数给出参数个数
+        let lua = mlua::Lua::new();
+        lua.load("function greet(name, greeting) end function log_all(level, ...) end").exec().unwrap();
+        let description = crate::describe_api(&lua).unwrap();
+        assert_eq!(description["greet"], serde_json::json!({"type": "function", "params": 2, "vararg": false}));
+        assert_eq!(description["log_all"], serde_json::json!({"type": "function", "params": 1, "vararg": true}));
+    }
+
+    #[test]
+    fn test_host_imports_unavailable() {
+        reset_mock();
+        let set_imports = |json: &str| {
+            let json = CString::new(json).unwrap();
+            lua_set_host_imports(json.as_ptr())
+        };
+        assert_eq!(set_imports(r#"["fetch", "telepathy"]"#), -1);
+        // 模拟只对接了模块获取的宿主：js_rdf_* 不可用
+        assert_eq!(set_imports(r#"["fetch"]"#), 0);
+        let result = run(r#"
+local ok, err = pcall(State.insert, "ex:s", "ex:p", 1)
+local query_ok, query_err = pcall(State.query, {subject = "ex:s"})
+return {ok, tostring(err), tostring(query_err)}
+"#);
+        let host_calls = with_mock(|m| (m.triples.len(), m.queries.len()));
+        assert_eq!(set_imports(""), 0);
+        assert_eq!(result["result"][0], false, "got: {}", result);
+        assert!(result["result"][1].as_str().unwrap().contains("host capability 'rdf' unavailable"), "got: {}", result);
+        assert!(result["result"][2].as_str().unwrap().contains("host capability 'rdf' unavailable"), "got: {}", result);
+        assert_eq!(host_calls, (0, 0), "unavailable imports are never called");
+
+        let result = run(r#"State.insert("ex:s", "ex:p", 1) return State.get("ex:s", "ex:p")"#);
+        assert_eq!(result["result"], 1, "got: {}", result);
+
+        // 没有时钟、随机源和用户上下文的宿主
+        with_mock(|m| m.user_context = Some(r#"{"name":"Alice","isAnon":false}"#.to_string()));
+        assert_eq!(set_imports(r#"["fetch", "rdf"]"#), 0);
+        lua_set_timeout_ms(1000);
+        let result = run(r#"
+local uuid_ok, uuid_err = pcall(mw.uuid)
+local clock_ok, clock_err = pcall(os.clock)
+return { uuid = tostring(uuid_err), clock = tostring(clock_err), anon = mw.getCurrentUser().isAnon }
+"#);
+        lua_set_timeout_ms(0);
+        assert_eq!(set_imports(""), 0);
+        assert!(result["result"]["uuid"].as_str().unwrap().contains("host capability 'random' unavailable"), "got: {}", result);
+        assert!(result["result"]["clock"].as_str().unwrap().contains("host capability 'clock' unavailable"), "got: {}", result);
+        assert_eq!(result["result"]["anon"], true, "got: {}", result);
+        assert!(result["warnings"].to_string().contains("lua_set_timeout_ms ignored"), "got: {}", result);
     }
 }
@@ -0,0 +1,366 @@
+//! mw.ustring 使用的模式匹配：与 Lua 5.4 的模式语法相同，但按 Unicode 码点而不是字节匹配
+//!
+//! `.`、字符类和集合都匹配一个完整的码点；`%a`、`%l`、`%u`、`%w`、`%s`、`%p`、`%c`、`%g` 按 Unicode 属性判断，
+//! `%d` 和 `%x` 只匹配 ASCII 数字。位置捕获 `()` 返回码点位置（从 1 开始）。
+
+/// Lua 的捕获数量上限（LUA_MAXCAPTURES）
+const MAX_CAPTURES: usize = 32;
+/// 匹配的最大递归深度（MAXCCALLS），超出时报错 "pattern too complex"
+const MAX_MATCH_DEPTH: usize = 200;
+
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+/// 一个捕获的值：子串或位置捕获 `()` 的码点位置（从 1 开始）
+#[derive(Debug, PartialEq)]
+pub(crate) enum Capture {
+    Text(String),
+    Position(usize),
+}
+
+/// 一次匹配的结果：匹配结束的码点下标及捕获；没有捕获时 captures 只含整个匹配
+pub(crate) struct Match {
+    pub(crate) end: usize,
+    pub(crate) captures: Vec<Capture>,
+}
+
+struct MatchState<'a> {
+    src: &'a [char],
+    pat: &'a [char],
+    depth: usize,
+    level: usize,
+    capture: [(usize, isize); MAX_CAPTURES],
+}
+
+impl<'a> MatchState<'a> {
+    fn new(src: &'a [char], pat: &'a [char]) -> Self {
+        MatchState { src, pat, depth: 0, level: 0, capture: [(0, 0); MAX_CAPTURES] }
+    }
+
+    fn reset(&mut self) {
+        self.depth = 0;
+        self.level = 0;
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        self.depth += 1;
+        if self.depth > MAX_MATCH_DEPTH {
+            return Err("pattern too complex".to_string());
+        }
+        let result = loop {
+            if p == self.pat.len() {
+                break Some(s);
+            }
+            match self.pat[p] {
+                '(' => {
+                    break if self.pat.get(p + 1) == Some(&')') {
+                        self.start_capture(s, p + 2, CAP_POSITION)?
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)?
+                    };
+                }
+                ')' => break self.end_capture(s, p + 1)?,
+                '$' if p + 1 == self.pat.len() => break (s == self.src.len()).then_some(s),
+                '%' if self.pat.get(p + 1) == Some(&'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    }
+                    None => break None,
+                },
+                '%' if self.pat.get(p + 1) == Some(&'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { '\0' } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or('\0');
+                    if !self.match_bracket(previous, p, ep - 1) && self.match_bracket(current, p, ep - 1) {
+                        p = ep;
+                        continue;
+                    }
+                    break None;
+                }
+                '%' if self.pat.get(p + 1).is_some_and(char::is_ascii_digit) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => break None,
+                    }
+                }
+                _ => {}
+            }
+
+            let ep = self.class_end(p)?;
+            let quantifier = self.pat.get(ep).copied();
+            if !(s < self.src.len() && self.single_match(self.src[s], p, ep)) {
+                // 不匹配时，允许零次的量词继续匹配模式的剩余部分
+                if matches!(quantifier, Some('*' | '?' | '-')) {
+                    p = ep + 1;
+                    continue;
+                }
+                break None;
+            }
+            match quantifier {
+                Some('?') => {
+                    if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                        break Some(end);
+                    }
+                    p = ep + 1;
+                }
+                Some('+') => break self.max_expand(s + 1, p, ep)?,
+                Some('*') => break self.max_expand(s, p, ep)?,
+                Some('-') => break self.min_expand(s, p, ep)?,
+                _ => {
+                    s += 1;
+                    p = ep;
+                }
+            }
+        };
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    /// 返回从 p 开始的单个字符类（字面量、`%x` 或 `[...]`）之后的位置
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pat[p];
+        p += 1;
+        if c == '%' {
+            if p >= self.pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == '[' {
+            if self.pat.get(p) == Some(&'^') {
+                p += 1;
+            }
+            // 第一个字符即使是 ']' 也属于集合
+            loop {
+                let Some(&c) = self.pat.get(p) else {
+                    return Err("malformed pattern (missing ']')".to_string());
+                };
+                p += 1;
+                if c == '%' && p < self.pat.len() {
+                    p += 1;
+                }
+                match self.pat.get(p) {
+                    Some(']') => return Ok(p + 1),
+                    Some(_) => {}
+                    None => return Err("malformed pattern (missing ']')".to_string()),
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    fn single_match(&self, c: char, p: usize, ep: usize) -> bool {
+        match self.pat[p] {
+            '.' => true,
+            '%' => match_class(c, self.pat[p + 1]),
+            '[' => self.match_bracket(c, p, ep - 1),
+            literal => literal == c,
+        }
+    }
+
+    /// 判断 c 是否属于集合 pat[p..=ec]（pat[p] 为 '['，pat[ec] 为 ']'）
+    fn match_bracket(&self, c: char, mut p: usize, ec: usize) -> bool {
+        let mut include = true;
+        p += 1;
+        if self.pat[p] == '^' {
+            include = false;
+            p += 1;
+        }
+        while p < ec {
+            if self.pat[p] == '%' {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return include;
+                }
+                p += 1;
+            } else if self.pat[p + 1] == '-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return include;
+                }
+                p += 3;
+            } else {
+                if self.pat[p] == c {
+                    return include;
+                }
+                p += 1;
+            }
+        }
+        !include
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while s + count < self.src.len() && self.single_match(self.src[s + count], p, ep) {
+            count += 1;
+        }
+        loop {
+            if let Some(end) = self.do_match(s + count, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if s < self.src.len() && self.single_match(self.src[s], p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> Result<Option<usize>, String> {
+        if self.level >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let open = (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_string())?;
+        self.capture[open].1 = (s - self.capture[open].0) as isize;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.capture[open].1 = CAP_UNFINISHED;
+        }
+        Ok(result)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// 反向引用 %1-%9：匹配与第 n 个已完成捕获相同的文本
+    fn match_capture(&self, s: usize, digit: char) -> Result<Option<usize>, String> {
+        let index = (digit as usize).checked_sub('1' as usize);
+        let Some(index) = index.filter(|&index| index < self.level && self.capture[index].1 != CAP_UNFINISHED) else {
+            return Err(format!("invalid capture index %{}", digit));
+        };
+        let (start, len) = self.capture[index];
+        let len = len.max(0) as usize;
+        let captured = &self.src[start..start + len];
+        Ok((self.src.len() - s >= len && &self.src[s..s + len] == captured).then_some(s + len))
+    }
+
+    fn captures(&self, start: usize, end: usize) -> Result<Vec<Capture>, String> {
+        if self.level == 0 {
+            return Ok(vec![Capture::Text(self.src[start..end].iter().collect())]);
+        }
+        (0..self.level)
+            .map(|l| match self.capture[l] {
+                (_, CAP_UNFINISHED) => Err("unfinished capture".to_string()),
+                (position, CAP_POSITION) => Ok(Capture::Position(position + 1)),
+                (from, len) => Ok(Capture::Text(self.src[from..from + len as usize].iter().collect())),
+            })
+            .collect()
+    }
+}
+
+/// 判断 c 是否属于 `%` 后跟 class 表示的字符类；大写的类为补集，其他字符按字面量比较
+fn match_class(c: char, class: char) -> bool {
+    let matched = match class.to_ascii_lowercase() {
+        'a' => c.is_alphabetic(),
+        'c' => c.is_control(),
+        'd' => c.is_ascii_digit(),
+        'g' => !c.is_control() && !c.is_whitespace(),
+        'l' => c.is_lowercase(),
+        'p' => c.is_ascii_punctuation() || (!c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace() && !c.is_control()),
+        's' => c.is_whitespace(),
+        'u' => c.is_uppercase(),
+        'w' => c.is_alphanumeric(),
+        'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matched
+    } else {
+        matched
+    }
+}
+
+/// 从码点下标 from 开始查找下一个匹配，跳过结束位置等于 last_end 的匹配（避免在同一位置重复得到空匹配）
+/// 与 Lua 5.4 的 string.gmatch 相同，模式开头的 '^' 不作为锚点
+pub(crate) fn find_next(src: &[char], pat: &[char], from: usize, last_end: Option<usize>) -> Result<Option<Match>, String> {
+    let mut state = MatchState::new(src, pat);
+    for start in from..=src.len() {
+        state.reset();
+        if let Some(end) = state.do_match(start, 0)? {
+            if Some(end) != last_end {
+                let captures = state.captures(start, end)?;
+                return Ok(Some(Match { end, captures }));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// 统计互不重叠的匹配次数，规则与 string.gsub 的计数相同（模式开头的 '^' 为锚点）
+pub(crate) fn count_matches(src: &[char], pat: &[char]) -> Result<usize, String> {
+    let (anchor, pat) = match pat.split_first() {
+        Some(('^', rest)) => (true, rest),
+        _ => (false, pat),
+    };
+    let mut state = MatchState::new(src, pat);
+    let (mut s, mut last_end, mut count) = (0, None, 0);
+    loop {
+        state.reset();
+        match state.do_match(s, 0)? {
+            Some(end) if Some(end) != last_end => {
+                count += 1;
+                s = end;
+                last_end = Some(end);
+            }
+            _ if s < src.len() => s += 1,
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    Ok(count)
+}
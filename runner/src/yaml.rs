@@ -0,0 +1,97 @@
+//! lua_run_yaml 使用的 YAML 输出：把 serde_json::Value 写成块格式的 YAML 文本
+//!
+//! 只需要输出、不需要解析，因此不依赖 YAML 库。映射的键按 serde_json::Map 的顺序输出；
+//! 可能被误读的字符串（布尔值、数字、含有特殊字符等）写成双引号形式，转义与 JSON 相同，JSON 字符串本身就是合法的 YAML 双引号标量。
+
+use serde_json::Value;
+
+/// YAML 1.1 和 1.2 中不加引号时会被解析为非字符串的字面量（比较时忽略大小写）
+const RESERVED_SCALARS: [&str; 13] = ["null", "~", "true", "false", "yes", "no", "on", "off", "y", "n", ".inf", ".nan", "<<"];
+
+/// 把值序列化为 YAML 文本，以换行结尾
+pub(crate) fn to_yaml(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, 0);
+    out
+}
+
+/// 写出 value：标量和空集合写在当前行；非空集合的第一项从当前位置开始，之后各项缩进 indent 个空格
+fn write_value(out: &mut String, value: &Value, indent: usize) {
+    match value {
+        Value::Array(items) if !items.is_empty() => {
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    push_indent(out, indent);
+                }
+                out.push_str("- ");
+                write_value(out, item, indent + 2);
+            }
+        }
+        Value::Object(map) if !map.is_empty() => {
+            for (i, (key, item)) in map.iter().enumerate() {
+                if i > 0 {
+                    push_indent(out, indent);
+                }
+                out.push_str(&string_scalar(key));
+                out.push(':');
+                match item {
+                    // 映射中的序列与键对齐，嵌套的映射多缩进一级
+                    Value::Array(items) if !items.is_empty() => {
+                        out.push('\n');
+                        push_indent(out, indent);
+                        write_value(out, item, indent);
+                    }
+                    Value::Object(map) if !map.is_empty() => {
+                        out.push('\n');
+                        push_indent(out, indent + 2);
+                        write_value(out, item, indent + 2);
+                    }
+                    _ => {
+                        out.push(' ');
+                        write_value(out, item, indent);
+                    }
+                }
+            }
+        }
+        Value::Array(_) => out.push_str("[]\n"),
+        Value::Object(_) => out.push_str("{}\n"),
+        Value::String(text) => {
+            out.push_str(&string_scalar(text));
+            out.push('\n');
+        }
+        // null、布尔值和数字的 JSON 写法在 YAML 中含义相同
+        scalar => {
+            out.push_str(&scalar.to_string());
+            out.push('\n');
+        }
+    }
+}
+
+fn push_indent(out: &mut String, indent: usize) {
+    out.extend(std::iter::repeat_n(' ', indent));
+}
+
+/// 字符串可以不加引号时原样输出，否则输出 JSON 形式的双引号字符串
+fn string_scalar(text: &str) -> String {
+    if needs_quotes(text) {
+        Value::from(text).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+fn needs_quotes(text: &str) -> bool {
+    let (Some(first), Some(last)) = (text.chars().next(), text.chars().last()) else {
+        return true;
+    };
+    "-?:,[]{}#&*!|>'\"%@`".contains(first)
+        || first.is_whitespace()
+        || last.is_whitespace()
+        || last == ':'
+        || first.is_ascii_digit()
+        || text.contains(": ")
+        || text.contains(" #")
+        || text.chars().any(|c| c.is_control() || c == '\u{feff}')
+        || RESERVED_SCALARS.iter().any(|reserved| text.eq_ignore_ascii_case(reserved))
+        || text.parse::<f64>().is_ok()
+}